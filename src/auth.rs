@@ -0,0 +1,82 @@
+//! HTTP Basic authentication of scrapes.
+
+use crate::constant_time_eq;
+
+/// Credentials which scrapes must present in the `Authorization` header.
+#[derive(Debug)]
+pub(crate) struct BasicAuth {
+    // The base64 encoding of `user:password`, as sent by clients.
+    credentials: Box<str>,
+}
+
+impl BasicAuth {
+    pub(crate) fn new(user: &str, password: &str) -> Self {
+        Self {
+            credentials: base64_encode(format!("{}:{}", user, password).as_bytes()).into(),
+        }
+    }
+
+    /// Returns `true` if the value of an `Authorization` header contains
+    /// these credentials.
+    pub(crate) fn check(&self, authorization: Option<&str>) -> bool {
+        let (scheme, credentials) = match authorization.and_then(|h| h.trim().split_once(' ')) {
+            Some(parts) => parts,
+            None => return false,
+        };
+        scheme.eq_ignore_ascii_case("basic")
+            && constant_time_eq(
+                credentials.trim_start().as_bytes(),
+                self.credentials.as_bytes(),
+            )
+    }
+}
+
+/// Encode `input` using the standard base64 alphabet, with padding.
+fn base64_encode(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut output = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let bytes = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (u32::from(bytes[0]) << 16) | (u32::from(bytes[1]) << 8) | u32::from(bytes[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                output.push(ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::{base64_encode, BasicAuth};
+
+    #[test]
+    fn test_base64_encode() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(
+            base64_encode(b"Aladdin:open sesame"),
+            "QWxhZGRpbjpvcGVuIHNlc2FtZQ=="
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        let auth = BasicAuth::new("Aladdin", "open sesame");
+        assert!(auth.check(Some("Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ==")));
+        assert!(auth.check(Some("basic QWxhZGRpbjpvcGVuIHNlc2FtZQ==")));
+        assert!(!auth.check(Some("Basic QWxhZGRpbjpvcGVuIHNlc2FtZA==")));
+        assert!(!auth.check(Some("Bearer QWxhZGRpbjpvcGVuIHNlc2FtZQ==")));
+        assert!(!auth.check(Some("QWxhZGRpbjpvcGVuIHNlc2FtZQ==")));
+        assert!(!auth.check(None));
+    }
+}
@@ -0,0 +1,2641 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env,
+    net::IpAddr,
+    sync::{mpsc, Arc, Mutex, OnceLock},
+    thread,
+    time::Duration,
+};
+
+use prometheus::{
+    core::{Collector, Desc},
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry,
+};
+use rocket::{
+    http::{ContentType, Method, Status},
+    Request,
+};
+
+use crate::{
+    auth::BasicAuth,
+    cache::OutputCache,
+    client_ip::ProxyHeader,
+    concurrency::ConcurrencyMax,
+    exemplars::{default_trace_id, Exemplars, TraceIdExtractor},
+    format::CustomEncoder,
+    histograms::DurationHistograms,
+    rates::{EndpointCap, EndpointRates},
+    retention::RetainedSeries,
+    rolling::{RollingQuantiles, QUANTILES},
+    summary::DurationSummary,
+    window::RequestWindow,
+    BackgroundRecorder, DurationUnit, EndpointMapper, EndpointStyle, GuardHandlerDurations,
+    HourlyCounter, LabelNames, MethodSource, Observation, PrometheusMetrics, Registries,
+    ResponseCallback, SeriesBudget, StatusCode, StatusMapper, NAMESPACE_ENV_VAR,
+};
+
+/// Builder for a [`PrometheusMetrics`] instance.
+///
+/// Create one using [`PrometheusMetrics::builder`], chain the configuration
+/// methods you need, then call [`PrometheusMetricsBuilder::build`]:
+///
+/// ```rust
+/// use rocket_prometheus::PrometheusMetrics;
+///
+/// let prometheus = PrometheusMetrics::builder()
+///     .group_prefix("/api/orders", "orders")
+///     .build()
+///     .expect("valid configuration");
+/// ```
+#[derive(Default)]
+#[must_use]
+pub struct PrometheusMetricsBuilder {
+    namespace: Option<String>,
+    registry: Option<Registry>,
+    endpoint_groups: Vec<(String, String)>,
+    target_info: BTreeMap<String, String>,
+    exemplar_threshold: Option<Duration>,
+    endpoint_style: EndpointStyle,
+    duration_unit: DurationUnit,
+    method_source: MethodSource,
+    buckets: Option<Vec<f64>>,
+    request_size_histogram: bool,
+    request_size_buckets: Option<Vec<f64>>,
+    method_buckets: Vec<(Method, Vec<f64>)>,
+    endpoint_buckets: Vec<(String, Vec<f64>)>,
+    rare_endpoints: Option<(u64, Duration)>,
+    max_endpoints: Option<usize>,
+    record_in_background: bool,
+    status_mapper: Option<StatusMapper>,
+    endpoint_mapper: Option<EndpointMapper>,
+    response_callbacks: Vec<ResponseCallback>,
+    bad_content_type_counter: bool,
+    series_budget: Option<usize>,
+    profile_label: bool,
+    duration_endpoints: Option<HashSet<String>>,
+    ignored_routes: HashSet<String>,
+    ignored_methods: HashSet<Method>,
+    handler_methods: Option<Vec<Method>>,
+    quantile_table_route: bool,
+    exclude_metrics_route: bool,
+    comment: Option<String>,
+    incomplete_bodies_counter: bool,
+    aggregate_endpoints: bool,
+    fairing_label: Option<String>,
+    const_labels: HashMap<String, String>,
+    label_names: LabelNames,
+    guard_handler_durations: bool,
+    stream_output: bool,
+    text_content_type: Option<ContentType>,
+    custom_encoder: Option<CustomEncoder>,
+    cache_ttl: Duration,
+    request_id_header: Option<String>,
+    rolling_quantiles_window: Option<usize>,
+    active_window: Option<Duration>,
+    series_retention: Option<Duration>,
+    setup_check: bool,
+    controllable_durations: bool,
+    conditional_requests_counter: bool,
+    route_method_label: bool,
+    route_name_label: bool,
+    query_label: bool,
+    host_allowlist: Option<HashSet<String>>,
+    status_class_label: bool,
+    token: Option<String>,
+    basic_auth: Option<BasicAuth>,
+    allowed_ips: Option<HashSet<IpAddr>>,
+    proxy_header: Option<(String, HashSet<IpAddr>)>,
+    // The UTC offset of the hourly counter, if enabled, or `None` for local time.
+    requests_by_hour_counter: Option<Option<i32>>,
+    concurrency_max: Option<bool>,
+    aborted_requests_counter: bool,
+    requests_received_counter: bool,
+    track_unmatched: bool,
+    catcher_endpoints: bool,
+    process_metrics: bool,
+    build_info: Option<(String, String)>,
+    trace_timings: bool,
+    trace_fairing_order: bool,
+    status_code_counters: Option<Vec<u16>>,
+    request_counter_disabled: bool,
+    last_request_timestamp: bool,
+    errors_counter: bool,
+    duration_histogram_disabled: bool,
+    duration_summary: Option<(Vec<f64>, usize)>,
+    exemplar_header: Option<String>,
+    exemplar_trace_id: Option<TraceIdExtractor>,
+    counter_status_class: bool,
+    histogram_status_class: bool,
+}
+
+impl PrometheusMetricsBuilder {
+    /// Create a new builder using the default configuration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the namespace (prefix) of the built-in metrics, e.g. `myapp` to
+    /// expose `myapp_http_requests_total`.
+    ///
+    /// The namespace is chosen in the following order of precedence:
+    ///
+    /// 1. the value passed to this method;
+    /// 2. the `ROCKET_PROMETHEUS_NAMESPACE` environment variable;
+    /// 3. the default, `rocket`.
+    ///
+    /// Setting the namespace here avoids mutating process-wide environment
+    /// variables, which is racy when several tests run in the same binary.
+    pub fn namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.namespace = Some(namespace.into());
+        self
+    }
+
+    /// Use a custom [`Registry`] for additional metrics.
+    ///
+    /// See [`PrometheusMetrics::with_registry`] for details.
+    pub fn registry(mut self, registry: Registry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// Record requests to routes whose URI starts with `prefix` using `group`
+    /// as the `endpoint` label instead of the route URI.
+    ///
+    /// This can be called multiple times; prefixes are checked in the order
+    /// they were added and the first match wins. Routes which don't match any
+    /// prefix are recorded using their URI as usual.
+    ///
+    /// Note that this is a plain string prefix match against the route URI,
+    /// so `/api/orders` will also match `/api/orders_archive`; include a
+    /// trailing slash if that isn't what you want.
+    ///
+    /// ```rust
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// // Requests to e.g. `/api/orders/<id>` will be recorded with
+    /// // `endpoint="orders"`.
+    /// let prometheus = PrometheusMetrics::builder()
+    ///     .group_prefix("/api/orders/", "orders")
+    ///     .group_prefix("/api/users/", "users")
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn group_prefix(mut self, prefix: impl Into<String>, group: impl Into<String>) -> Self {
+        self.endpoint_groups.push((prefix.into(), group.into()));
+        self
+    }
+
+    /// Add a `fairing` const label with the given value to all built-in
+    /// metrics.
+    ///
+    /// Each [`PrometheusMetrics`] keeps its built-in metrics in its own
+    /// internal registry, so attaching several instances to one application
+    /// (e.g. one per mount tree) never causes registration conflicts, even if
+    /// they share a custom registry such as the default one. However, their
+    /// series have identical names and labels, so they can't be told apart
+    /// once scraped into the same job; this label distinguishes them.
+    ///
+    /// The label is called `fairing` rather than `instance` because Prometheus
+    /// attaches its own `instance` label to every scraped series.
+    ///
+    /// ```rust
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let public = PrometheusMetrics::builder()
+    ///     .fairing_label("public")
+    ///     .build()
+    ///     .unwrap();
+    /// let admin = PrometheusMetrics::builder()
+    ///     .fairing_label("admin")
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// By default no such label is added.
+    pub fn fairing_label(mut self, value: impl Into<String>) -> Self {
+        self.fairing_label = Some(value.into());
+        self
+    }
+
+    /// Set the name of the `endpoint` label of the built-in metrics, e.g. to
+    /// `path` to match existing dashboards.
+    ///
+    /// Building fails if the name isn't a valid Prometheus label name, or
+    /// clashes with another label.
+    ///
+    /// Defaults to `endpoint`.
+    pub fn endpoint_label_name(mut self, name: impl Into<String>) -> Self {
+        self.label_names.endpoint = name.into();
+        self
+    }
+
+    /// Set the name of the `method` label of the built-in metrics, e.g. to
+    /// `http_method`.
+    ///
+    /// Building fails if the name isn't a valid Prometheus label name, or
+    /// clashes with another label.
+    ///
+    /// Defaults to `method`.
+    pub fn method_label_name(mut self, name: impl Into<String>) -> Self {
+        self.label_names.method = name.into();
+        self
+    }
+
+    /// Set the name of the `status` label of the built-in metrics, e.g. to
+    /// `code`.
+    ///
+    /// Building fails if the name isn't a valid Prometheus label name, or
+    /// clashes with another label.
+    ///
+    /// Defaults to `status`.
+    pub fn status_label_name(mut self, name: impl Into<String>) -> Self {
+        self.label_names.status = name.into();
+        self
+    }
+
+    /// Add const labels with the given names and values to all built-in
+    /// metrics, e.g. to tag every series with the region the application
+    /// runs in.
+    ///
+    /// ```rust
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let prometheus = PrometheusMetrics::builder()
+    ///     .const_labels([("region", "eu-west-1")])
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// Calling this more than once adds to the existing labels. Building
+    /// fails if a name isn't a valid Prometheus label name, or clashes with
+    /// another label of the built-in metrics.
+    ///
+    /// By default no const labels are added.
+    pub fn const_labels<I, K, V>(mut self, labels: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.const_labels.extend(
+            labels
+                .into_iter()
+                .map(|(name, value)| (name.into(), value.into())),
+        );
+        self
+    }
+
+    /// Set the syntax used for dynamic segments of route URIs in the
+    /// `endpoint` label, for label processors which can't handle `<` and `>`.
+    ///
+    /// ```rust
+    /// use rocket_prometheus::{EndpointStyle, PrometheusMetrics};
+    ///
+    /// // Record `/users/<id>` as `/users/{id}`.
+    /// let prometheus = PrometheusMetrics::builder()
+    ///     .endpoint_style(EndpointStyle::Braces)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// The style doesn't apply to endpoints grouped using
+    /// [`PrometheusMetricsBuilder::group_prefix`], which are labelled with the
+    /// group name. Endpoints passed to
+    /// [`PrometheusMetricsBuilder::duration_endpoints`] must use the same
+    /// style.
+    ///
+    /// Defaults to [`EndpointStyle::Rocket`], i.e. `/users/<id>`.
+    pub fn endpoint_style(mut self, style: EndpointStyle) -> Self {
+        self.endpoint_style = style;
+        self
+    }
+
+    /// Record rarely requested endpoints under a single `endpoint="<other>"`
+    /// label value.
+    ///
+    /// Request counts are tracked per endpoint over consecutive windows of
+    /// length `window`. An endpoint is recorded under its own name while it
+    /// has received at least `min_requests` requests in either the current or
+    /// the previous window; otherwise it's recorded as `<other>`. This keeps
+    /// label cardinality down while still observing traffic to the long tail
+    /// of endpoints in aggregate.
+    ///
+    /// This is an approximation: an endpoint's first requests in each busy
+    /// period are recorded as `<other>` until it crosses the threshold, and
+    /// an endpoint whose traffic hovers around the threshold will have series
+    /// under both its own name and `<other>`. Endpoint grouping (see
+    /// [`PrometheusMetricsBuilder::group_prefix`]) is applied first, so the
+    /// rate is tracked per group.
+    ///
+    /// By default all endpoints are recorded under their own name.
+    pub fn collapse_rare_endpoints(mut self, min_requests: u64, window: Duration) -> Self {
+        self.rare_endpoints = Some((min_requests, window));
+        self
+    }
+
+    /// Record at most `max_endpoints` distinct `endpoint` label values under
+    /// their own name, after which any new endpoint is recorded as
+    /// `endpoint="<overflow>"`.
+    ///
+    /// This protects against unbounded series growth, for example from a
+    /// client requesting many distinct URIs when endpoints are labelled by
+    /// [`PrometheusMetricsBuilder::endpoint_label`] or
+    /// [`PrometheusMetricsBuilder::track_unmatched`] is enabled. Endpoints
+    /// are admitted in the order they're first requested, and are never
+    /// forgotten. The `<other>`, `<unmatched>` and `<catcher:CODE>` label
+    /// values don't count towards the maximum.
+    ///
+    /// By default the number of endpoints isn't limited.
+    pub fn max_endpoints(mut self, max_endpoints: usize) -> Self {
+        self.max_endpoints = Some(max_endpoints);
+        self
+    }
+
+    /// Record the built-in metrics on a background thread rather than in the
+    /// fairing's `on_response` callback.
+    ///
+    /// When enabled, `on_response` only sends a small record of each request
+    /// (endpoint, method, status and duration) over a channel, and a dedicated
+    /// thread drains the channel and updates the metrics. This keeps the
+    /// label lookups and histogram observation off the request path.
+    ///
+    /// Metrics are therefore _eventually_ consistent: a scrape made
+    /// immediately after a response has been sent may not include that
+    /// request yet. The thread exits once every clone of the built
+    /// [`PrometheusMetrics`] has been dropped.
+    ///
+    /// Defaults to `false`, meaning metrics are recorded synchronously.
+    pub fn record_in_background(mut self, enabled: bool) -> Self {
+        self.record_in_background = enabled;
+        self
+    }
+
+    /// Transform response status codes before they are used as the `status`
+    /// label.
+    ///
+    /// This can be used to remap or clamp status codes which shouldn't appear
+    /// in metrics verbatim. By default status codes are recorded unchanged.
+    ///
+    /// ```rust
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// // Fold our custom 4xx codes into a plain 400.
+    /// let prometheus = PrometheusMetrics::builder()
+    ///     .map_status(|code| match code {
+    ///         450..=499 => 400,
+    ///         code => code,
+    ///     })
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn map_status<F>(mut self, map: F) -> Self
+    where
+        F: Fn(u16) -> u16 + Send + Sync + 'static,
+    {
+        self.status_mapper = Some(Arc::new(map));
+        self
+    }
+
+    /// Derive the `endpoint` label of each request with a custom function.
+    ///
+    /// This replaces the default label, the URI of the matched route (e.g.
+    /// `/users/<id>`), giving full control over its cardinality. The
+    /// function is only called for requests which matched a route and aren't
+    /// [ignored](Self::ignore_routes), and its result is used as-is: prefix
+    /// groups and the [endpoint style](Self::endpoint_style) aren't applied.
+    /// Since labels can't be known ahead of requests, series for
+    /// [`status_code_counters`](Self::status_code_counters) aren't
+    /// initialized at liftoff.
+    ///
+    /// ```rust
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// // Label requests by their first path segment.
+    /// let prometheus = PrometheusMetrics::builder()
+    ///     .endpoint_label(|req| {
+    ///         let first = req.uri().path().segments().next().unwrap_or_default();
+    ///         format!("/{}", first)
+    ///     })
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// By default the route URI is used.
+    pub fn endpoint_label<F>(mut self, map: F) -> Self
+    where
+        F: Fn(&Request<'_>) -> String + Send + Sync + 'static,
+    {
+        self.endpoint_mapper = Some(Arc::new(map));
+        self
+    }
+
+    /// Call `callback` with each request, its duration and its response
+    /// status after the built-in metrics have been updated for it.
+    ///
+    /// This reuses the fairing's timing for custom instrumentation, such as
+    /// logging slow requests or updating metrics of your own, without
+    /// measuring requests again in another fairing. Callbacks are called in
+    /// the order they were added, from `on_response`, so they should be
+    /// quick. They're only called for requests recorded in the built-in
+    /// metrics, so not for [ignored](Self::ignore_routes) requests, requests
+    /// without a start time (see the crate docs) or, by default, requests
+    /// which didn't match a route. The status is that of the response, before
+    /// any [mapping](Self::map_status).
+    ///
+    /// When [recording in the background](Self::record_in_background),
+    /// callbacks are called once the request has been sent to the recorder
+    /// thread, which may not have updated the built-in metrics yet.
+    ///
+    /// ```rust
+    /// use std::time::Duration;
+    ///
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let prometheus = PrometheusMetrics::builder()
+    ///     .response_callback(|req, duration, status| {
+    ///         if duration > Duration::from_secs(1) {
+    ///             eprintln!("slow request: {} {} ({})", req.method(), req.uri(), status);
+    ///         }
+    ///     })
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// By default no callbacks are called.
+    pub fn response_callback<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&Request<'_>, Duration, Status) + Send + Sync + 'static,
+    {
+        self.response_callbacks.push(Box::new(callback));
+        self
+    }
+
+    /// Add a `status_class` label to the built-in metrics, containing the
+    /// class of the response status (e.g. `2xx` or `4xx`) alongside the
+    /// full `status` label.
+    ///
+    /// Grouping by this label is much cheaper than matching the `status`
+    /// label against a regular expression. Note that it doesn't add any
+    /// series, since each status belongs to a single class.
+    ///
+    /// This can't be combined with
+    /// [`PrometheusMetricsBuilder::counter_status_class`] or
+    /// [`PrometheusMetricsBuilder::histogram_status_class`]; doing so makes
+    /// [`PrometheusMetricsBuilder::build`] return an error.
+    ///
+    /// Defaults to `false`.
+    pub fn status_class_label(mut self, enabled: bool) -> Self {
+        self.status_class_label = enabled;
+        self
+    }
+
+    /// Replace the `status` label of `http_requests_total` with a
+    /// `status_class` label containing the class of the status, e.g. `2xx`.
+    ///
+    /// Together with [`PrometheusMetricsBuilder::histogram_status_class`],
+    /// this allows the cardinality of the counter and histogram to be traded
+    /// off independently. The other built-in metrics keep their `status`
+    /// label.
+    ///
+    /// Defaults to `false`.
+    pub fn counter_status_class(mut self, enabled: bool) -> Self {
+        self.counter_status_class = enabled;
+        self
+    }
+
+    /// Replace the `status` label of `http_requests_duration_seconds` with a
+    /// `status_class` label containing the class of the status, e.g. `2xx`.
+    ///
+    /// Successful and error responses often have quite different latency
+    /// distributions, so a histogram per status class is usually enough
+    /// while having far fewer series than one per status code. The counter
+    /// can keep its full `status` label, see
+    /// [`PrometheusMetricsBuilder::counter_status_class`].
+    ///
+    /// Defaults to `false`.
+    pub fn histogram_status_class(mut self, enabled: bool) -> Self {
+        self.histogram_status_class = enabled;
+        self
+    }
+
+    /// Whether to create the `http_requests_total` counter.
+    ///
+    /// Services which only care about latency can disable it to avoid its
+    /// series, since the `_count` of `http_requests_duration_seconds` gives
+    /// the same totals (for requests whose duration is observed). When
+    /// disabled the counter is neither exposed nor incremented, although
+    /// [`PrometheusMetrics::http_requests_total`] still returns a (private)
+    /// counter.
+    ///
+    /// Defaults to `true`.
+    pub fn enable_request_counter(mut self, enabled: bool) -> Self {
+        self.request_counter_disabled = !enabled;
+        self
+    }
+
+    /// Whether to create the `http_requests_duration_seconds` histogram.
+    ///
+    /// Services with many routes can disable it to avoid a series per bucket
+    /// for every endpoint, method and status, keeping only
+    /// `http_requests_total`. When disabled the histogram (including any
+    /// [per-method histograms](Self::method_buckets)) is neither exposed nor
+    /// observed, although
+    /// [`PrometheusMetrics::http_requests_duration_seconds`] still returns a
+    /// (private) histogram. Request start times are then only captured if
+    /// another metric needs them.
+    ///
+    /// Defaults to `true`.
+    pub fn enable_duration_histogram(mut self, enabled: bool) -> Self {
+        self.duration_histogram_disabled = !enabled;
+        self
+    }
+
+    /// Observe request durations with an `http_requests_duration_seconds_summary`
+    /// summary instead of the `http_requests_duration_seconds` histogram.
+    ///
+    /// The summary exposes the given quantiles (e.g. `0.5`, `0.9` and `0.99`),
+    /// computed when metrics are gathered from the last `window`
+    /// observations of each series, along with the count and sum of all
+    /// observations. This avoids choosing buckets up front, but summary
+    /// quantiles can't be meaningfully aggregated, e.g. across instances or
+    /// endpoints, whereas histogram buckets can. The histogram isn't exposed
+    /// while the summary is enabled, and
+    /// [`PrometheusMetricsBuilder::method_buckets`] is ignored.
+    ///
+    /// ```rust
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let prometheus = PrometheusMetrics::builder()
+    ///     .duration_summary(vec![0.5, 0.9, 0.99], 1000)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// Building fails if `window` is zero or a quantile isn't between 0 and 1.
+    ///
+    /// By default the histogram is used.
+    pub fn duration_summary(mut self, quantiles: Vec<f64>, window: usize) -> Self {
+        self.duration_summary = Some((quantiles, window));
+        self
+    }
+
+    /// Attach exemplars to the `http_requests_duration_seconds` histogram,
+    /// using the trace ID found in the given request header, e.g.
+    /// `traceparent`.
+    ///
+    /// The most recent observation in each bucket is kept as its exemplar,
+    /// with a `trace_id` label, so that a latency spike can be followed to
+    /// an example trace. Exemplars are only exposed when metrics are
+    /// scraped as OpenMetrics, the only format which supports them, and
+    /// aren't recorded for the
+    /// [`PrometheusMetricsBuilder::duration_summary`].
+    ///
+    /// The trace ID is extracted from a W3C `traceparent` header value, or
+    /// is the whole header value otherwise; use
+    /// [`PrometheusMetricsBuilder::exemplar_trace_id`] to customise this.
+    ///
+    /// ```rust
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let prometheus = PrometheusMetrics::builder()
+    ///     .exemplar_header("traceparent")
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// By default no exemplars are recorded.
+    pub fn exemplar_header(mut self, header: impl Into<String>) -> Self {
+        self.exemplar_header = Some(header.into());
+        self
+    }
+
+    /// Extract the trace ID of exemplars from the value of the
+    /// [`PrometheusMetricsBuilder::exemplar_header`] with `extract`.
+    ///
+    /// Requests for which `extract` returns `None` don't record an exemplar.
+    /// OpenMetrics limits the length of exemplar labels, so trace IDs longer
+    /// than 120 characters are also skipped.
+    ///
+    /// ```rust
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let prometheus = PrometheusMetrics::builder()
+    ///     .exemplar_header("uber-trace-id")
+    ///     .exemplar_trace_id(|value| value.split(':').next().map(str::to_owned))
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// Defaults to the trace ID of a W3C `traceparent` header, or the whole
+    /// header value.
+    pub fn exemplar_trace_id<F>(mut self, extract: F) -> Self
+    where
+        F: Fn(&str) -> Option<String> + Send + Sync + 'static,
+    {
+        self.exemplar_trace_id = Some(Arc::new(extract));
+        self
+    }
+
+    /// Count responses with each of the given status codes in a separate
+    /// `http_responses_{code}_total` counter (labels: endpoint, method),
+    /// instead of in the status-labelled `http_requests_total` counter.
+    ///
+    /// This suits backends which can't handle the `status` label well.
+    /// Responses with a status not in `codes` aren't counted. The status is
+    /// the one used for the `status` label, i.e. after applying
+    /// [`PrometheusMetricsBuilder::map_status`]. When the application lifts
+    /// off, each counter is initialized to zero for every mounted route.
+    ///
+    /// With this enabled `http_requests_total` is no longer exposed, although
+    /// [`PrometheusMetrics::http_requests_total`] still returns a (private)
+    /// counter. The other built-in metrics keep their `status` label.
+    ///
+    /// By default responses are only counted in `http_requests_total`.
+    pub fn status_code_counters(mut self, codes: impl IntoIterator<Item = u16>) -> Self {
+        self.status_code_counters = Some(codes.into_iter().collect());
+        self
+    }
+
+    /// Set the unit in which request durations are observed by the
+    /// `http_requests_duration_seconds` histogram, which is named after the
+    /// unit, e.g. `http_requests_duration_milliseconds`.
+    ///
+    /// Prometheus conventions recommend seconds, but other units can match
+    /// existing dashboards. The default buckets are scaled to the unit;
+    /// buckets set with [`PrometheusMetricsBuilder::buckets`] or
+    /// [`PrometheusMetricsBuilder::method_buckets`] must be given in it. Other
+    /// duration metrics are always in seconds.
+    ///
+    /// ```rust
+    /// use rocket_prometheus::{DurationUnit, PrometheusMetrics};
+    ///
+    /// let prometheus = PrometheusMetrics::builder()
+    ///     .duration_unit(DurationUnit::Milliseconds)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// Defaults to [`DurationUnit::Seconds`].
+    pub fn duration_unit(mut self, unit: DurationUnit) -> Self {
+        self.duration_unit = unit;
+        self
+    }
+
+    /// Set the buckets of the `http_requests_duration_seconds` histogram, as
+    /// upper bounds in seconds (or the configured
+    /// [duration unit](Self::duration_unit)).
+    ///
+    /// The default buckets range from 5ms to 10s, which doesn't suit
+    /// applications whose requests are much faster or slower. Buckets must be
+    /// non-empty and in increasing order, otherwise
+    /// [`PrometheusMetricsBuilder::build`] returns an error.
+    ///
+    /// ```rust
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let prometheus = PrometheusMetrics::builder()
+    ///     .buckets(vec![0.00001, 0.00005, 0.0001, 0.0005, 0.001, 0.01])
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// Defaults to [`prometheus::DEFAULT_BUCKETS`].
+    pub fn buckets(mut self, buckets: Vec<f64>) -> Self {
+        self.buckets = Some(buckets);
+        self
+    }
+
+    /// Create an `http_request_size_bytes` histogram (with the same labels as
+    /// `http_requests_duration_seconds`) observing the size of request
+    /// bodies, as given by the `Content-Length` header.
+    ///
+    /// Requests without a valid `Content-Length` header, such as those using
+    /// chunked transfer encoding, aren't observed.
+    ///
+    /// Defaults to `false`.
+    pub fn request_size_histogram(mut self, enabled: bool) -> Self {
+        self.request_size_histogram = enabled;
+        self
+    }
+
+    /// Set the buckets of the `http_request_size_bytes` histogram, as upper
+    /// bounds in bytes.
+    ///
+    /// Buckets must be non-empty and in increasing order, otherwise
+    /// [`PrometheusMetricsBuilder::build`] returns an error.
+    ///
+    /// Defaults to powers of 10 from 100 bytes to 100MB.
+    pub fn request_size_buckets(mut self, buckets: Vec<f64>) -> Self {
+        self.request_size_buckets = Some(buckets);
+        self
+    }
+
+    /// Use the given buckets for the `http_requests_duration_seconds`
+    /// histogram of requests with the given method.
+    ///
+    /// Different methods often have very different expected latencies, e.g.
+    /// fast `GET`s and slow `POST`s. Since buckets are fixed per histogram,
+    /// each configured method gets its own histogram, and requests with any
+    /// other method use the default histogram. The histograms share the
+    /// `http_requests_duration_seconds` name and labels and are exposed as a
+    /// single metric family, so queries are unaffected. Each series uses
+    /// memory proportional to its number of buckets, so finer buckets for one
+    /// method don't cost anything for the others.
+    ///
+    /// Requests with a configured method aren't observed by the histogram
+    /// returned by [`PrometheusMetrics::http_requests_duration_seconds`].
+    ///
+    /// ```rust
+    /// use rocket::http::Method;
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let prometheus = PrometheusMetrics::builder()
+    ///     .method_buckets(Method::Post, vec![0.1, 0.5, 1.0, 5.0, 30.0])
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// By default all methods use the same buckets.
+    pub fn method_buckets(mut self, method: Method, buckets: Vec<f64>) -> Self {
+        self.method_buckets.push((method, buckets));
+        self
+    }
+
+    /// Use the given buckets for the `http_requests_duration_seconds`
+    /// histogram of requests to the given endpoints, keyed by `endpoint`
+    /// label value, which is the route URI unless endpoints are grouped or
+    /// mapped.
+    ///
+    /// This suits applications mixing very fast endpoints, such as cached
+    /// lookups, with slow ones, such as report generation. As with
+    /// [`PrometheusMetricsBuilder::method_buckets`], each configured
+    /// endpoint gets its own histogram, with the endpoint as a const label,
+    /// and all other endpoints use the default histogram. The metric name
+    /// doesn't change: all histograms are exposed as the single
+    /// `http_requests_duration_seconds` family, so existing queries and
+    /// dashboards keep working, and `histogram_quantile` over a single
+    /// endpoint uses its own buckets. Aggregating buckets across endpoints
+    /// with different buckets (e.g. `sum by (le)`) only combines the bucket
+    /// bounds they have in common, so such queries are less precise.
+    ///
+    /// Endpoint buckets take precedence over method buckets, and requests to
+    /// configured endpoints aren't observed by the histogram returned by
+    /// [`PrometheusMetrics::http_requests_duration_seconds`]. Calling this
+    /// again adds to the configured endpoints.
+    ///
+    /// ```rust
+    /// use std::collections::HashMap;
+    ///
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let mut buckets = HashMap::new();
+    /// buckets.insert("/cache/<key>", vec![0.0001, 0.0005, 0.001, 0.005]);
+    /// buckets.insert("/reports/<id>", vec![1.0, 5.0, 15.0, 60.0]);
+    /// let prometheus = PrometheusMetrics::builder()
+    ///     .endpoint_buckets(buckets)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// Building fails if an endpoint is given more than once. By default all
+    /// endpoints use the same buckets.
+    pub fn endpoint_buckets<I, S>(mut self, buckets: I) -> Self
+    where
+        I: IntoIterator<Item = (S, Vec<f64>)>,
+        S: Into<String>,
+    {
+        self.endpoint_buckets.extend(
+            buckets
+                .into_iter()
+                .map(|(endpoint, buckets)| (endpoint.into(), buckets)),
+        );
+        self
+    }
+
+    /// Track requests with a missing, malformed or unexpected `Content-Type`.
+    ///
+    /// When enabled, an `http_bad_content_type_total` counter (labels:
+    /// endpoint, method) is incremented for matched requests using a method
+    /// which supports a payload (e.g. `POST` or `PUT`) where either:
+    ///
+    /// - the `Content-Type` header is missing or can't be parsed, unless the
+    ///   request has `Content-Length: 0`, or
+    /// - the route declares a `format` and the `Content-Type` doesn't match it.
+    ///
+    /// Note that Rocket won't route a request to a route whose declared
+    /// `format` doesn't match the request, so in practice the second case only
+    /// catches requests which fell through to a route without a `format`.
+    /// Requests which didn't match any route aren't counted, since they can't
+    /// be attributed to an endpoint.
+    ///
+    /// Defaults to `false`.
+    pub fn bad_content_type_counter(mut self, enabled: bool) -> Self {
+        self.bad_content_type_counter = enabled;
+        self
+    }
+
+    /// Create an `http_incomplete_bodies_total` counter (labels: endpoint)
+    /// for tracking requests whose body wasn't fully received.
+    ///
+    /// This is a user-driven counter: Rocket 0.5 doesn't tell fairings how
+    /// much of a request's body was read, so the fairing **never** increments
+    /// it. Instead, handlers which read request bodies can increment it via
+    /// [`PrometheusMetrics::http_incomplete_bodies_total`] when reading fails
+    /// part way through, e.g. because the client disconnected.
+    ///
+    /// Defaults to `false`.
+    pub fn incomplete_bodies_counter(mut self, enabled: bool) -> Self {
+        self.incomplete_bodies_counter = enabled;
+        self
+    }
+
+    /// Create an `http_conditional_requests_total` counter (labels: endpoint,
+    /// result) for measuring how often conditional requests are answered
+    /// without a body.
+    ///
+    /// Requests with an `If-Modified-Since` or `If-None-Match` header are
+    /// counted with `result="not_modified"` if the response status is `304`,
+    /// or `result="full"` if it's `200`. Conditional requests with any other
+    /// response status, and requests without either header, aren't counted.
+    ///
+    /// Defaults to `false`.
+    pub fn conditional_requests_counter(mut self, enabled: bool) -> Self {
+        self.conditional_requests_counter = enabled;
+        self
+    }
+
+    /// Create an `http_requests_by_hour_total` counter (labels: endpoint,
+    /// hour) counting requests by the hour of the day (`0` to `23`) in which
+    /// their response was sent.
+    ///
+    /// The hour is computed at the given offset from UTC in seconds, e.g.
+    /// `3600` for UTC+1, or in the server's local time zone if `None`.
+    ///
+    /// In the local time zone, hours follow daylight saving time: when the
+    /// clocks go forward, the skipped hour counts no requests that day, and
+    /// when they go back, the repeated hour counts the requests of both
+    /// occurrences. A fixed offset avoids this, but then the hours are off
+    /// by one for part of the year in time zones with daylight saving time.
+    ///
+    /// ```rust
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let prometheus = PrometheusMetrics::builder()
+    ///     .requests_by_hour_counter(None)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// This shows diurnal traffic patterns directly, for setups whose
+    /// retention is too short to see them in `http_requests_total`. With
+    /// longer retention the same information is available from the time
+    /// series itself, making this counter redundant. It adds at most 24
+    /// series per endpoint.
+    ///
+    /// By default no such counter is created.
+    pub fn requests_by_hour_counter(mut self, utc_offset_secs: Option<i32>) -> Self {
+        self.requests_by_hour_counter = Some(utc_offset_secs);
+        self
+    }
+
+    /// Create an `http_requests_errors_total` counter (labels: endpoint,
+    /// method) counting responses with a `5xx` status.
+    ///
+    /// This gives a direct error rate series, without matching the `status`
+    /// label with a regular expression. The status is the one used for the
+    /// `status` label, i.e. after applying
+    /// [`PrometheusMetricsBuilder::map_status`].
+    ///
+    /// Defaults to `false`.
+    pub fn errors_counter(mut self, enabled: bool) -> Self {
+        self.errors_counter = enabled;
+        self
+    }
+
+    /// Create an `http_request_last_timestamp_seconds` gauge (labels:
+    /// endpoint, method) set to the Unix time at which the latest response
+    /// for each endpoint and method was sent.
+    ///
+    /// This allows alerting on endpoints which haven't been requested for a
+    /// while, e.g. `time() - http_request_last_timestamp_seconds > 3600`.
+    ///
+    /// Defaults to `false`.
+    pub fn last_request_timestamp(mut self, enabled: bool) -> Self {
+        self.last_request_timestamp = enabled;
+        self
+    }
+
+    /// Create an `http_requests_received_total` counter (labels: method)
+    /// counting every response, whether or not the request matched a route.
+    ///
+    /// Unlike `http_requests_total`, which only counts requests matching a
+    /// route, this gives the true number of requests received, for
+    /// computing the overall request rate.
+    ///
+    /// Defaults to `false`.
+    pub fn requests_received_counter(mut self, enabled: bool) -> Self {
+        self.requests_received_counter = enabled;
+        self
+    }
+
+    /// Record requests which didn't match any route, such as 404s from
+    /// scanners or misconfigured clients, in the built-in metrics.
+    ///
+    /// These are labelled with an `endpoint` of `<unmatched>` and their
+    /// actual method and status. If the `route_method` label is enabled it is
+    /// empty for these requests.
+    ///
+    /// Defaults to `false`, since such requests are otherwise invisible and
+    /// recording them adds series for every method and status clients send.
+    pub fn track_unmatched(mut self, enabled: bool) -> Self {
+        self.track_unmatched = enabled;
+        self
+    }
+
+    /// Record responses produced by catchers with an `endpoint` label of
+    /// `<catcher:CODE>`, such as `<catcher:429>`, so that errors such as rate
+    /// limiting are tracked separately from the routes' own responses.
+    ///
+    /// Rocket doesn't expose which catcher (if any) produced a response, so
+    /// only two cases are detected:
+    ///
+    /// - requests which didn't match any route, which are recorded whether
+    ///   or not [`PrometheusMetricsBuilder::track_unmatched`] is enabled;
+    /// - requests whose catcher called [`mark_caught`](crate::mark_caught).
+    ///
+    /// Responses from catchers after a route was tried, for example when a
+    /// request guard fails, are otherwise still recorded under that route's
+    /// endpoint. The `<catcher:CODE>` label values don't count towards
+    /// [`PrometheusMetricsBuilder::max_endpoints`].
+    ///
+    /// Defaults to `false`.
+    pub fn catcher_endpoints(mut self, enabled: bool) -> Self {
+        self.catcher_endpoints = enabled;
+        self
+    }
+
+    /// Expose metrics about the process itself, such as
+    /// `process_cpu_seconds_total`, `process_resident_memory_bytes` and
+    /// `process_open_fds`, alongside the built-in metrics.
+    ///
+    /// These are only available on Linux with the `process` feature enabled.
+    /// Elsewhere a warning is logged when building and no process metrics
+    /// are exposed. They don't use the namespace or const labels, so that
+    /// they have their conventional names.
+    ///
+    /// Defaults to `false`.
+    pub fn process_metrics(mut self, enabled: bool) -> Self {
+        self.process_metrics = enabled;
+        self
+    }
+
+    /// Expose a `rocket_prometheus_build_info` gauge, always set to 1, whose
+    /// labels describe the build, so dashboards can annotate deploys.
+    ///
+    /// The labels are `version` (the version of this crate), and the given
+    /// `git_sha` and `build_date`, which are typically set at compile time:
+    ///
+    /// ```rust
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let prometheus = PrometheusMetrics::builder()
+    ///     .build_info(
+    ///         option_env!("GIT_SHA").unwrap_or("unknown"),
+    ///         option_env!("BUILD_DATE").unwrap_or("unknown"),
+    ///     )
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// Like the [process metrics](Self::process_metrics), the gauge doesn't
+    /// use the namespace or const labels, so it has the same name in every
+    /// application.
+    ///
+    /// By default no such gauge is created.
+    pub fn build_info(mut self, git_sha: &str, build_date: &str) -> Self {
+        self.build_info = Some((git_sha.to_owned(), build_date.to_owned()));
+        self
+    }
+
+    /// Create an `http_aborted_requests_total` counter (labels: reason) for
+    /// requests which Rocket answered without matching a route.
+    ///
+    /// These requests are otherwise left out of the built-in metrics, since
+    /// they have no `endpoint`. The `reason` is a coarse classification based
+    /// on the response status, as Rocket 0.5 doesn't tell fairings why a
+    /// request wasn't routed:
+    ///
+    /// - `bad_request`: the request was malformed (status `400`);
+    /// - `no_route`: no route matched the request (status `404`);
+    /// - `other`: any other status.
+    ///
+    /// Requests which matched a route but were rejected by a request guard
+    /// are recorded in the built-in metrics as normal, with the status of the
+    /// catcher's response, since Rocket doesn't expose whether a response
+    /// came from the handler or a catcher.
+    ///
+    /// Defaults to `false`.
+    pub fn aborted_requests_counter(mut self, enabled: bool) -> Self {
+        self.aborted_requests_counter = enabled;
+        self
+    }
+
+    /// Add a `has_request_id` label (`true` or `false`) to the built-in
+    /// metrics, recording whether the request included the given header,
+    /// e.g. `X-Request-ID`.
+    ///
+    /// This measures how widely a correlation ID is being propagated without
+    /// recording the (high cardinality) ID itself.
+    ///
+    /// By default no such label is added.
+    pub fn request_id_label(mut self, header: impl Into<String>) -> Self {
+        self.request_id_header = Some(header.into());
+        self
+    }
+
+    /// Choose whether the `method` label of the built-in metrics contains the
+    /// method of the request or the method declared by the matched route.
+    ///
+    /// The two differ when Rocket handles a `HEAD` request with a `GET`
+    /// route because no `HEAD` route matched: by default such requests are
+    /// recorded with `method="HEAD"`, while with [`MethodSource::Route`]
+    /// they're recorded with `method="GET"`, alongside the `GET` requests to
+    /// the same handler. Requests which didn't match a route, if tracked,
+    /// always use the request method. To record both, see
+    /// [`PrometheusMetricsBuilder::route_method_label`].
+    ///
+    /// ```rust
+    /// use rocket_prometheus::{MethodSource, PrometheusMetrics};
+    ///
+    /// let prometheus = PrometheusMetrics::builder()
+    ///     .method_label_source(MethodSource::Route)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// Defaults to [`MethodSource::Request`].
+    pub fn method_label_source(mut self, source: MethodSource) -> Self {
+        self.method_source = source;
+        self
+    }
+
+    /// Add a `route_method` label to the built-in metrics, containing the
+    /// method declared by the matched route.
+    ///
+    /// The `method` label always contains the method of the request itself.
+    /// The two can differ, for example when a `HEAD` request is handled by a
+    /// `GET` route, or when a method override is applied by a fairing, so
+    /// this label shows which route actually handled the request.
+    ///
+    /// Defaults to `false`.
+    pub fn route_method_label(mut self, enabled: bool) -> Self {
+        self.route_method_label = enabled;
+        self
+    }
+
+    /// Add a `route_name` label to the built-in metrics, containing the name
+    /// of the matched route, or its URI if it has no name.
+    ///
+    /// Routes generated by Rocket's attribute macros are named after their
+    /// handler function. Routes sharing a URI but differing in rank or
+    /// `format` are merged in the `endpoint` label, but can be told apart by
+    /// this label. To use the route name as the `endpoint` label instead, see
+    /// [`PrometheusMetricsBuilder::endpoint_label`].
+    ///
+    /// Defaults to `false`.
+    pub fn route_name_label(mut self, enabled: bool) -> Self {
+        self.route_name_label = enabled;
+        self
+    }
+
+    /// Add a `has_query` label (`true` or `false`) to the built-in metrics,
+    /// recording whether the request URI had a query string.
+    ///
+    /// This splits the metrics of endpoints whose behaviour depends heavily
+    /// on query parameters, at the cost of up to twice as many series.
+    ///
+    /// Defaults to `false`.
+    pub fn query_label(mut self, enabled: bool) -> Self {
+        self.query_label = enabled;
+        self
+    }
+
+    /// Add a `host` label to the built-in metrics, containing the domain of
+    /// the request's `Host` header if it's one of `hosts`.
+    ///
+    /// This splits metrics by domain when serving several from one
+    /// application. Only the given hosts are recorded under their own name,
+    /// since clients can send any `Host` header: others are recorded as
+    /// `<other>`, and requests without a `Host` header as `<none>`. Hosts
+    /// are matched case-insensitively and without the port.
+    ///
+    /// ```rust
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let prometheus = PrometheusMetrics::builder()
+    ///     .host_label(["example.com", "api.example.com"])
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// By default no such label is added.
+    pub fn host_label<I, S>(mut self, hosts: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.host_allowlist.get_or_insert_with(HashSet::new).extend(
+            hosts
+                .into_iter()
+                .map(|host| host.into().to_ascii_lowercase()),
+        );
+        self
+    }
+
+    /// Split request durations into time spent in request guards and time
+    /// spent in the handler.
+    ///
+    /// Fairings aren't notified when request guards finish, so the boundary
+    /// must be marked by the application, either with the [`HandlerStart`]
+    /// request guard (placed after all other request guards) or by calling
+    /// [`mark_handler_start`] from a custom guard. For requests where it was
+    /// marked, two extra histograms (with the same labels as
+    /// `http_requests_duration_seconds`) are observed:
+    ///
+    /// - `http_requests_guard_duration_seconds`: from the start of the request
+    ///   until the mark. This includes routing and any earlier fairings.
+    /// - `http_requests_handler_duration_seconds`: from the mark until the
+    ///   response is ready. This includes data guards, the handler itself and
+    ///   any responder work.
+    ///
+    /// The existing `http_requests_duration_seconds` histogram is unaffected.
+    ///
+    /// Defaults to `false`.
+    ///
+    /// [`HandlerStart`]: crate::HandlerStart
+    /// [`mark_handler_start`]: crate::mark_handler_start
+    pub fn guard_handler_durations(mut self, enabled: bool) -> Self {
+        self.guard_handler_durations = enabled;
+        self
+    }
+
+    /// Observe an `http_requests_controllable_duration_seconds` histogram
+    /// (with the same labels as `http_requests_duration_seconds`) measuring
+    /// request durations minus any time the handler marked as excluded.
+    ///
+    /// Handlers exclude time, such as calls to a known-slow dependency, using
+    /// the [`ExcludedTime`] request guard or [`exclude_duration`]. Excluded
+    /// time is subtracted from the full request duration, clamping at zero,
+    /// which gives a latency measurement covering only the work the
+    /// application itself is responsible for.
+    ///
+    /// The existing `http_requests_duration_seconds` histogram is unaffected.
+    ///
+    /// Defaults to `false`.
+    ///
+    /// [`ExcludedTime`]: crate::ExcludedTime
+    /// [`exclude_duration`]: crate::exclude_duration
+    pub fn controllable_durations(mut self, enabled: bool) -> Self {
+        self.controllable_durations = enabled;
+        self
+    }
+
+    /// Create an `http_requests_concurrency_max` gauge (labels: endpoint)
+    /// recording the highest number of requests to each endpoint in flight
+    /// at once.
+    ///
+    /// This reveals peaks in concurrency which a gauge of the current number
+    /// of requests in flight would miss between scrapes. If
+    /// `reset_on_scrape` is `true`, the peak is reset after each scrape, so
+    /// each value covers the time since the previous scrape; otherwise it
+    /// covers the time since startup. Resetting is only meaningful with a
+    /// single scraper.
+    ///
+    /// Requests are counted from the start of the fairing's `on_request`
+    /// callback until its `on_response` callback, for the endpoints recorded
+    /// by `http_requests_total`. Since the endpoint of a request isn't known
+    /// until it has been routed, the peak is computed from the times
+    /// requests were in flight once they finish: a peak shows up once the
+    /// requests making it up have finished, and requests in flight during a
+    /// scrape may count towards the peaks of both periods.
+    ///
+    /// By default no such gauge is created.
+    pub fn concurrency_max(mut self, reset_on_scrape: bool) -> Self {
+        self.concurrency_max = Some(reset_on_scrape);
+        self
+    }
+
+    /// Log the timestamps captured for each request at `trace` level, for
+    /// profiling exactly what the duration histograms measure.
+    ///
+    /// Each line gives the offset from the start of the request (when the
+    /// fairing's `on_request` callback ran) of the handler start mark (see
+    /// [`PrometheusMetricsBuilder::guard_handler_durations`]) and of the
+    /// response (when the fairing's `on_response` callback ran), along with
+    /// any time excluded using [`ExcludedTime`](crate::ExcludedTime). No
+    /// metrics are affected. When disabled, or when `trace` logging is
+    /// disabled, nothing extra is done per request.
+    ///
+    /// Defaults to `false`.
+    pub fn trace_timings(mut self, enabled: bool) -> Self {
+        self.trace_timings = enabled;
+        self
+    }
+
+    /// Log, at `trace` level, when each instance of this fairing saw each
+    /// request, to help diagnose interactions with other fairings.
+    ///
+    /// Each instance's `on_request` and `on_response` callbacks record the
+    /// request's method and URI as they see it, along with whether an earlier
+    /// instance already set the request's start time and which route it was
+    /// matched to. Each event is stamped with its position among the events
+    /// recorded for the request, and the events so far are logged from
+    /// `on_response`. Differences between what `on_request` and
+    /// `on_response` saw show that another fairing modified the request in
+    /// between. Fairings run in the order they were attached, so attaching
+    /// this fairing first makes its durations cover other fairings' work.
+    ///
+    /// When disabled, or when `trace` logging is disabled, nothing extra is
+    /// done per request.
+    ///
+    /// Defaults to `false`.
+    pub fn trace_fairing_order(mut self, enabled: bool) -> Self {
+        self.trace_fairing_order = enabled;
+        self
+    }
+
+    /// Expose request duration quantiles per endpoint, computed in-process
+    /// over the last `window` requests to each endpoint.
+    ///
+    /// This adds three gauges (labels: endpoint):
+    /// `http_requests_duration_p50_seconds`,
+    /// `http_requests_duration_p95_seconds` and
+    /// `http_requests_duration_p99_seconds`, computed when metrics are
+    /// scraped. It's intended for setups without a Prometheus server to
+    /// compute quantiles from the histogram.
+    ///
+    /// Up to `window` durations are kept in memory for each endpoint, and
+    /// every scrape sorts them, so memory and scrape time grow with the window
+    /// size and number of endpoints. In exchange the quantiles are exact for
+    /// the window rather than estimated from bucket boundaries. However, like
+    /// summaries, they can't be meaningfully aggregated across instances, and
+    /// a window of recent requests reflects a varying time span depending on
+    /// traffic.
+    ///
+    /// By default no quantiles are computed.
+    pub fn rolling_quantiles(mut self, window: usize) -> Self {
+        self.rolling_quantiles_window = Some(window);
+        self
+    }
+
+    /// Add an `http_requests_active_window` gauge (same labels as
+    /// `http_requests_total`) counting the requests completed during the
+    /// last `window`.
+    ///
+    /// Unlike the counter, this is unaffected by restarts, so dashboards can
+    /// show a smoothed request rate without `rate()`. Prometheus can compute
+    /// rates from the counter itself, so this is only useful where that
+    /// isn't an option. The window is divided into ten slots, so requests
+    /// leave the count in steps of a tenth of the window. A small count is
+    /// kept in memory for every slot of every series.
+    ///
+    /// ```rust
+    /// use std::time::Duration;
+    ///
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let prometheus = PrometheusMetrics::builder()
+    ///     .active_window(Duration::from_secs(60))
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// Building fails if `window` is zero. By default no such gauge is
+    /// created.
+    pub fn active_window(mut self, window: Duration) -> Self {
+        self.active_window = Some(window);
+        self
+    }
+
+    /// Remove series of `http_requests_total` and
+    /// `http_requests_duration_seconds` which haven't been observed for
+    /// `retention`.
+    ///
+    /// Label values such as endpoints only requested for a short while
+    /// otherwise stay in memory, and in the output, for the lifetime of the
+    /// process. The time each series was last observed is tracked, and
+    /// expired series are removed when metrics are next gathered, so memory
+    /// for a series is only released at the first scrape after it expires.
+    ///
+    /// Removing a series resets it: if the same label values are observed
+    /// again the series starts from zero, which Prometheus treats as a
+    /// counter reset. `rate()` and `increase()` handle this, but the series
+    /// is absent while expired, so queries over a range including the gap
+    /// only see the requests on either side of it. Choose a retention well
+    /// above the scrape interval and the range of any `rate()` queries.
+    ///
+    /// ```rust
+    /// use std::time::Duration;
+    ///
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let prometheus = PrometheusMetrics::builder()
+    ///     .series_retention(Duration::from_secs(6 * 60 * 60))
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// Per-method histograms and the other built-in metrics are unaffected.
+    /// Building fails if `retention` is zero. By default series are never
+    /// removed.
+    pub fn series_retention(mut self, retention: Duration) -> Self {
+        self.series_retention = Some(retention);
+        self
+    }
+
+    /// Set a soft limit on the number of series in each metric family.
+    ///
+    /// Whenever metrics are scraped, any family (from either the custom or the
+    /// internal registry) with more than `max_series` series causes a warning
+    /// to be logged and the `metrics_cardinality_warnings_total` counter to be
+    /// incremented. All metrics are still served as normal.
+    ///
+    /// Since the check happens while gathering, increments made during a
+    /// scrape are only visible in the following scrape.
+    ///
+    /// By default no budget is configured.
+    pub fn series_budget(mut self, max_series: usize) -> Self {
+        self.series_budget = Some(max_series);
+        self
+    }
+
+    /// Add a `profile` label containing the name of the active Rocket config
+    /// profile (e.g. `debug` or `release`) to the built-in metrics.
+    ///
+    /// The profile isn't known until the application lifts off, after the
+    /// metrics have been created, so it can't be set as a const label.
+    /// Instead it's read from Rocket's figment in the fairing's `on_liftoff`
+    /// callback and added to each built-in series when metrics are gathered
+    /// by the handler. Metrics gathered before liftoff won't include it, and
+    /// metrics in the custom registry are never labelled. Building fails if
+    /// a [const label](Self::const_labels) or a renamed label such as the
+    /// [endpoint label](Self::endpoint_label_name) is also named `profile`.
+    ///
+    /// Defaults to `false`.
+    pub fn profile_label(mut self, enabled: bool) -> Self {
+        self.profile_label = enabled;
+        self
+    }
+
+    /// Also count every request in `http_requests_total` under a synthetic
+    /// `endpoint="__all__"` series (still labelled by method and status).
+    ///
+    /// This provides a global request rate without summing over every
+    /// endpoint label value. Note that summing `http_requests_total` across
+    /// all endpoints will double count requests when this is enabled.
+    ///
+    /// Defaults to `false`.
+    pub fn aggregate_endpoints(mut self, enabled: bool) -> Self {
+        self.aggregate_endpoints = enabled;
+        self
+    }
+
+    /// Don't record requests to the given routes in the built-in metrics,
+    /// e.g. to leave out health checks or the metrics endpoint itself.
+    ///
+    /// ```rust
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let prometheus = PrometheusMetrics::builder()
+    ///     .ignore_routes(["/health", "/metrics"])
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// Routes are matched against the full URI of the route the request
+    /// matched, including its mount point and any dynamic segments or query
+    /// parameters, e.g. `/users/<id>?<fields>`, so matching is stable across
+    /// different path parameters. Ignored requests are still included in
+    /// `http_requests_in_flight` (and other metrics recorded before the
+    /// route is known), but in no metrics labelled by endpoint.
+    ///
+    /// By default all routes are recorded.
+    pub fn ignore_routes<I, S>(mut self, routes: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.ignored_routes
+            .extend(routes.into_iter().map(Into::into));
+        self
+    }
+
+    /// Don't record requests with the given methods in the built-in metrics,
+    /// e.g. to leave out CORS preflight `OPTIONS` requests.
+    ///
+    /// ```rust
+    /// use rocket::http::Method;
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let prometheus = PrometheusMetrics::builder()
+    ///     .ignore_methods([Method::Options, Method::Head])
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// Methods are matched against the method of the request itself, not of
+    /// the route which handled it, so `HEAD` requests handled by a `GET`
+    /// route are ignored if `HEAD` is. As with
+    /// [`PrometheusMetricsBuilder::ignore_routes`], ignored requests are
+    /// still included in metrics recorded before the response is sent.
+    ///
+    /// By default requests with any method are recorded.
+    pub fn ignore_methods<I>(mut self, methods: I) -> Self
+    where
+        I: IntoIterator<Item = Method>,
+    {
+        self.ignored_methods.extend(methods);
+        self
+    }
+
+    /// Register the routes serving metrics for the given methods, e.g. for
+    /// monitoring proxies which scrape using `POST`.
+    ///
+    /// ```rust
+    /// use rocket::http::Method;
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let prometheus = PrometheusMetrics::builder()
+    ///     .handler_methods([Method::Get, Method::Post])
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// The response is the same whatever the method, and any request body is
+    /// ignored. `HEAD` requests are also handled as long as `GET` is
+    /// included. Building fails if `methods` is empty.
+    ///
+    /// Defaults to `GET` only.
+    pub fn handler_methods<I>(mut self, methods: I) -> Self
+    where
+        I: IntoIterator<Item = Method>,
+    {
+        let mut handler_methods: Vec<Method> = vec![];
+        for method in methods {
+            if !handler_methods.contains(&method) {
+                handler_methods.push(method);
+            }
+        }
+        self.handler_methods = Some(handler_methods);
+        self
+    }
+
+    /// Add a `summary` route, e.g. `/metrics/summary`, serving a plain text
+    /// table of the estimated p50, p95 and p99 request durations of each
+    /// endpoint, for a quick look without running PromQL:
+    ///
+    /// ```text
+    /// # Estimated from the buckets of rocket_http_requests_duration_seconds
+    /// endpoint           count        p50        p95        p99
+    /// /hello/<name>         42     0.0031     0.0093     0.0099
+    /// /metrics               7     0.0009     0.0048     0.0050
+    /// ```
+    ///
+    /// The quantiles are estimated from `http_requests_duration_seconds`
+    /// since the process started (or the metrics were last reset),
+    /// aggregating all methods and statuses, in the same way as PromQL's
+    /// `histogram_quantile`. They're only as precise as the buckets allow,
+    /// and are in the configured
+    /// [duration unit](PrometheusMetricsBuilder::duration_unit). If methods
+    /// of an endpoint have different
+    /// [buckets](PrometheusMetricsBuilder::method_buckets), only the bucket
+    /// bounds they share are used. The route handles the same
+    /// [methods](Self::handler_methods) as the metrics routes, and is
+    /// protected in the same way.
+    ///
+    /// Defaults to `false`.
+    pub fn quantile_table_route(mut self, enabled: bool) -> Self {
+        self.quantile_table_route = enabled;
+        self
+    }
+
+    /// Don't record requests handled by the routes serving metrics, i.e.
+    /// those added by mounting a [`PrometheusMetrics`] instance.
+    ///
+    /// Scrapes otherwise count towards the built-in metrics, inflating
+    /// request rates and skewing latencies with fast metric responses. The
+    /// routes are recognised by name, so this works wherever they are
+    /// mounted, and also excludes scrapes of other instances. As with
+    /// [`PrometheusMetricsBuilder::ignore_routes`], excluded requests are
+    /// still included in `http_requests_in_flight`.
+    ///
+    /// Defaults to `false`.
+    pub fn exclude_metrics_route(mut self, enabled: bool) -> Self {
+        self.exclude_metrics_route = enabled;
+        self
+    }
+
+    /// Only observe request durations for the given endpoints.
+    ///
+    /// Requests to any other endpoint are still counted in
+    /// `http_requests_total`, but aren't observed in the
+    /// `http_requests_duration_seconds` histogram. This limits the memory
+    /// used by the histogram to the endpoints whose latency matters.
+    ///
+    /// Endpoints are compared against the `endpoint` label value, i.e. the
+    /// route URI (such as `/hello/<name>`) or the group name if the route was
+    /// grouped using [`PrometheusMetricsBuilder::group_prefix`].
+    ///
+    /// By default durations are observed for all endpoints.
+    pub fn duration_endpoints<I, S>(mut self, endpoints: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.duration_endpoints = Some(endpoints.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Stream the metrics served by the handler, encoding one metric family at
+    /// a time as the response body is sent.
+    ///
+    /// For registries with a very large number of series this avoids building
+    /// the whole encoded output in memory before responding, reducing peak
+    /// memory usage and time to first byte. The metrics are still gathered
+    /// up front. Since the response status is sent before encoding starts,
+    /// any family which fails to encode is logged and omitted rather than
+    /// causing an error response.
+    ///
+    /// Streamed output is never compressed.
+    ///
+    /// Defaults to `false`, meaning the output is encoded into a single buffer.
+    pub fn stream_output(mut self, enabled: bool) -> Self {
+        self.stream_output = enabled;
+        self
+    }
+
+    /// Override the `Content-Type` of responses in the Prometheus text
+    /// format, for scrapers which expect a different version or parameters.
+    ///
+    /// The content type otherwise depends on the format negotiated with the
+    /// scraper: `text/plain; version=0.0.4; charset=utf-8` for the Prometheus
+    /// text format, `application/openmetrics-text; version=1.0.0;
+    /// charset=utf-8` for OpenMetrics, and so on. Only the text format is
+    /// affected by this override, and the body is unchanged.
+    ///
+    /// ```rust
+    /// use rocket::http::ContentType;
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let prometheus = PrometheusMetrics::builder()
+    ///     .text_content_type(ContentType::new("text", "plain").with_params(("version", "0.0.4")))
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// Defaults to `text/plain; version=0.0.4; charset=utf-8`.
+    pub fn text_content_type(mut self, content_type: ContentType) -> Self {
+        self.text_content_type = Some(content_type);
+        self
+    }
+
+    /// Encode served metrics with the given encoder, returning them with the
+    /// given `Content-Type`, e.g. for a nonstandard exposition format
+    /// expected by an internal aggregator.
+    ///
+    /// The encoder is used for every scrape, whatever formats the scraper
+    /// accepts, and receives the gathered metric families sorted by name.
+    /// Since the encoder may need all families at once,
+    /// [`PrometheusMetricsBuilder::stream_output`] is ignored, and the
+    /// [comment](Self::comment) and exemplars aren't written. Responses are
+    /// still compressed and cached as configured.
+    ///
+    /// ```rust
+    /// use std::io::Write;
+    ///
+    /// use prometheus::{proto::MetricFamily, Encoder};
+    /// use rocket::http::ContentType;
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// /// Writes the name of each metric family on its own line.
+    /// struct NameEncoder;
+    ///
+    /// impl Encoder for NameEncoder {
+    ///     fn encode<W: Write>(
+    ///         &self,
+    ///         families: &[MetricFamily],
+    ///         writer: &mut W,
+    ///     ) -> prometheus::Result<()> {
+    ///         for family in families {
+    ///             writeln!(writer, "{}", family.get_name())?;
+    ///         }
+    ///         Ok(())
+    ///     }
+    ///
+    ///     fn format_type(&self) -> &str {
+    ///         "text/plain"
+    ///     }
+    /// }
+    ///
+    /// let prometheus = PrometheusMetrics::builder()
+    ///     .encoder(NameEncoder, ContentType::Plain)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// By default the format is negotiated with the scraper, defaulting to
+    /// the Prometheus text format.
+    pub fn encoder<E>(mut self, encoder: E, content_type: ContentType) -> Self
+    where
+        E: Encoder + Send + Sync + 'static,
+    {
+        self.custom_encoder = Some(CustomEncoder::new(encoder, content_type));
+        self
+    }
+
+    /// Serve the same encoded metrics to scrapes for up to `ttl`, rather than
+    /// gathering and encoding them for every scrape.
+    ///
+    /// This reduces the cost of frequent scrapes, e.g. by several Prometheus
+    /// replicas, of applications with many series, at the cost of serving
+    /// values up to `ttl` old. Output is cached separately for each
+    /// exposition format, and compressed for each scrape. Streamed output
+    /// (see [`PrometheusMetricsBuilder::stream_output`]) isn't cached.
+    ///
+    /// Defaults to zero, meaning every scrape gathers fresh metrics.
+    pub fn cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = ttl;
+        self
+    }
+
+    /// Require scrapes to pass `token` as the `token` query parameter, e.g.
+    /// `/metrics?token=...`.
+    ///
+    /// Requests with a missing or incorrect token are rejected with
+    /// `403 Forbidden`. Tokens are compared in constant time. This is a
+    /// lightweight alternative to header-based authentication for scrapers
+    /// which can only add query parameters. Note that query strings are often
+    /// recorded in access logs, so the token should be treated as such.
+    ///
+    /// By default no token is required.
+    pub fn require_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Require scrapes to authenticate with HTTP Basic authentication, using
+    /// the given user and password.
+    ///
+    /// Requests with a missing or incorrect `Authorization` header are
+    /// rejected with `401 Unauthorized` and a `WWW-Authenticate` challenge.
+    /// Credentials are compared in constant time. Basic authentication sends
+    /// the password in plain text, so the endpoint should be served over TLS.
+    ///
+    /// By default no credentials are required.
+    pub fn basic_auth(mut self, user: &str, password: &str) -> Self {
+        self.basic_auth = Some(BasicAuth::new(user, password));
+        self
+    }
+
+    /// Only allow scrapes from the given IP addresses.
+    ///
+    /// The client's address is determined by [`Request::client_ip`], so it is
+    /// taken from Rocket's configured `ip_header` (`X-Real-IP` by default) if
+    /// present. That header can be set by any client, so it should only be
+    /// relied on behind a proxy which overwrites it; use
+    /// [`PrometheusMetricsBuilder::client_ip_header`] to only trust it from
+    /// known proxies. Requests from other
+    /// addresses, or whose address can't be determined, are rejected with
+    /// `403 Forbidden`. IPv4-mapped IPv6 addresses match their IPv4
+    /// equivalents. Calling this more than once extends the allowlist.
+    ///
+    /// By default requests from any address are allowed.
+    ///
+    /// [`Request::client_ip`]: rocket::Request::client_ip
+    pub fn allow_ips<I>(mut self, ips: I) -> Self
+    where
+        I: IntoIterator<Item = IpAddr>,
+    {
+        self.allowed_ips
+            .get_or_insert_with(HashSet::new)
+            .extend(ips.into_iter().map(|ip| ip.to_canonical()));
+        self
+    }
+
+    /// Determine the client's address from the given header, such as
+    /// `X-Forwarded-For` or `X-Real-IP`, when the request comes from one of
+    /// `trusted_proxies`, for the IP-based features such as
+    /// [`PrometheusMetricsBuilder::allow_ips`].
+    ///
+    /// Behind a load balancer the socket peer is the load balancer, not the
+    /// client. The header is only used if the peer is one of the trusted
+    /// proxies; otherwise, or if the header is missing or contains an
+    /// invalid address, the peer's own address is used, so clients can't
+    /// spoof their address by sending the header themselves. Rocket's
+    /// `ip_header` setting is ignored.
+    ///
+    /// The header may contain a comma-separated list of addresses, as
+    /// appended to by each proxy. The client is taken to be the last address
+    /// which isn't a trusted proxy, since anything before it may have been
+    /// sent by the client.
+    ///
+    /// ```rust
+    /// use std::net::{IpAddr, Ipv4Addr};
+    ///
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let prometheus = PrometheusMetrics::builder()
+    ///     .client_ip_header("X-Forwarded-For", [IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))])
+    ///     .allow_ips([IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7))])
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// By default the address is determined by [`Request::client_ip`].
+    ///
+    /// [`Request::client_ip`]: rocket::Request::client_ip
+    pub fn client_ip_header<I>(mut self, header: &str, trusted_proxies: I) -> Self
+    where
+        I: IntoIterator<Item = IpAddr>,
+    {
+        let trusted_proxies = trusted_proxies
+            .into_iter()
+            .map(|ip| ip.to_canonical())
+            .collect();
+        self.proxy_header = Some((header.to_owned(), trusted_proxies));
+        self
+    }
+
+    /// Write a comment block before the metrics served by the handler.
+    ///
+    /// Each line of `comment` is written as a `# ` comment line, which
+    /// standard scrapers ignore. Lines must not start with `HELP` or `TYPE`,
+    /// since scrapers would interpret those as metric metadata.
+    ///
+    /// ```rust
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let prometheus = PrometheusMetrics::builder()
+    ///     .comment("Produced by my-service")
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// By default no comment is written.
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    /// Log an error if the instance was attached as a fairing but not mounted
+    /// as a handler, or vice versa.
+    ///
+    /// Both steps are needed for metrics to be recorded and served, and
+    /// missing one of them is a common cause of metrics not showing up. A
+    /// missing handler is reported when the application lifts off. Since an
+    /// unattached fairing never runs, a missing fairing can only be reported
+    /// by the handler, the first time metrics are scraped.
+    ///
+    /// Enabling this is recommended.
+    ///
+    /// Defaults to `false`.
+    pub fn check_setup(mut self, enabled: bool) -> Self {
+        self.setup_check = enabled;
+        self
+    }
+
+    /// Add resource attributes, such as the service name, instance or
+    /// version, to a `target_info` metric describing the application.
+    ///
+    /// OpenMetrics (and OpenTelemetry) expose attributes of the scraped
+    /// target as the labels of a `target_info` series with the value `1`,
+    /// which can be joined onto other series in queries. It's only written
+    /// when metrics are scraped as OpenMetrics, the only format which defines
+    /// it; other formats are unaffected. The metric isn't prefixed by the
+    /// namespace.
+    ///
+    /// ```rust
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let prometheus = PrometheusMetrics::builder()
+    ///     .target_info([("service_name", "my-service"), ("service_version", "1.2.3")])
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// Calling this more than once adds to the existing attributes, replacing
+    /// any with the same name. Building fails if a name isn't a valid
+    /// Prometheus label name.
+    ///
+    /// By default no `target_info` metric is written.
+    pub fn target_info<I, K, V>(mut self, attributes: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.target_info.extend(
+            attributes
+                .into_iter()
+                .map(|(name, value)| (name.into(), value.into())),
+        );
+        self
+    }
+
+    /// Only record exemplars for requests which took longer than
+    /// `threshold`.
+    ///
+    /// An exemplar for every bucket is mostly spent on fast, unremarkable
+    /// requests, so this focuses exemplars on the slow tail worth tracing.
+    /// Requests at or below the threshold are still observed by the
+    /// histogram, they just don't replace the exemplar of their bucket. As
+    /// with all exemplars, they only appear when metrics are scraped as
+    /// OpenMetrics.
+    ///
+    /// ```rust
+    /// use std::time::Duration;
+    ///
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let prometheus = PrometheusMetrics::builder()
+    ///     .exemplar_header("traceparent")
+    ///     .exemplar_threshold(Duration::from_millis(500))
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// By default exemplars are recorded for requests of any duration.
+    pub fn exemplar_threshold(mut self, threshold: Duration) -> Self {
+        self.exemplar_threshold = Some(threshold);
+        self
+    }
+
+    /// Build the [`PrometheusMetrics`] instance.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the built-in metrics could not be created or
+    /// registered, for example because the configured namespace is not a
+    /// valid metric name prefix, if any configured buckets are empty or not
+    /// in increasing order, if the rolling quantile window is zero, if a
+    /// line of the comment starts with `HELP`
+    /// or `TYPE`, or if the background recorder thread could not be spawned.
+    ///
+    /// It also fails if a `target_info` attribute isn't a valid label name.
+    pub fn build(self) -> Result<PrometheusMetrics, prometheus::Error> {
+        let target_info = (!self.target_info.is_empty())
+            .then(|| render_target_info(&self.target_info))
+            .transpose()?;
+        let comment = self.comment.as_deref().map(render_comment).transpose()?;
+        let handler_methods = self.handler_methods.unwrap_or_else(|| vec![Method::Get]);
+        if handler_methods.is_empty() {
+            return Err(prometheus::Error::Msg(
+                "handler methods must not be empty".into(),
+            ));
+        }
+
+        let rocket_registry = Registry::new();
+        let namespace = match self.namespace {
+            Some(namespace) => namespace,
+            None => env::var(NAMESPACE_ENV_VAR).unwrap_or_else(|_| "rocket".into()),
+        };
+        let mut const_labels = self.const_labels;
+        for name in const_labels.keys() {
+            check_label_name(name)?;
+        }
+        if let Some(fairing) = self.fairing_label {
+            if const_labels.contains_key("fairing") {
+                return Err(prometheus::Error::Msg(
+                    "the fairing label can't also be set as a const label".into(),
+                ));
+            }
+            const_labels.insert("fairing".to_owned(), fairing);
+        }
+        let factory = MetricFactory {
+            namespace: &namespace,
+            const_labels: &const_labels,
+            registry: &rocket_registry,
+        };
+
+        let label_names = self.label_names;
+        for name in [
+            &label_names.endpoint,
+            &label_names.method,
+            &label_names.status,
+        ] {
+            check_label_name(name)?;
+        }
+        let endpoint_label = label_names.endpoint.as_str();
+        let method_label = label_names.method.as_str();
+        let status_label = label_names.status.as_str();
+        if self.profile_label
+            && (const_labels.contains_key("profile")
+                || [endpoint_label, method_label, status_label].contains(&"profile"))
+        {
+            return Err(prometheus::Error::Msg(
+                "the profile label can't also be set as a const label or label name".into(),
+            ));
+        }
+
+        // Labels used by the built-in request metrics.
+        let mut labels = vec![endpoint_label, method_label, status_label];
+        if self.request_id_header.is_some() {
+            labels.push("has_request_id");
+        }
+        if self.route_method_label {
+            labels.push("route_method");
+        }
+        if self.route_name_label {
+            labels.push("route_name");
+        }
+        if self.query_label {
+            labels.push("has_query");
+        }
+        if self.host_allowlist.is_some() {
+            labels.push("host");
+        }
+        if self.status_class_label {
+            if self.counter_status_class || self.histogram_status_class {
+                return Err(prometheus::Error::Msg(
+                    "the status_class label can't be added to metrics already labelled by status class".into(),
+                ));
+            }
+            labels.push("status_class");
+        }
+
+        // The counter and histogram may use the status class instead.
+        let with_status_class = |enabled: bool| -> Vec<&str> {
+            labels
+                .iter()
+                .map(|&label| {
+                    if enabled && label == status_label {
+                        "status_class"
+                    } else {
+                        label
+                    }
+                })
+                .collect()
+        };
+        let counter_labels = with_status_class(self.counter_status_class);
+        let histogram_labels = with_status_class(self.histogram_status_class);
+
+        let http_requests_total =
+            if self.status_code_counters.is_some() || self.request_counter_disabled {
+                // Disabled or replaced by the per-status counters, so not registered.
+                IntCounterVec::new(
+                    factory.opts("http_requests_total", "Total number of HTTP requests"),
+                    &counter_labels,
+                )?
+            } else {
+                factory.counter_vec(
+                    "http_requests_total",
+                    "Total number of HTTP requests",
+                    &counter_labels,
+                )?
+            };
+        let status_code_counters = self
+            .status_code_counters
+            .map(|codes| {
+                codes
+                    .into_iter()
+                    .map(|code| {
+                        let counter = factory.counter_vec(
+                            &format!("http_responses_{}_total", code),
+                            &format!("Total number of HTTP responses with status {}", code),
+                            &[endpoint_label, method_label],
+                        )?;
+                        Ok((code, counter))
+                    })
+                    .collect::<Result<HashMap<_, _>, prometheus::Error>>()
+                    .map(Arc::new)
+            })
+            .transpose()?;
+        let duration_histogram =
+            !self.duration_histogram_disabled && self.duration_summary.is_none();
+        let duration_unit = self.duration_unit;
+        let duration_name = format!("http_requests_duration_{}", duration_unit.name());
+        let duration_help = format!(
+            "HTTP request duration in {} for all requests",
+            duration_unit.name()
+        );
+        let default_buckets: Vec<f64> = prometheus::DEFAULT_BUCKETS
+            .iter()
+            .map(|bucket| bucket * duration_unit.per_second())
+            .collect();
+        let duration_buckets = self.buckets.as_deref().unwrap_or(&default_buckets);
+        // Registered along with any per-endpoint and per-method histograms
+        // below, if enabled.
+        let http_requests_duration_seconds = factory.unregistered_histogram_vec(
+            &duration_name,
+            &duration_help,
+            &histogram_labels,
+            duration_buckets,
+        )?;
+        let exemplar_trace_id = self.exemplar_trace_id;
+        let exemplar_threshold = self.exemplar_threshold;
+        let exemplars = self
+            .exemplar_header
+            .filter(|_| duration_histogram)
+            .map(|header| {
+                Arc::new(Exemplars::new(
+                    header,
+                    exemplar_trace_id.unwrap_or_else(|| Arc::new(default_trace_id)),
+                    factory.opts(&duration_name, &duration_help).fq_name(),
+                    histogram_labels
+                        .iter()
+                        .map(|&label| label.to_owned())
+                        .collect(),
+                    exemplar_threshold,
+                ))
+            });
+        let http_requests_duration_summary = self
+            .duration_summary
+            .map(|(quantiles, window)| {
+                let opts = factory.opts(&format!("{}_summary", duration_name), &duration_help);
+                let desc = Desc::new(
+                    opts.fq_name(),
+                    opts.help.clone(),
+                    histogram_labels
+                        .iter()
+                        .map(|&label| label.to_owned())
+                        .collect(),
+                    opts.const_labels.clone(),
+                )?;
+                factory.register(DurationSummary::new(desc, quantiles, window)?)
+            })
+            .transpose()?;
+        let http_requests_in_flight = factory.gauge(
+            "http_requests_in_flight",
+            "Number of HTTP requests currently being handled",
+        )?;
+        let bucketed_histogram = |buckets: &[f64]| {
+            let histogram = factory.unregistered_histogram_vec(
+                &duration_name,
+                &duration_help,
+                &histogram_labels,
+                buckets,
+            )?;
+            Ok::<_, prometheus::Error>((histogram, buckets.to_vec()))
+        };
+        let mut endpoint_histograms = HashMap::new();
+        for (endpoint, buckets) in self.endpoint_buckets.iter().filter(|_| duration_histogram) {
+            if endpoint_histograms
+                .insert(endpoint.clone(), bucketed_histogram(buckets)?)
+                .is_some()
+            {
+                return Err(prometheus::Error::Msg(format!(
+                    "duplicate endpoint buckets for {}",
+                    endpoint
+                )));
+            }
+        }
+        let method_histograms = self
+            .method_buckets
+            .iter()
+            .filter(|_| duration_histogram)
+            .map(|(method, buckets)| Ok((method.as_str().to_owned(), bucketed_histogram(buckets)?)))
+            .collect::<Result<HashMap<_, _>, prometheus::Error>>()?;
+        // The histograms share a name, so they're registered as one collector.
+        let duration_histograms = DurationHistograms::new(
+            (http_requests_duration_seconds, duration_buckets.to_vec()),
+            endpoint_histograms,
+            method_histograms,
+        );
+        if duration_histogram {
+            factory.register(duration_histograms.clone())?;
+        }
+
+        let guard_handler_durations = self
+            .guard_handler_durations
+            .then(|| {
+                Ok::<_, prometheus::Error>(GuardHandlerDurations {
+                    guards: factory.histogram_vec(
+                        "http_requests_guard_duration_seconds",
+                        "HTTP request duration in seconds spent before the handler started",
+                        &labels,
+                    )?,
+                    handler: factory.histogram_vec(
+                        "http_requests_handler_duration_seconds",
+                        "HTTP request duration in seconds spent after the handler started",
+                        &labels,
+                    )?,
+                })
+            })
+            .transpose()?;
+
+        let http_requests_controllable_duration_seconds = self
+            .controllable_durations
+            .then(|| {
+                factory.histogram_vec(
+                    "http_requests_controllable_duration_seconds",
+                    "HTTP request duration in seconds excluding time marked by the handler",
+                    &labels,
+                )
+            })
+            .transpose()?;
+
+        let request_size_buckets = self.request_size_buckets;
+        let http_request_size_bytes = self
+            .request_size_histogram
+            .then(|| {
+                let buckets = match request_size_buckets {
+                    Some(buckets) => buckets,
+                    None => prometheus::exponential_buckets(100.0, 10.0, 7)?,
+                };
+                factory.histogram_vec_with_buckets(
+                    "http_request_size_bytes",
+                    "HTTP request body size in bytes",
+                    &labels,
+                    &buckets,
+                )
+            })
+            .transpose()?;
+
+        let http_bad_content_type_total = self
+            .bad_content_type_counter
+            .then(|| {
+                factory.counter_vec(
+                    "http_bad_content_type_total",
+                    "Total number of HTTP requests with a missing or unexpected content type",
+                    &[endpoint_label, method_label],
+                )
+            })
+            .transpose()?;
+
+        let http_incomplete_bodies_total = self
+            .incomplete_bodies_counter
+            .then(|| {
+                factory.counter_vec(
+                    "http_incomplete_bodies_total",
+                    "Total number of HTTP requests whose body was reported by the application as not fully received",
+                    &[endpoint_label],
+                )
+            })
+            .transpose()?;
+
+        let http_conditional_requests_total = self
+            .conditional_requests_counter
+            .then(|| {
+                factory.counter_vec(
+                    "http_conditional_requests_total",
+                    "Total number of conditional HTTP requests by whether the full response was sent",
+                    &[endpoint_label, "result"],
+                )
+            })
+            .transpose()?;
+
+        let http_requests_received_total = self
+            .requests_received_counter
+            .then(|| {
+                factory.counter_vec(
+                    "http_requests_received_total",
+                    "Total number of HTTP requests received, including those not matching a route",
+                    &[method_label],
+                )
+            })
+            .transpose()?;
+
+        let http_aborted_requests_total = self
+            .aborted_requests_counter
+            .then(|| {
+                factory.counter_vec(
+                    "http_aborted_requests_total",
+                    "Total number of HTTP requests answered without matching a route",
+                    &["reason"],
+                )
+            })
+            .transpose()?;
+
+        let http_requests_by_hour_total = self
+            .requests_by_hour_counter
+            .map(|utc_offset_secs| {
+                Ok::<_, prometheus::Error>(HourlyCounter {
+                    counter: factory.counter_vec(
+                        "http_requests_by_hour_total",
+                        "Total number of HTTP requests by hour of the day",
+                        &[endpoint_label, "hour"],
+                    )?,
+                    utc_offset_secs: utc_offset_secs.map(i64::from),
+                })
+            })
+            .transpose()?;
+
+        let http_requests_errors_total = self
+            .errors_counter
+            .then(|| {
+                factory.counter_vec(
+                    "http_requests_errors_total",
+                    "Total number of HTTP requests answered with a server error status",
+                    &[endpoint_label, method_label],
+                )
+            })
+            .transpose()?;
+
+        let http_request_last_timestamp_seconds = self
+            .last_request_timestamp
+            .then(|| {
+                factory.gauge_vec(
+                    "http_request_last_timestamp_seconds",
+                    "Unix time in seconds at which the latest HTTP response was sent",
+                    &[endpoint_label, method_label],
+                )
+            })
+            .transpose()?;
+
+        let rolling_quantiles = self
+            .rolling_quantiles_window
+            .map(|window| {
+                if window == 0 {
+                    return Err(prometheus::Error::Msg(
+                        "rolling quantile window must not be empty".into(),
+                    ));
+                }
+                let gauge = |q: f64| {
+                    let percentile = (q * 100.0).round();
+                    GaugeVec::new(
+                        factory.opts(
+                            &format!("http_requests_duration_p{}_seconds", percentile),
+                            &format!(
+                                "p{} HTTP request duration in seconds over recent requests",
+                                percentile
+                            ),
+                        ),
+                        &[endpoint_label],
+                    )
+                };
+                let gauges = [
+                    gauge(QUANTILES[0])?,
+                    gauge(QUANTILES[1])?,
+                    gauge(QUANTILES[2])?,
+                ];
+                factory.register(RollingQuantiles::new(window, gauges))
+            })
+            .transpose()?;
+
+        let series_retention = self
+            .series_retention
+            .map(|retention| {
+                if retention.is_zero() {
+                    return Err(prometheus::Error::Msg(
+                        "series retention must not be zero".into(),
+                    ));
+                }
+                Ok(Arc::new(RetainedSeries::new(retention)))
+            })
+            .transpose()?;
+
+        let http_requests_active_window = self
+            .active_window
+            .map(|window| {
+                if window.is_zero() {
+                    return Err(prometheus::Error::Msg(
+                        "active request window must not be empty".into(),
+                    ));
+                }
+                let gauge = IntGaugeVec::new(
+                    factory.opts(
+                        "http_requests_active_window",
+                        &format!(
+                            "Number of HTTP requests completed in the last {} seconds",
+                            window.as_secs_f64()
+                        ),
+                    ),
+                    &counter_labels,
+                )?;
+                factory.register(RequestWindow::new(window, gauge))
+            })
+            .transpose()?;
+
+        let http_requests_concurrency_max = self
+            .concurrency_max
+            .map(|reset_on_scrape| {
+                let gauge = IntGaugeVec::new(
+                    factory.opts(
+                        "http_requests_concurrency_max",
+                        "Highest number of HTTP requests to an endpoint in flight at once",
+                    ),
+                    &[endpoint_label],
+                )?;
+                factory.register(ConcurrencyMax::new(gauge, reset_on_scrape))
+            })
+            .transpose()?;
+
+        let series_budget = self
+            .series_budget
+            .map(|max_series| {
+                let warnings = factory.counter(
+                    "metrics_cardinality_warnings_total",
+                    "Total number of times a metric family exceeded the series budget",
+                )?;
+                Ok::<_, prometheus::Error>(SeriesBudget {
+                    max_series,
+                    warnings,
+                })
+            })
+            .transpose()?;
+
+        if self.process_metrics {
+            #[cfg(all(feature = "process", target_os = "linux"))]
+            rocket_registry.register(Box::new(
+                prometheus::process_collector::ProcessCollector::for_self(),
+            ))?;
+            #[cfg(not(all(feature = "process", target_os = "linux")))]
+            log::warn!(
+                "process metrics require the `process` feature and Linux, so won't be exposed"
+            );
+        }
+
+        if let Some((git_sha, build_date)) = &self.build_info {
+            let build_info = IntGaugeVec::new(
+                Opts::new(
+                    "rocket_prometheus_build_info",
+                    "Build information, with a constant value of 1",
+                ),
+                &["version", "git_sha", "build_date"],
+            )?;
+            build_info
+                .with_label_values(&[env!("CARGO_PKG_VERSION"), git_sha, build_date])
+                .set(1);
+            rocket_registry.register(Box::new(build_info))?;
+        }
+
+        let cache_ttl = self.cache_ttl;
+        // Only capture start times if a metric needs them.
+        let time_requests = duration_histogram
+            || http_requests_duration_summary.is_some()
+            || self.guard_handler_durations
+            || self.controllable_durations
+            || self.trace_timings
+            || !self.response_callbacks.is_empty()
+            || rolling_quantiles.is_some()
+            || http_requests_concurrency_max.is_some();
+        let http_requests_missing_timer_total = time_requests
+            .then(|| {
+                factory.counter_vec(
+                    "http_requests_missing_timer_total",
+                    "Total number of HTTP requests without a start time, which aren't otherwise counted",
+                    &[endpoint_label, method_label],
+                )
+            })
+            .transpose()?;
+        let mut metrics = PrometheusMetrics {
+            http_requests_total,
+            duration_histograms,
+            http_requests_duration_summary,
+            exemplars,
+            http_requests_in_flight,
+            rocket_registry,
+            custom_registry: self.registry.unwrap_or_else(Registry::new),
+            extra_collectors: Arc::default(),
+            endpoint_groups: self.endpoint_groups,
+            endpoint_style: self.endpoint_style,
+            duration_unit,
+            method_source: self.method_source,
+            endpoint_rates: self
+                .rare_endpoints
+                .map(|(min_requests, window)| EndpointRates::new(min_requests, window)),
+            endpoint_cap: self.max_endpoints.map(EndpointCap::new),
+            background_recorder: None,
+            status_mapper: self.status_mapper,
+            endpoint_mapper: self.endpoint_mapper,
+            response_callbacks: Arc::new(self.response_callbacks),
+            http_bad_content_type_total,
+            http_incomplete_bodies_total,
+            http_conditional_requests_total,
+            http_aborted_requests_total,
+            http_requests_received_total,
+            status_code_counters,
+            http_requests_by_hour_total,
+            http_request_last_timestamp_seconds,
+            http_requests_errors_total,
+            http_requests_missing_timer_total,
+            guard_handler_durations,
+            rolling_quantiles,
+            http_requests_active_window,
+            series_retention,
+            http_requests_controllable_duration_seconds,
+            http_request_size_bytes,
+            http_requests_concurrency_max,
+            series_budget,
+            profile: self.profile_label.then(|| Arc::new(OnceLock::new())),
+            event_counters: Arc::new(Mutex::new(HashMap::new())),
+            duration_endpoints: self.duration_endpoints.map(Arc::new),
+            label_names: Arc::new(label_names),
+            ignored_routes: Arc::new(self.ignored_routes),
+            ignored_methods: Arc::new(self.ignored_methods),
+            exclude_metrics_route: self.exclude_metrics_route,
+            comment,
+            target_info,
+            counter_status_class: self.counter_status_class,
+            histogram_status_class: self.histogram_status_class,
+            aggregate_endpoints: self.aggregate_endpoints,
+            request_counter: !self.request_counter_disabled,
+            duration_histogram,
+            time_requests,
+            track_unmatched: self.track_unmatched,
+            catcher_endpoints: self.catcher_endpoints,
+            stream_output: self.stream_output,
+            text_content_type: self.text_content_type,
+            custom_encoder: self.custom_encoder.map(Arc::new),
+            output_cache: (!cache_ttl.is_zero()).then(|| Arc::new(OutputCache::new(cache_ttl))),
+            request_id_header: self.request_id_header,
+            token: self.token.map(Into::into),
+            basic_auth: self.basic_auth.map(Arc::new),
+            allowed_ips: self.allowed_ips.map(Arc::new),
+            proxy_header: self.proxy_header.map(|(header, trusted_proxies)| {
+                Arc::new(ProxyHeader::new(header, trusted_proxies))
+            }),
+            trace_timings: self.trace_timings,
+            trace_fairing_order: self.trace_fairing_order,
+            route_method_label: self.route_method_label,
+            route_name_label: self.route_name_label,
+            query_label: self.query_label,
+            host_allowlist: self.host_allowlist.map(Arc::new),
+            status_class_label: self.status_class_label,
+            served_registries: Registries::All,
+            handler_methods: Arc::new(handler_methods),
+            quantile_table_route: self.quantile_table_route,
+            serves_quantile_table: false,
+            setup_check: self.setup_check.then(Default::default),
+        };
+
+        if self.record_in_background {
+            let (sender, receiver) = mpsc::channel::<Observation>();
+            // The recorder must not hold a sender itself, otherwise the
+            // channel would never close and the thread would never exit.
+            let recorder = metrics.clone();
+            thread::Builder::new()
+                .name("rocket-prometheus-recorder".into())
+                .spawn(move || {
+                    for obs in receiver {
+                        let status = StatusCode::from(obs.labels.code);
+                        let labels = obs.labels.values(&status);
+                        recorder.record(&labels, obs.duration, obs.trace_id);
+                    }
+                })?;
+            metrics.background_recorder = Some(BackgroundRecorder {
+                sender,
+                label_values: Arc::default(),
+            });
+        }
+
+        Ok(metrics)
+    }
+}
+
+/// Creates built-in metrics using the configured namespace and const labels,
+/// registering them on the internal registry.
+struct MetricFactory<'a> {
+    namespace: &'a str,
+    const_labels: &'a HashMap<String, String>,
+    registry: &'a Registry,
+}
+
+impl MetricFactory<'_> {
+    fn opts(&self, name: &str, help: &str) -> Opts {
+        Opts::new(name, help)
+            .namespace(self.namespace)
+            .const_labels(self.const_labels.clone())
+    }
+
+    fn register<C: Collector + Clone + 'static>(
+        &self,
+        collector: C,
+    ) -> Result<C, prometheus::Error> {
+        self.registry.register(Box::new(collector.clone()))?;
+        Ok(collector)
+    }
+
+    fn counter(&self, name: &str, help: &str) -> Result<IntCounter, prometheus::Error> {
+        self.register(IntCounter::with_opts(self.opts(name, help))?)
+    }
+
+    fn gauge(&self, name: &str, help: &str) -> Result<IntGauge, prometheus::Error> {
+        self.register(IntGauge::with_opts(self.opts(name, help))?)
+    }
+
+    fn gauge_vec(
+        &self,
+        name: &str,
+        help: &str,
+        labels: &[&str],
+    ) -> Result<GaugeVec, prometheus::Error> {
+        self.register(GaugeVec::new(self.opts(name, help), labels)?)
+    }
+
+    fn counter_vec(
+        &self,
+        name: &str,
+        help: &str,
+        labels: &[&str],
+    ) -> Result<IntCounterVec, prometheus::Error> {
+        self.register(IntCounterVec::new(self.opts(name, help), labels)?)
+    }
+
+    fn histogram_vec_with_buckets(
+        &self,
+        name: &str,
+        help: &str,
+        labels: &[&str],
+        buckets: &[f64],
+    ) -> Result<HistogramVec, prometheus::Error> {
+        self.register(self.unregistered_histogram_vec(name, help, labels, buckets)?)
+    }
+
+    /// Create a histogram vec without registering it, so that it can be
+    /// registered as part of another collector.
+    fn unregistered_histogram_vec(
+        &self,
+        name: &str,
+        help: &str,
+        labels: &[&str],
+        buckets: &[f64],
+    ) -> Result<HistogramVec, prometheus::Error> {
+        check_buckets(buckets)?;
+        let opts = HistogramOpts::from(self.opts(name, help)).buckets(buckets.to_vec());
+        HistogramVec::new(opts, labels)
+    }
+
+    fn histogram_vec(
+        &self,
+        name: &str,
+        help: &str,
+        labels: &[&str],
+    ) -> Result<HistogramVec, prometheus::Error> {
+        self.register(HistogramVec::new(self.opts(name, help).into(), labels)?)
+    }
+}
+
+/// Check that histogram buckets are non-empty and strictly increasing.
+fn check_buckets(buckets: &[f64]) -> Result<(), prometheus::Error> {
+    if buckets.is_empty() {
+        return Err(prometheus::Error::Msg(
+            "histogram buckets must not be empty".into(),
+        ));
+    }
+    if buckets.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return Err(prometheus::Error::Msg(format!(
+            "histogram buckets must be in increasing order: {:?}",
+            buckets
+        )));
+    }
+    Ok(())
+}
+
+/// Check that `name` is a valid, non-reserved Prometheus label name.
+fn check_label_name(name: &str) -> Result<(), prometheus::Error> {
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !name.starts_with("__");
+    if valid {
+        Ok(())
+    } else {
+        Err(prometheus::Error::Msg(format!(
+            "invalid label name: {:?}",
+            name
+        )))
+    }
+}
+
+/// Render the OpenMetrics `target_info` family with the given attributes as
+/// labels.
+fn render_target_info(
+    attributes: &BTreeMap<String, String>,
+) -> Result<Arc<str>, prometheus::Error> {
+    let mut labels = vec![];
+    for (name, value) in attributes {
+        check_label_name(name)?;
+        let value = value
+            .replace('\\', r"\\")
+            .replace('\n', r"\n")
+            .replace('"', r#"\""#);
+        labels.push(format!("{}=\"{}\"", name, value));
+    }
+    Ok(format!(
+        "# TYPE target info\n# HELP target Target metadata\ntarget_info{{{}}} 1\n",
+        labels.join(",")
+    )
+    .into())
+}
+
+/// Render a (possibly multi-line) comment as Prometheus comment lines.
+fn render_comment(comment: &str) -> Result<Arc<str>, prometheus::Error> {
+    let mut rendered = String::new();
+    for line in comment.lines() {
+        let first_token = line.split_whitespace().next();
+        if matches!(first_token, Some("HELP" | "TYPE")) {
+            return Err(prometheus::Error::Msg(format!(
+                "comment line {:?} would be parsed as metric metadata",
+                line
+            )));
+        }
+        rendered.push_str("# ");
+        rendered.push_str(line);
+        rendered.push('\n');
+    }
+    Ok(rendered.into())
+}
@@ -0,0 +1,56 @@
+//! Caching of the encoded metrics output.
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use crate::{format::Format, Registries};
+
+/// Encoded metrics, reused until they are older than a time-to-live.
+pub(crate) struct OutputCache {
+    ttl: Duration,
+    // Entries are keyed by what was encoded, since the handler's routes serve
+    // different registries and scrapers may negotiate different formats.
+    entries: Mutex<HashMap<(Registries, Format), (Instant, Vec<u8>)>>,
+}
+
+impl OutputCache {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::default(),
+        }
+    }
+
+    /// Get the cached output for `key` if it hasn't expired, otherwise
+    /// encode it afresh with `encode` and cache the result.
+    ///
+    /// The lock is held while encoding, so concurrent scrapes of an expired
+    /// entry wait for a single encoding rather than each gathering metrics.
+    /// Errors aren't cached.
+    pub(crate) fn get_or_encode<E>(
+        &self,
+        key: (Registries, Format),
+        encode: impl FnOnce() -> Result<Vec<u8>, E>,
+    ) -> Result<Vec<u8>, E> {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some((encoded_at, output)) = entries.get(&key) {
+            if encoded_at.elapsed() < self.ttl {
+                return Ok(output.clone());
+            }
+        }
+        let output = encode()?;
+        entries.insert(key, (Instant::now(), output.clone()));
+        Ok(output)
+    }
+
+    /// Discard all cached output.
+    pub(crate) fn clear(&self) {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+}
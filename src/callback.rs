@@ -0,0 +1,97 @@
+//! Metrics whose values are computed when metrics are gathered.
+
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    Gauge,
+};
+
+/// A gauge which is set from a callback each time it is collected.
+pub(crate) struct CallbackGauge {
+    gauge: Gauge,
+    callback: Box<dyn Fn() -> f64 + Send + Sync>,
+}
+
+impl CallbackGauge {
+    pub(crate) fn new(gauge: Gauge, callback: Box<dyn Fn() -> f64 + Send + Sync>) -> Self {
+        Self { gauge, callback }
+    }
+}
+
+impl Collector for CallbackGauge {
+    fn desc(&self) -> Vec<&Desc> {
+        self.gauge.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.gauge.set((self.callback)());
+        self.gauge.collect()
+    }
+}
+
+/// A pair of gauges recording the usage and capacity of a resource, set from
+/// a single callback each time they are collected.
+pub(crate) struct UsageGauges {
+    used: Gauge,
+    capacity: Gauge,
+    callback: Box<dyn Fn() -> (f64, f64) + Send + Sync>,
+}
+
+impl UsageGauges {
+    pub(crate) fn new(
+        used: Gauge,
+        capacity: Gauge,
+        callback: Box<dyn Fn() -> (f64, f64) + Send + Sync>,
+    ) -> Self {
+        Self {
+            used,
+            capacity,
+            callback,
+        }
+    }
+}
+
+impl Collector for UsageGauges {
+    fn desc(&self) -> Vec<&Desc> {
+        self.used
+            .desc()
+            .into_iter()
+            .chain(self.capacity.desc())
+            .collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        // Call the callback once so the two values are consistent.
+        let (used, capacity) = (self.callback)();
+        self.used.set(used);
+        self.capacity.set(capacity);
+        self.used
+            .collect()
+            .into_iter()
+            .chain(self.capacity.collect())
+            .collect()
+    }
+}
+
+/// A collector producing metric families from a callback each time it is
+/// collected.
+pub(crate) struct FnCollector {
+    callback: Box<dyn Fn() -> Vec<MetricFamily> + Send + Sync>,
+}
+
+impl FnCollector {
+    pub(crate) fn new(callback: Box<dyn Fn() -> Vec<MetricFamily> + Send + Sync>) -> Self {
+        Self { callback }
+    }
+}
+
+impl Collector for FnCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        // The families aren't known until they're collected.
+        vec![]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        (self.callback)()
+    }
+}
@@ -0,0 +1,48 @@
+//! Attributing responses to the catchers which produced them.
+//!
+//! Rocket doesn't tell fairings whether a response came from a route or a
+//! catcher, so responses from catchers after a route was tried (for example
+//! when a request guard fails) can only be told apart if the catcher marks
+//! them.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use rocket::Request;
+
+/// Value stored in request-local state marking that a catcher handled the
+/// request.
+#[derive(Default)]
+struct Caught(AtomicBool);
+
+/// Mark the response to a request as produced by a catcher.
+///
+/// When [`PrometheusMetricsBuilder::catcher_endpoints`] is enabled, marked
+/// responses are recorded with an `endpoint` label of `<catcher:CODE>`
+/// instead of the route which was tried. Call this from any catcher whose
+/// responses should be tracked separately from their route, such as a `429`
+/// catcher for a rate limiting request guard:
+///
+/// ```rust
+/// use rocket::{catch, Request};
+/// use rocket_prometheus::mark_caught;
+///
+/// #[catch(429)]
+/// fn too_many_requests(req: &Request<'_>) -> &'static str {
+///     mark_caught(req);
+///     "Slow down!"
+/// }
+/// ```
+///
+/// Requests which didn't match any route don't need to be marked.
+///
+/// [`PrometheusMetricsBuilder::catcher_endpoints`]: crate::PrometheusMetricsBuilder::catcher_endpoints
+pub fn mark_caught(req: &Request<'_>) {
+    req.local_cache(Caught::default)
+        .0
+        .store(true, Ordering::Relaxed);
+}
+
+/// Whether the response to a request was marked with [`mark_caught`].
+pub(crate) fn is_caught(req: &Request<'_>) -> bool {
+    req.local_cache(Caught::default).0.load(Ordering::Relaxed)
+}
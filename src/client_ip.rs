@@ -0,0 +1,84 @@
+//! Determining the client's address behind trusted reverse proxies.
+
+use std::{collections::HashSet, net::IpAddr};
+
+use rocket::Request;
+
+/// Reads the client's address from a header set by trusted proxies.
+#[derive(Debug)]
+pub(crate) struct ProxyHeader {
+    header: String,
+    trusted_proxies: HashSet<IpAddr>,
+}
+
+impl ProxyHeader {
+    pub(crate) fn new(header: String, trusted_proxies: HashSet<IpAddr>) -> Self {
+        Self {
+            header,
+            trusted_proxies,
+        }
+    }
+
+    /// The address of the client which sent a request.
+    ///
+    /// The header is only used if the request came directly from a trusted
+    /// proxy, otherwise the address of the peer is returned.
+    pub(crate) fn client_ip(&self, req: &Request<'_>) -> Option<IpAddr> {
+        let peer = req.remote()?.ip().to_canonical();
+        if !self.trusted_proxies.contains(&peer) {
+            return Some(peer);
+        }
+        let forwarded: Vec<&str> = req.headers().get(&self.header).collect();
+        Some(self.parse_header(&forwarded).unwrap_or(peer))
+    }
+
+    /// The client's address from the values of the header, which may each
+    /// contain a comma-separated list of addresses as in `X-Forwarded-For`.
+    ///
+    /// Each proxy appends the address it received the request from, so the
+    /// client is the last address which isn't a trusted proxy: anything
+    /// before it may have been sent by the client itself.
+    fn parse_header(&self, values: &[&str]) -> Option<IpAddr> {
+        let mut addresses = values
+            .iter()
+            .flat_map(|value| value.split(','))
+            .map(|address| address.trim().parse::<IpAddr>().map(|ip| ip.to_canonical()))
+            .collect::<Result<Vec<_>, _>>()
+            .ok()?;
+        let first = addresses.first().copied();
+        addresses.retain(|ip| !self.trusted_proxies.contains(ip));
+        addresses.last().copied().or(first)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        iter,
+        net::{IpAddr, Ipv4Addr},
+    };
+
+    use super::ProxyHeader;
+
+    #[test]
+    fn test_parse_header() {
+        let proxy = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let header = ProxyHeader::new("X-Forwarded-For".into(), iter::once(proxy).collect());
+        let client = "203.0.113.7".parse().ok();
+
+        assert_eq!(header.parse_header(&["203.0.113.7"]), client);
+        // Addresses before the last untrusted one can't be relied on.
+        assert_eq!(
+            header.parse_header(&["198.51.100.1, 203.0.113.7, 10.0.0.1"]),
+            client
+        );
+        assert_eq!(
+            header.parse_header(&["198.51.100.1", "203.0.113.7"]),
+            client
+        );
+        assert_eq!(header.parse_header(&["10.0.0.1"]), Some(proxy));
+        assert_eq!(header.parse_header(&["::ffff:203.0.113.7"]), client);
+        assert_eq!(header.parse_header(&["unknown"]), None);
+        assert_eq!(header.parse_header(&[]), None);
+    }
+}
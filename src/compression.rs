@@ -0,0 +1,96 @@
+//! Negotiation and compression of the metrics response body.
+
+use std::io;
+
+/// A content coding which can be applied to the metrics response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    /// No compression.
+    Identity,
+    /// Gzip compression.
+    #[cfg(feature = "gzip")]
+    Gzip,
+    /// Zstandard compression.
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Encoding {
+    /// Choose the preferred supported encoding accepted by a client, given
+    /// the value of its `Accept-Encoding` header.
+    ///
+    /// zstd is preferred over gzip, since it compresses better.
+    #[cfg_attr(not(any(feature = "gzip", feature = "zstd")), allow(unused_variables))]
+    pub(crate) fn negotiate(accept_encoding: Option<&str>) -> Self {
+        #[cfg(feature = "zstd")]
+        if accept_encoding.is_some_and(|header| accepts(header, "zstd")) {
+            return Self::Zstd;
+        }
+        #[cfg(feature = "gzip")]
+        if accept_encoding.is_some_and(|header| accepts(header, "gzip")) {
+            return Self::Gzip;
+        }
+        Self::Identity
+    }
+
+    /// The value of the `Content-Encoding` header for this encoding, if any.
+    pub(crate) fn header_value(self) -> Option<&'static str> {
+        match self {
+            Self::Identity => None,
+            #[cfg(feature = "gzip")]
+            Self::Gzip => Some("gzip"),
+            #[cfg(feature = "zstd")]
+            Self::Zstd => Some("zstd"),
+        }
+    }
+
+    /// Compress `body` using this encoding.
+    pub(crate) fn encode(self, body: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Identity => Ok(body.to_vec()),
+            #[cfg(feature = "gzip")]
+            Self::Gzip => {
+                use std::io::Write;
+
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            #[cfg(feature = "zstd")]
+            Self::Zstd => zstd::encode_all(body, 0),
+        }
+    }
+}
+
+/// Returns `true` if an `Accept` or `Accept-Encoding` header value lists
+/// `value` (a media type or content coding) with a non-zero quality.
+pub(crate) fn accepts(header: &str, value: &str) -> bool {
+    header.split(',').any(|item| {
+        let mut params = item.split(';').map(str::trim);
+        let name = params.next().unwrap_or_default();
+        if !name.eq_ignore_ascii_case(value) {
+            return false;
+        }
+        // A quality of zero means the coding is not acceptable.
+        !params.any(|param| {
+            param
+                .strip_prefix("q=")
+                .and_then(|q| q.parse::<f64>().ok())
+                .is_some_and(|q| q == 0.0)
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::accepts;
+
+    #[test]
+    fn test_accepts() {
+        assert!(accepts("zstd", "zstd"));
+        assert!(accepts("gzip, ZSTD;q=0.5", "zstd"));
+        assert!(!accepts("gzip, zstd;q=0", "zstd"));
+        assert!(!accepts("gzip, deflate", "zstd"));
+    }
+}
@@ -0,0 +1,260 @@
+//! Tracking of the highest number of requests handled concurrently by each
+//! endpoint.
+//!
+//! The endpoint of a request is only known once it has been routed, after
+//! the fairing's `on_request` callback, so requests can't be counted per
+//! endpoint as they start. Instead, when a request finishes, the time it was
+//! in flight is compared with the other requests to its endpoint. The
+//! concurrency of an endpoint peaks at the start of one of its requests, and
+//! the count at a start time is final once every request in flight at that
+//! time has finished.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex, PoisonError},
+    time::Instant,
+};
+
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    IntGaugeVec,
+};
+
+/// Requests to a single endpoint which may still affect its peak.
+#[derive(Default)]
+struct Endpoint {
+    // Start and end times of finished requests which may overlap requests
+    // still in flight.
+    finished: Vec<(Instant, Instant)>,
+    // Start times of finished requests, along with the number of requests
+    // found in flight at that time so far.
+    pending: Vec<(Instant, i64)>,
+    // The highest final count.
+    max: i64,
+}
+
+impl Endpoint {
+    /// Add a finished request.
+    fn add(&mut self, start: Instant, end: Instant) {
+        for (at, count) in &mut self.pending {
+            if start <= *at && *at <= end {
+                *count += 1;
+            }
+        }
+        let overlapping = self
+            .finished
+            .iter()
+            .filter(|&&(other_start, other_end)| other_start <= start && start <= other_end)
+            .count();
+        self.pending.push((start, 1 + overlapping as i64));
+        self.finished.push((start, end));
+    }
+
+    /// Finalize the counts at times before `horizon`, the start of the
+    /// oldest request in flight, which no request in flight can overlap.
+    fn settle(&mut self, horizon: Option<Instant>) {
+        let settled = |at: Instant| horizon.map_or(true, |horizon| at < horizon);
+        let max = &mut self.max;
+        self.pending.retain(|&(at, count)| {
+            if settled(at) {
+                *max = (*max).max(count);
+            }
+            !settled(at)
+        });
+        self.finished.retain(|&(_, end)| !settled(end));
+    }
+
+    /// The highest concurrency seen so far, including counts which may still
+    /// increase.
+    fn peak(&self) -> i64 {
+        self.pending
+            .iter()
+            .map(|&(_, count)| count)
+            .fold(self.max, i64::max)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    // Start times of the requests in flight, with the number of requests
+    // started at each time.
+    in_flight: BTreeMap<Instant, usize>,
+    endpoints: HashMap<String, Endpoint>,
+}
+
+impl State {
+    /// The start time of the oldest request in flight, if any.
+    fn horizon(&self) -> Option<Instant> {
+        self.in_flight.keys().next().copied()
+    }
+
+    fn start(&mut self, start: Instant) {
+        *self.in_flight.entry(start).or_default() += 1;
+    }
+
+    fn finish(&mut self, start: Instant, end: Instant, endpoint: Option<&str>) {
+        if let Some(count) = self.in_flight.get_mut(&start) {
+            *count -= 1;
+            if *count == 0 {
+                self.in_flight.remove(&start);
+            }
+        }
+        if let Some(endpoint) = endpoint {
+            let horizon = self.horizon();
+            let requests = self.endpoints.entry(endpoint.to_owned()).or_default();
+            requests.add(start, end);
+            requests.settle(horizon);
+        }
+    }
+}
+
+/// Collector exposing the highest number of requests to each endpoint
+/// observed in flight at once.
+#[derive(Clone)]
+pub(crate) struct ConcurrencyMax {
+    state: Arc<Mutex<State>>,
+    reset_on_scrape: bool,
+    gauge: IntGaugeVec,
+}
+
+impl ConcurrencyMax {
+    pub(crate) fn new(gauge: IntGaugeVec, reset_on_scrape: bool) -> Self {
+        Self {
+            state: Arc::default(),
+            reset_on_scrape,
+            gauge,
+        }
+    }
+
+    /// Record that a request started at `start`.
+    pub(crate) fn start(&self, start: Instant) {
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .start(start);
+    }
+
+    /// Record that the request started at `start` is finishing.
+    ///
+    /// The request is recorded when the returned value is dropped, for the
+    /// endpoint set with [`Finishing::endpoint`], or for no endpoint if the
+    /// request isn't tracked.
+    pub(crate) fn finish(&self, start: Option<Instant>) -> Finishing<'_> {
+        Finishing {
+            concurrency: self,
+            start,
+            endpoint: None,
+        }
+    }
+
+    /// Forget the peaks of all endpoints.
+    pub(crate) fn reset(&self) {
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .endpoints
+            .clear();
+        self.gauge.reset();
+    }
+}
+
+impl Collector for ConcurrencyMax {
+    fn desc(&self) -> Vec<&Desc> {
+        self.gauge.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        {
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            let horizon = state.horizon();
+            for (endpoint, requests) in state.endpoints.iter_mut() {
+                requests.settle(horizon);
+                self.gauge
+                    .with_label_values(&[endpoint.as_str()])
+                    .set(requests.peak());
+                if self.reset_on_scrape {
+                    // Requests still pending count towards the next period
+                    // too, since they overlap it.
+                    requests.max = 0;
+                }
+            }
+        }
+        self.gauge.collect()
+    }
+}
+
+/// A request which is finishing, recorded when dropped so that it's
+/// recorded however the fairing's `on_response` callback returns.
+pub(crate) struct Finishing<'a> {
+    concurrency: &'a ConcurrencyMax,
+    start: Option<Instant>,
+    endpoint: Option<String>,
+}
+
+impl Finishing<'_> {
+    /// Count the request towards the peak of `endpoint`.
+    pub(crate) fn endpoint(&mut self, endpoint: &str) {
+        self.endpoint = Some(endpoint.to_owned());
+    }
+}
+
+impl Drop for Finishing<'_> {
+    fn drop(&mut self) {
+        if let Some(start) = self.start {
+            self.concurrency
+                .state
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .finish(start, Instant::now(), self.endpoint.as_deref());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::State;
+
+    #[test]
+    fn test_peak() {
+        let t0 = Instant::now();
+        let at = |millis| t0 + Duration::from_millis(millis);
+        let peak = |state: &State, endpoint: &str| state.endpoints[endpoint].peak();
+        let mut state = State::default();
+
+        // /a: [0, 30], [10, 20] and [25, 40] overlap at most two at a time.
+        // /b: [5, 15] overlaps them, but is another endpoint.
+        for start in [0, 5, 10, 25] {
+            state.start(at(start));
+        }
+        state.finish(at(5), at(15), Some("/b"));
+        state.finish(at(10), at(20), Some("/a"));
+        state.finish(at(0), at(30), Some("/a"));
+        // Requests which finish later may still add to counts.
+        assert_eq!(peak(&state, "/a"), 2);
+        state.start(at(35));
+        state.finish(at(25), at(40), Some("/a"));
+        assert_eq!(peak(&state, "/a"), 2);
+        assert_eq!(peak(&state, "/b"), 1);
+        // [35, 50] overlaps only [25, 40].
+        state.finish(at(35), at(50), Some("/a"));
+        assert_eq!(peak(&state, "/a"), 2);
+
+        // Nothing is in flight, so everything has been settled.
+        let requests = &state.endpoints["/a"];
+        assert!(requests.pending.is_empty() && requests.finished.is_empty());
+        assert_eq!(requests.max, 2);
+
+        // A request which finishes after one which started later still
+        // counts towards it.
+        state.start(at(60));
+        state.start(at(70));
+        state.finish(at(70), at(75), Some("/a"));
+        state.start(at(72));
+        state.finish(at(72), at(80), Some("/a"));
+        state.finish(at(60), at(90), Some("/a"));
+        assert_eq!(peak(&state, "/a"), 3);
+    }
+}
@@ -0,0 +1,63 @@
+//! Configuration loaded with Rocket's figment.
+
+use rocket::serde::Deserialize;
+
+use crate::{PrometheusMetrics, PrometheusMetricsBuilder};
+
+/// Configuration of a [`PrometheusMetrics`] instance which can be
+/// deserialized, e.g. from a `[default.prometheus]` table in `Rocket.toml` or
+/// from `ROCKET_PROMETHEUS` environment variables via Rocket's figment.
+///
+/// Omitted fields keep the defaults of [`PrometheusMetricsBuilder`].
+///
+/// ```rust
+/// use rocket_prometheus::{PrometheusConfig, PrometheusMetrics};
+///
+/// let rocket = rocket::build();
+/// let config: PrometheusConfig = rocket
+///     .figment()
+///     .extract_inner("prometheus")
+///     .unwrap_or_default();
+/// let prometheus = PrometheusMetrics::from_config(config).unwrap();
+/// let rocket = rocket
+///     .attach(prometheus.clone())
+///     .mount("/metrics", prometheus);
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(crate = "rocket::serde", default)]
+#[non_exhaustive]
+pub struct PrometheusConfig {
+    /// See [`PrometheusMetricsBuilder::namespace`].
+    pub namespace: Option<String>,
+    /// See [`PrometheusMetricsBuilder::buckets`].
+    pub buckets: Option<Vec<f64>>,
+    /// See [`PrometheusMetricsBuilder::ignore_routes`].
+    pub ignore_routes: Vec<String>,
+}
+
+impl PrometheusConfig {
+    /// Create a [`PrometheusMetricsBuilder`] with this configuration, to which
+    /// further options can be added.
+    pub fn builder(self) -> PrometheusMetricsBuilder {
+        let mut builder = PrometheusMetricsBuilder::new().ignore_routes(self.ignore_routes);
+        if let Some(namespace) = self.namespace {
+            builder = builder.namespace(namespace);
+        }
+        if let Some(buckets) = self.buckets {
+            builder = builder.buckets(buckets);
+        }
+        builder
+    }
+}
+
+impl PrometheusMetrics {
+    /// Create a new [`PrometheusMetrics`] with the given configuration.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the configuration is invalid, see
+    /// [`PrometheusMetricsBuilder::build`].
+    pub fn from_config(config: PrometheusConfig) -> Result<Self, prometheus::Error> {
+        config.builder().build()
+    }
+}
@@ -0,0 +1,240 @@
+//! Trace-ID exemplars for the request duration histogram.
+//!
+//! The `prometheus` crate doesn't support exemplars, so the most recent
+//! exemplar of each histogram bucket is tracked here and rendered when
+//! metrics are encoded as OpenMetrics, the only format which supports them.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, SystemTime},
+};
+
+use rocket::Request;
+
+/// Extracts a trace ID from the value of a request header.
+pub(crate) type TraceIdExtractor = Arc<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+/// OpenMetrics limits the combined length of an exemplar's label names and
+/// values to 128 UTF-8 characters.
+const MAX_LABELS_LEN: usize = 128;
+
+/// A single observation along with the trace it belongs to.
+struct Exemplar {
+    trace_id: String,
+    value: f64,
+    timestamp: f64,
+}
+
+/// The most recent exemplar of each bucket of a histogram.
+pub(crate) struct Exemplars {
+    header: String,
+    extract: TraceIdExtractor,
+    // The name of the histogram family, including any namespace.
+    family: String,
+    label_names: Vec<String>,
+    // Only requests slower than this record exemplars, if set.
+    threshold: Option<Duration>,
+    // Keyed by the escaped label values, in the order of `label_names`, and
+    // the bits of the bucket's upper bound, since series may have different
+    // buckets.
+    latest: Mutex<HashMap<(Vec<String>, u64), Exemplar>>,
+}
+
+impl Exemplars {
+    pub(crate) fn new(
+        header: String,
+        extract: TraceIdExtractor,
+        family: String,
+        label_names: Vec<String>,
+        threshold: Option<Duration>,
+    ) -> Self {
+        Self {
+            header,
+            extract,
+            family,
+            label_names,
+            threshold,
+            latest: Mutex::default(),
+        }
+    }
+
+    /// The trace ID of a request, if it has one.
+    pub(crate) fn trace_id(&self, req: &Request<'_>) -> Option<String> {
+        let value = req.headers().get_one(&self.header)?;
+        (self.extract)(value)
+            .filter(|id| !id.is_empty() && "trace_id".len() + id.chars().count() <= MAX_LABELS_LEN)
+    }
+
+    /// Whether a request which took `duration` should record an exemplar.
+    pub(crate) fn exceeds_threshold(&self, duration: Duration) -> bool {
+        !self
+            .threshold
+            .is_some_and(|threshold| duration <= threshold)
+    }
+
+    /// Record an observation of the histogram series with the given label
+    /// values, whose histogram has the given buckets, as the exemplar of its
+    /// bucket.
+    pub(crate) fn observe(&self, labels: &[&str], value: f64, buckets: &[f64], trace_id: String) {
+        let bucket = buckets
+            .iter()
+            .copied()
+            .find(|&bound| value <= bound)
+            .unwrap_or(f64::INFINITY)
+            .to_bits();
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0.0, |d| d.as_secs_f64());
+        let key = labels.iter().map(|label| escape(label)).collect();
+        self.latest
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(
+                (key, bucket),
+                Exemplar {
+                    trace_id,
+                    value,
+                    timestamp,
+                },
+            );
+    }
+
+    /// Forget all exemplars.
+    pub(crate) fn reset(&self) {
+        self.latest
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    /// The exemplar to append to a line of OpenMetrics text, if it is a
+    /// bucket of the histogram with a recorded exemplar.
+    pub(crate) fn annotate(&self, line: &str) -> Option<String> {
+        let labels = line
+            .strip_prefix(self.family.as_str())?
+            .strip_prefix("_bucket{")?;
+        let pairs = parse_labels(labels)?;
+        let value_of = |name: &str| {
+            pairs
+                .iter()
+                .find(|(label, _)| *label == name)
+                .map(|(_, value)| value.to_string())
+        };
+        let bucket = match value_of("le")?.as_str() {
+            "+Inf" => f64::INFINITY,
+            le => le.parse().ok()?,
+        }
+        .to_bits();
+        let key = self
+            .label_names
+            .iter()
+            .map(|name| value_of(name))
+            .collect::<Option<Vec<_>>>()?;
+        let latest = self.latest.lock().unwrap_or_else(PoisonError::into_inner);
+        let exemplar = latest.get(&(key, bucket))?;
+        Some(format!(
+            " # {{trace_id=\"{}\"}} {} {}",
+            escape(&exemplar.trace_id),
+            exemplar.value,
+            exemplar.timestamp
+        ))
+    }
+}
+
+/// Extract the trace ID from a W3C `traceparent` header value, or use the
+/// whole value if it isn't one.
+pub(crate) fn default_trace_id(value: &str) -> Option<String> {
+    let value = value.trim();
+    let mut parts = value.split('-');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(version), Some(trace_id), Some(_), Some(_))
+            if version.len() == 2
+                && trace_id.len() == 32
+                && trace_id.bytes().all(|b| b.is_ascii_hexdigit()) =>
+        {
+            Some(trace_id.to_owned())
+        }
+        _ => Some(value.to_owned()),
+    }
+}
+
+/// Escape a label value as the text format does.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('\n', r"\n")
+        .replace('"', r#"\""#)
+}
+
+/// Parse the label pairs of a sample line, starting after the opening brace.
+///
+/// Values are left escaped.
+fn parse_labels(mut rest: &str) -> Option<Vec<(&str, &str)>> {
+    let mut pairs = vec![];
+    loop {
+        rest = rest.trim_start_matches(',');
+        if rest.starts_with('}') {
+            return Some(pairs);
+        }
+        let (name, after) = rest.split_once("=\"")?;
+        let mut escaped = false;
+        let end = after.char_indices().find_map(|(i, c)| match c {
+            _ if escaped => {
+                escaped = false;
+                None
+            }
+            '\\' => {
+                escaped = true;
+                None
+            }
+            '"' => Some(i),
+            _ => None,
+        })?;
+        pairs.push((name, &after[..end]));
+        rest = &after[end + 1..];
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::{default_trace_id, Exemplars};
+
+    #[test]
+    fn test_default_trace_id() {
+        assert_eq!(
+            default_trace_id("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").as_deref(),
+            Some("4bf92f3577b34da6a3ce929d0e0e4736")
+        );
+        assert_eq!(default_trace_id(" abc123 ").as_deref(), Some("abc123"));
+    }
+
+    #[test]
+    fn test_annotate() {
+        let exemplars = Exemplars::new(
+            "traceparent".into(),
+            Arc::new(default_trace_id),
+            "rocket_http_requests_duration_seconds".into(),
+            vec!["endpoint".into(), "method".into(), "status".into()],
+            None,
+        );
+        exemplars.observe(&["/a\"b", "GET", "200"], 0.5, &[0.1, 1.0], "abc".into());
+
+        let line = r#"rocket_http_requests_duration_seconds_bucket{endpoint="/a\"b",method="GET",status="200",le="1"} 1"#;
+        let annotation = exemplars.annotate(line).unwrap();
+        assert!(annotation.starts_with(r#" # {trace_id="abc"} 0.5 "#));
+
+        let other_bucket = line.replace(r#"le="1""#, r#"le="0.1""#);
+        assert_eq!(exemplars.annotate(&other_bucket), None);
+        let other_series = line.replace("GET", "POST");
+        assert_eq!(exemplars.annotate(&other_series), None);
+        assert_eq!(
+            exemplars.annotate(
+                r#"rocket_http_requests_duration_seconds_sum{endpoint="/a\"b",method="GET",status="200"} 0.5"#
+            ),
+            None
+        );
+    }
+}
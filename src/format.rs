@@ -0,0 +1,203 @@
+//! Negotiation and encoding of the metrics exposition format.
+
+use prometheus::{
+    proto::{MetricFamily, MetricType},
+    Encoder, TextEncoder,
+};
+use rocket::http::ContentType;
+
+use crate::{compression::accepts, exemplars::Exemplars};
+
+/// A format in which metrics can be exposed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Format {
+    /// The Prometheus text format, version 0.0.4.
+    Text,
+    /// The OpenMetrics text format, version 1.0.0.
+    OpenMetrics,
+    /// The Prometheus protobuf format, as length-delimited `MetricFamily`
+    /// messages.
+    #[cfg(feature = "protobuf")]
+    Protobuf,
+}
+
+impl Format {
+    /// Choose the preferred supported format accepted by a client, given the
+    /// value of its `Accept` header.
+    ///
+    /// The Prometheus text format is used unless another supported format is
+    /// explicitly accepted. Quality values are only used to exclude formats;
+    /// protobuf is preferred over OpenMetrics if both are accepted.
+    pub(crate) fn negotiate(accept: Option<&str>) -> Self {
+        let accept = match accept {
+            Some(accept) => accept,
+            None => return Self::Text,
+        };
+        #[cfg(feature = "protobuf")]
+        if accepts(accept, "application/vnd.google.protobuf") {
+            return Self::Protobuf;
+        }
+        if accepts(accept, "application/openmetrics-text") {
+            return Self::OpenMetrics;
+        }
+        Self::Text
+    }
+
+    /// The `Content-Type` of metrics in this format.
+    pub(crate) fn content_type(self) -> ContentType {
+        match self {
+            Self::Text => ContentType::new("text", "plain")
+                .with_params([("version", "0.0.4"), ("charset", "utf-8")]),
+            Self::OpenMetrics => ContentType::new("application", "openmetrics-text")
+                .with_params([("version", "1.0.0"), ("charset", "utf-8")]),
+            #[cfg(feature = "protobuf")]
+            Self::Protobuf => ContentType::new("application", "vnd.google.protobuf").with_params([
+                ("proto", "io.prometheus.client.MetricFamily"),
+                ("encoding", "delimited"),
+            ]),
+        }
+    }
+
+    /// Whether comment lines can be written before the metrics.
+    pub(crate) fn supports_comments(self) -> bool {
+        match self {
+            Self::Text => true,
+            // OpenMetrics only allows `HELP`, `TYPE` and `UNIT` comments.
+            Self::OpenMetrics => false,
+            #[cfg(feature = "protobuf")]
+            Self::Protobuf => false,
+        }
+    }
+
+    /// Bytes which must be written after all metrics, if any.
+    pub(crate) fn trailer(self) -> Option<&'static [u8]> {
+        match self {
+            Self::OpenMetrics => Some(b"# EOF\n"),
+            _ => None,
+        }
+    }
+
+    /// Encode `families` in this format, appending them to `buffer`.
+    ///
+    /// This doesn't write the [trailer](Self::trailer), so that it can be
+    /// called once per family when streaming. `exemplars` are only written
+    /// in the OpenMetrics format.
+    pub(crate) fn encode(
+        self,
+        families: &[MetricFamily],
+        exemplars: Option<&Exemplars>,
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), prometheus::Error> {
+        match self {
+            Self::Text => TextEncoder::new().encode(families, buffer),
+            Self::OpenMetrics => {
+                for family in families {
+                    let mut text = vec![];
+                    TextEncoder::new().encode(std::slice::from_ref(family), &mut text)?;
+                    write_openmetrics(family, &String::from_utf8_lossy(&text), exemplars, buffer);
+                }
+                Ok(())
+            }
+            #[cfg(feature = "protobuf")]
+            Self::Protobuf => prometheus::ProtobufEncoder::new().encode(families, buffer),
+        }
+    }
+}
+
+/// A user-supplied encoder used instead of format negotiation.
+pub(crate) struct CustomEncoder {
+    // `Encoder::encode` is generic over the writer, so the encoder can't be
+    // a trait object itself.
+    encode: Box<dyn Fn(&[MetricFamily], &mut Vec<u8>) -> prometheus::Result<()> + Send + Sync>,
+    pub(crate) content_type: ContentType,
+}
+
+impl CustomEncoder {
+    pub(crate) fn new<E>(encoder: E, content_type: ContentType) -> Self
+    where
+        E: Encoder + Send + Sync + 'static,
+    {
+        Self {
+            encode: Box::new(move |families, buffer| encoder.encode(families, buffer)),
+            content_type,
+        }
+    }
+
+    /// Encode `families`, appending them to `buffer`.
+    pub(crate) fn encode(
+        &self,
+        families: &[MetricFamily],
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), prometheus::Error> {
+        (self.encode)(families, buffer)
+    }
+}
+
+/// Rewrite a single family encoded in the Prometheus text format as
+/// OpenMetrics, appending it to `buffer`.
+///
+/// The formats differ only in that counter families are named without the
+/// `_total` suffix, which their samples must have, and that `untyped` is
+/// called `unknown`. OpenMetrics also allows exemplars on histogram buckets,
+/// which are appended to their samples.
+fn write_openmetrics(
+    family: &MetricFamily,
+    text: &str,
+    exemplars: Option<&Exemplars>,
+    buffer: &mut Vec<u8>,
+) {
+    let name = family.get_name();
+    let is_counter = family.get_field_type() == MetricType::COUNTER;
+    let family_name = match name.strip_suffix("_total") {
+        Some(base) if is_counter => base,
+        _ => name,
+    };
+    let help = format!("# HELP {} ", name);
+    let type_ = format!("# TYPE {} ", name);
+    for line in text.lines() {
+        if let Some(rest) = line.strip_prefix(&help) {
+            buffer.extend_from_slice(format!("# HELP {} {}", family_name, rest).as_bytes());
+        } else if let Some(rest) = line.strip_prefix(&type_) {
+            let rest = if rest == "untyped" { "unknown" } else { rest };
+            buffer.extend_from_slice(format!("# TYPE {} {}", family_name, rest).as_bytes());
+        } else if is_counter && family_name == name && line.starts_with(name) {
+            buffer.extend_from_slice(format!("{}_total{}", name, &line[name.len()..]).as_bytes());
+        } else {
+            buffer.extend_from_slice(line.as_bytes());
+            if let Some(exemplar) = exemplars.and_then(|exemplars| exemplars.annotate(line)) {
+                buffer.extend_from_slice(exemplar.as_bytes());
+            }
+        }
+        buffer.push(b'\n');
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Format;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(Format::negotiate(None), Format::Text);
+        assert_eq!(Format::negotiate(Some("*/*")), Format::Text);
+        assert_eq!(
+            Format::negotiate(Some("text/plain;version=0.0.4")),
+            Format::Text
+        );
+        assert_eq!(
+            Format::negotiate(Some(
+                "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5"
+            )),
+            Format::OpenMetrics
+        );
+        #[cfg(feature = "protobuf")]
+        {
+            let accept = "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=delimited;q=0.7,text/plain;version=0.0.4;q=0.3";
+            assert_eq!(Format::negotiate(Some(accept)), Format::Protobuf);
+            assert_eq!(
+                Format::negotiate(Some("application/vnd.google.protobuf;q=0")),
+                Format::Text
+            );
+        }
+    }
+}
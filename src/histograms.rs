@@ -0,0 +1,120 @@
+//! The request duration histogram, made up of one `HistogramVec` per set of
+//! buckets.
+//!
+//! A `HistogramVec` uses the same buckets for all of its series, so endpoints
+//! and methods configured with their own buckets get their own `HistogramVec`
+//! with the same name and labels. Registering these separately would fail, so
+//! they are merged into a single metric family when collected instead.
+
+use std::{collections::HashMap, sync::Arc};
+
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    HistogramVec,
+};
+
+/// A histogram along with its bucket upper bounds, which `HistogramVec`
+/// doesn't expose.
+#[derive(Clone)]
+struct Bucketed {
+    histogram: HistogramVec,
+    buckets: Arc<[f64]>,
+}
+
+/// Collector exposing the duration histograms of all bucket sets as one
+/// metric family.
+#[derive(Clone)]
+pub(crate) struct DurationHistograms {
+    default: Bucketed,
+    // Histograms with custom buckets, keyed by endpoint and by method.
+    by_endpoint: Arc<HashMap<String, Bucketed>>,
+    by_method: Arc<HashMap<String, Bucketed>>,
+}
+
+impl DurationHistograms {
+    /// Create the collector from the default histogram and those with
+    /// custom buckets, each along with its buckets.
+    pub(crate) fn new(
+        default: (HistogramVec, Vec<f64>),
+        by_endpoint: HashMap<String, (HistogramVec, Vec<f64>)>,
+        by_method: HashMap<String, (HistogramVec, Vec<f64>)>,
+    ) -> Self {
+        let bucketed = |(histogram, buckets): (HistogramVec, Vec<f64>)| Bucketed {
+            histogram,
+            buckets: buckets.into(),
+        };
+        let by_key = |histograms: HashMap<String, (HistogramVec, Vec<f64>)>| -> HashMap<_, _> {
+            histograms
+                .into_iter()
+                .map(|(key, histogram)| (key, bucketed(histogram)))
+                .collect()
+        };
+        Self {
+            default: bucketed(default),
+            by_endpoint: Arc::new(by_key(by_endpoint)),
+            by_method: Arc::new(by_key(by_method)),
+        }
+    }
+
+    /// The histogram observing requests whose endpoint and method don't have
+    /// custom buckets.
+    pub(crate) fn default_histogram(&self) -> &HistogramVec {
+        &self.default.histogram
+    }
+
+    /// The histogram observing the series with the given label values,
+    /// starting with the endpoint and method. Endpoint buckets take
+    /// precedence over method buckets.
+    fn select(&self, labels: &[&str]) -> &Bucketed {
+        self.by_endpoint
+            .get(labels[0])
+            .or_else(|| self.by_method.get(labels[1]))
+            .unwrap_or(&self.default)
+    }
+
+    /// Observe a value for the series with the given label values, returning
+    /// the buckets of the histogram it was observed by.
+    pub(crate) fn observe(&self, labels: &[&str], value: f64) -> &[f64] {
+        let bucketed = self.select(labels);
+        bucketed.histogram.with_label_values(labels).observe(value);
+        &bucketed.buckets
+    }
+
+    /// Remove the series with the given label values, if it exists.
+    pub(crate) fn remove(&self, labels: &[&str]) {
+        // The series may already have been removed by `reset`.
+        let _ = self.select(labels).histogram.remove_label_values(labels);
+    }
+
+    /// Reset all series of all histograms.
+    pub(crate) fn reset(&self) {
+        for bucketed in self.all() {
+            bucketed.histogram.reset();
+        }
+    }
+
+    fn all(&self) -> impl Iterator<Item = &Bucketed> {
+        std::iter::once(&self.default)
+            .chain(self.by_endpoint.values())
+            .chain(self.by_method.values())
+    }
+}
+
+impl Collector for DurationHistograms {
+    fn desc(&self) -> Vec<&Desc> {
+        self.default.histogram.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut families = self.default.histogram.collect();
+        if let Some(family) = families.first_mut() {
+            for bucketed in self.all().skip(1) {
+                for mut other in bucketed.histogram.collect() {
+                    family.mut_metric().extend(other.take_metric());
+                }
+            }
+        }
+        families
+    }
+}
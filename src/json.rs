@@ -0,0 +1,205 @@
+//! A JSON representation of gathered metrics.
+//!
+//! This module is only available with the `json` feature enabled.
+
+use prometheus::proto::{LabelPair, MetricFamily, MetricType};
+use serde_json::{json, Map, Value};
+
+use crate::{PrometheusMetrics, Registries};
+
+impl PrometheusMetrics {
+    /// Gather the metrics from both the custom and the internal registry as
+    /// JSON, for consumers which can't parse the Prometheus text format.
+    ///
+    /// The result is an array with one object per metric family, with its
+    /// `name`, `help` and `type` (`counter`, `gauge`, `histogram`, `summary`
+    /// or `untyped`), and its `samples`. Samples are listed as in the text
+    /// format, so histograms and summaries have separate `_bucket`, `_sum`
+    /// and `_count` samples, each with a `name`, an object of `labels` and a
+    /// numeric `value` (or `null` if it isn't finite):
+    ///
+    /// ```json
+    /// [
+    ///   {
+    ///     "name": "rocket_http_requests_total",
+    ///     "help": "Total number of HTTP requests",
+    ///     "type": "counter",
+    ///     "samples": [
+    ///       {
+    ///         "name": "rocket_http_requests_total",
+    ///         "labels": { "endpoint": "/metrics", "method": "GET", "status": "200" },
+    ///         "value": 2.0
+    ///       }
+    ///     ]
+    ///   }
+    /// ]
+    /// ```
+    ///
+    /// This is only available with the `json` feature enabled.
+    pub fn gather_json(&self) -> Value {
+        let families = self.gather_registries(Registries::All);
+        Value::Array(families.iter().map(family_json).collect())
+    }
+}
+
+/// Convert a single metric family to JSON.
+fn family_json(family: &MetricFamily) -> Value {
+    let name = family.get_name();
+    let type_ = match family.get_field_type() {
+        MetricType::COUNTER => "counter",
+        MetricType::GAUGE => "gauge",
+        MetricType::HISTOGRAM => "histogram",
+        MetricType::SUMMARY => "summary",
+        MetricType::UNTYPED => "untyped",
+    };
+    let mut samples = vec![];
+    for metric in family.get_metric() {
+        let labels = metric.get_label();
+        match family.get_field_type() {
+            MetricType::COUNTER => {
+                samples.push(sample(name, labels, None, metric.get_counter().get_value()))
+            }
+            MetricType::GAUGE => {
+                samples.push(sample(name, labels, None, metric.get_gauge().get_value()))
+            }
+            MetricType::UNTYPED => {
+                samples.push(sample(name, labels, None, metric.get_untyped().get_value()))
+            }
+            MetricType::HISTOGRAM => {
+                let histogram = metric.get_histogram();
+                let bucket_name = format!("{}_bucket", name);
+                let mut has_inf = false;
+                for bucket in histogram.get_bucket() {
+                    let upper_bound = bucket.get_upper_bound();
+                    has_inf |= upper_bound == f64::INFINITY;
+                    samples.push(sample(
+                        &bucket_name,
+                        labels,
+                        Some(("le", &format_bound(upper_bound))),
+                        bucket.get_cumulative_count() as f64,
+                    ));
+                }
+                // The `+Inf` bucket is implicit, as in the text format.
+                if !has_inf {
+                    samples.push(sample(
+                        &bucket_name,
+                        labels,
+                        Some(("le", "+Inf")),
+                        histogram.get_sample_count() as f64,
+                    ));
+                }
+                samples.push(sample(
+                    &format!("{}_sum", name),
+                    labels,
+                    None,
+                    histogram.get_sample_sum(),
+                ));
+                samples.push(sample(
+                    &format!("{}_count", name),
+                    labels,
+                    None,
+                    histogram.get_sample_count() as f64,
+                ));
+            }
+            MetricType::SUMMARY => {
+                let summary = metric.get_summary();
+                for quantile in summary.get_quantile() {
+                    samples.push(sample(
+                        name,
+                        labels,
+                        Some(("quantile", &format_bound(quantile.get_quantile()))),
+                        quantile.get_value(),
+                    ));
+                }
+                samples.push(sample(
+                    &format!("{}_sum", name),
+                    labels,
+                    None,
+                    summary.get_sample_sum(),
+                ));
+                samples.push(sample(
+                    &format!("{}_count", name),
+                    labels,
+                    None,
+                    summary.get_sample_count() as f64,
+                ));
+            }
+        }
+    }
+    json!({
+        "name": name,
+        "help": family.get_help(),
+        "type": type_,
+        "samples": samples,
+    })
+}
+
+/// A single sample, with an optional extra label such as `le`.
+fn sample(name: &str, labels: &[LabelPair], extra: Option<(&str, &str)>, value: f64) -> Value {
+    let mut label_map: Map<String, Value> = labels
+        .iter()
+        .map(|label| (label.get_name().to_owned(), label.get_value().into()))
+        .collect();
+    if let Some((label, label_value)) = extra {
+        label_map.insert(label.to_owned(), label_value.into());
+    }
+    json!({
+        "name": name,
+        "labels": label_map,
+        "value": value,
+    })
+}
+
+/// Format a bucket bound or quantile as in the text format.
+fn format_bound(bound: f64) -> String {
+    if bound == f64::INFINITY {
+        "+Inf".to_owned()
+    } else {
+        bound.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::PrometheusMetrics;
+
+    #[test]
+    fn test_gather_json() {
+        let pm = PrometheusMetrics::builder()
+            .buckets(vec![0.1, 1.0])
+            .build()
+            .unwrap();
+        pm.http_requests_total()
+            .with_label_values(&["/", "GET", "200"])
+            .inc();
+        pm.http_requests_duration_seconds()
+            .with_label_values(&["/", "GET", "200"])
+            .observe(0.5);
+
+        let json = pm.gather_json();
+        let families = json.as_array().unwrap();
+        let counter = families
+            .iter()
+            .find(|family| family["name"] == "rocket_http_requests_total")
+            .unwrap();
+        assert_eq!(counter["type"], "counter");
+        assert_eq!(counter["samples"][0]["labels"]["endpoint"], "/");
+        assert_eq!(counter["samples"][0]["value"], 1.0);
+
+        let histogram = families
+            .iter()
+            .find(|family| family["name"] == "rocket_http_requests_duration_seconds")
+            .unwrap();
+        let samples = histogram["samples"].as_array().unwrap();
+        assert_eq!(samples.len(), 5);
+        assert_eq!(
+            samples[0]["name"],
+            "rocket_http_requests_duration_seconds_bucket"
+        );
+        assert_eq!(samples[0]["labels"]["le"], "0.1");
+        assert_eq!(samples[0]["value"], 0.0);
+        assert_eq!(samples[2]["labels"]["le"], "+Inf");
+        assert_eq!(samples[2]["value"], 1.0);
+        assert_eq!(samples[3]["value"], 0.5);
+    }
+}
@@ -45,6 +45,9 @@ rocket_http_requests_duration_seconds_bucket{endpoint="/metrics",method="GET",st
 rocket_http_requests_duration_seconds_bucket{endpoint="/metrics",method="GET",status="200",le="+Inf"} 2
 rocket_http_requests_duration_seconds_sum{endpoint="/metrics",method="GET",status="200"} 0.0011045669999999999
 rocket_http_requests_duration_seconds_count{endpoint="/metrics",method="GET",status="200"} 2
+# HELP rocket_http_requests_in_flight Number of HTTP requests currently being handled
+# TYPE rocket_http_requests_in_flight gauge
+rocket_http_requests_in_flight 1
 # HELP rocket_http_requests_total Total number of HTTP requests
 # TYPE rocket_http_requests_total counter
 rocket_http_requests_total{endpoint="/metrics",method="GET",status="200"} 2
@@ -52,16 +55,92 @@ rocket_http_requests_total{endpoint="/metrics",method="GET",status="200"} 2
 
 # Metrics
 
-By default this crate tracks two metrics:
+By default this crate tracks three metrics:
 
 - `rocket_http_requests_total` (labels: endpoint, method, status): the
   total number of HTTP requests handled by Rocket.
 - `rocket_http_requests_duration_seconds` (labels: endpoint, method, status):
   the request duration for all HTTP requests handled by Rocket.
+- `rocket_http_requests_in_flight`: the number of HTTP requests currently
+  being handled by Rocket.
+
+Request durations span from the fairing's `on_request` callback to its
+`on_response` callback. This includes routing, request and data guards, the
+handler and catchers, as well as the `on_request` callbacks of fairings
+attached after this one and the `on_response` callbacks of fairings attached
+before it. It doesn't include reading the request head or writing the
+response body to the client, so streamed responses are only measured until
+they start. To split the duration further, see
+`PrometheusMetricsBuilder::guard_handler_durations`, or use `request_start`
+to measure from the same starting point.
+
+Requests for which no start time was recorded, which shouldn't normally
+happen, have no duration to observe. They are counted by
+`rocket_http_requests_missing_timer_total` (labels: endpoint, method) instead
+of `rocket_http_requests_total` and the duration metrics, so the request
+counter and the histogram's `_count` still agree. The missing timer counter
+has no series unless that happens.
 
 The 'rocket' prefix of these metrics can be changed by setting the
 `ROCKET_PROMETHEUS_NAMESPACE` environment variable.
 
+## Configuration
+
+All configuration, including the namespace, registry and histogram buckets,
+can be set in one place using [`PrometheusMetricsBuilder`]. Settings made with the
+builder take precedence over environment variables, which makes it
+possible to configure separate instances independently, e.g. in tests:
+
+```rust
+use rocket_prometheus::PrometheusMetrics;
+
+let prometheus = PrometheusMetrics::builder()
+    .namespace("myapp")
+    .buckets(vec![0.001, 0.01, 0.1, 1.0])
+    .build()
+    .expect("valid configuration");
+```
+
+[`PrometheusMetrics::new`] and [`PrometheusMetrics::with_registry`] use the
+builder with its default configuration.
+
+Common settings can also be loaded from Rocket's configuration, e.g. a
+`[default.prometheus]` table in `Rocket.toml`, by extracting a
+[`PrometheusConfig`] and passing it to [`PrometheusMetrics::from_config`].
+
+## Compression
+
+Enabling the `gzip` or `zstd` feature compresses the metrics response for
+scrapers which send a matching `Accept-Encoding` header (zstd is preferred if
+both are enabled and accepted). Responses are sent uncompressed otherwise.
+
+## Exposition formats
+
+Enabling the `protobuf` feature serves metrics in the Prometheus protobuf
+exposition format to scrapers which list it in their `Accept` header.
+Scrapers which accept `application/openmetrics-text` are sent the OpenMetrics
+format. The Prometheus text format is used otherwise.
+
+## Pushgateway
+
+Short-lived processes which can't be scraped can push their metrics to a
+Prometheus Pushgateway instead, using `PrometheusMetrics::push` (or
+`push_async` from async code). This requires the `pushgateway` feature.
+
+## JSON
+
+Enabling the `json` feature adds `PrometheusMetrics::gather_json`, which
+returns the gathered metrics as a `serde_json::Value`, for consumers such as
+custom dashboards which can't parse the Prometheus text format.
+
+## Process metrics
+
+Enabling the `process` feature and calling
+`PrometheusMetricsBuilder::process_metrics` exposes the standard
+`process_cpu_seconds_total`, `process_resident_memory_bytes`,
+`process_open_fds` and related metrics alongside the built-in ones. These are
+only available on Linux.
+
 ## Custom Metrics
 
 Further metrics can be tracked by registering them with the registry of the
@@ -104,35 +183,134 @@ fn launch() -> _ {
 #![deny(missing_docs)]
 #![deny(unsafe_code)]
 
-use std::{env, time::Instant};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex, OnceLock, PoisonError, RwLock,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 
-use prometheus::{opts, Encoder, HistogramVec, IntCounterVec, Registry, TextEncoder};
+use chrono::Timelike;
+use prometheus::{
+    core::Collector,
+    proto::{LabelPair, MetricFamily},
+    Gauge, GaugeVec, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+};
 use rocket::{
     fairing::{Fairing, Info, Kind},
-    http::{ContentType, Method},
+    futures::stream,
+    http::{ContentType, Header, MediaType, Method, Status},
+    response::stream::ByteStream,
     route::{Handler, Outcome},
-    Data, Request, Response, Route,
+    tokio::task,
+    Data, Orbit, Request, Response, Rocket, Route,
+};
+
+mod auth;
+mod builder;
+mod cache;
+mod callback;
+mod catcher;
+mod client_ip;
+mod compression;
+mod concurrency;
+mod config;
+mod exemplars;
+mod format;
+mod histograms;
+#[cfg(feature = "json")]
+mod json;
+#[cfg(feature = "pushgateway")]
+mod push;
+mod rates;
+mod retention;
+mod rolling;
+mod summary;
+mod table;
+#[cfg(feature = "test-util")]
+pub mod testing;
+mod timing;
+mod window;
+
+pub use builder::PrometheusMetricsBuilder;
+pub use catcher::mark_caught;
+pub use config::PrometheusConfig;
+pub use timing::{
+    exclude_duration, handler_start, mark_handler_start, request_start, ExcludedTime, HandlerStart,
 };
 
+use auth::BasicAuth;
+use cache::OutputCache;
+use callback::{CallbackGauge, FnCollector, UsageGauges};
+use client_ip::ProxyHeader;
+use compression::Encoding;
+use concurrency::ConcurrencyMax;
+use exemplars::Exemplars;
+use format::{CustomEncoder, Format};
+use histograms::DurationHistograms;
+use rates::{EndpointCap, EndpointRates};
+use retention::RetainedSeries;
+use rolling::RollingQuantiles;
+use summary::DurationSummary;
+use timing::{ExcludedDuration, HandlerStartTime};
+use window::RequestWindow;
+
 /// Re-export Prometheus so users can use it without having to explicitly
 /// add a specific version to their dependencies, which can result in
 /// mysterious compiler error messages.
+///
+/// This is only available with the `reexport-prometheus` feature, which is
+/// enabled by default.
+#[cfg(feature = "reexport-prometheus")]
 pub use prometheus;
 
 /// Environment variable used to configure the namespace of metrics exposed
 /// by `PrometheusMetrics`.
 const NAMESPACE_ENV_VAR: &str = "ROCKET_PROMETHEUS_NAMESPACE";
 
+/// `endpoint` label value used for the synthetic aggregate of all endpoints.
+const ALL_ENDPOINTS: &str = "__all__";
+
+/// `endpoint` label value used for rarely requested endpoints, if enabled.
+const OTHER_ENDPOINTS: &str = "<other>";
+
+/// `endpoint` label value used for endpoints beyond the configured maximum,
+/// if any.
+const OVERFLOW_ENDPOINTS: &str = "<overflow>";
+
+/// `host` label value used for requests without a `Host` header.
+const NO_HOST: &str = "<none>";
+
+/// `host` label value used for hosts which aren't in the allowlist.
+const OTHER_HOSTS: &str = "<other>";
+
+/// `endpoint` label value used for requests which didn't match a route, if
+/// they are tracked.
+const UNMATCHED_ENDPOINT: &str = "<unmatched>";
+
+/// Name given to the routes serving metrics, used to find them at liftoff.
+const ROUTE_NAME: &str = "rocket_prometheus";
+
+/// Rank of the routes below the mount point, such as `/rocket`, which is
+/// lower than the default ranks of application routes (-12 to -1).
+const SUFFIX_ROUTE_RANK: isize = 100;
+
 #[derive(Clone)]
 #[must_use = "must be attached and mounted to a Rocket instance"]
 /// Fairing and Handler implementing request instrumentation.
 ///
-/// By default this tracks two metrics:
+/// By default this tracks three metrics:
 ///
 /// - `rocket_http_requests_total` (labels: endpoint, method, status): the
 ///   total number of HTTP requests handled by Rocket.
 /// - `rocket_http_requests_duration_seconds` (labels: endpoint, method, status):
 ///   the request duration for all HTTP requests handled by Rocket.
+/// - `rocket_http_requests_in_flight`: the number of HTTP requests currently
+///   being handled by Rocket.
 ///
 /// The `rocket` prefix of these metrics can be changed by setting the
 /// `ROCKET_PROMETHEUS_NAMESPACE` environment variable.
@@ -174,6 +352,9 @@ const NAMESPACE_ENV_VAR: &str = "ROCKET_PROMETHEUS_NAMESPACE";
 /// rocket_http_requests_duration_seconds_bucket{endpoint="/metrics",method="GET",status="200",le="+Inf"} 2
 /// rocket_http_requests_duration_seconds_sum{endpoint="/metrics",method="GET",status="200"} 0.0011045669999999999
 /// rocket_http_requests_duration_seconds_count{endpoint="/metrics",method="GET",status="200"} 2
+/// # HELP rocket_http_requests_in_flight Number of HTTP requests currently being handled
+/// # TYPE rocket_http_requests_in_flight gauge
+/// rocket_http_requests_in_flight 1
 /// # HELP rocket_http_requests_total Total number of HTTP requests
 /// # TYPE rocket_http_requests_total counter
 /// rocket_http_requests_total{endpoint="/metrics",method="GET",status="200"} 2
@@ -181,7 +362,12 @@ const NAMESPACE_ENV_VAR: &str = "ROCKET_PROMETHEUS_NAMESPACE";
 pub struct PrometheusMetrics {
     // Standard metrics tracked by the fairing.
     http_requests_total: IntCounterVec,
-    http_requests_duration_seconds: HistogramVec,
+    duration_histograms: DurationHistograms,
+    http_requests_duration_summary: Option<DurationSummary>,
+    http_requests_in_flight: IntGauge,
+
+    // Trace-ID exemplars of the duration histogram, if enabled.
+    exemplars: Option<Arc<Exemplars>>,
 
     // The registry used by the fairing for Rocket metrics.
     //
@@ -198,8 +384,372 @@ pub struct PrometheusMetrics {
     //
     // See `rocket_registry` for details on why these metrics are stored on a separate registry.
     custom_registry: Registry,
+
+    // Extra collectors gathered after the custom registry.
+    extra_collectors: Arc<RwLock<Vec<Box<dyn Collector>>>>,
+
+    // Ordered (prefix, group) pairs used to group endpoints by route URI prefix.
+    endpoint_groups: Vec<(String, String)>,
+
+    // Syntax used for dynamic segments in the `endpoint` label.
+    endpoint_style: EndpointStyle,
+
+    // Unit in which the duration histogram is observed.
+    duration_unit: DurationUnit,
+
+    // Where the `method` label comes from.
+    method_source: MethodSource,
+
+    // Request rates used to collapse rarely requested endpoints, if enabled.
+    endpoint_rates: Option<EndpointRates>,
+
+    // Distinct endpoints recorded so far, if their number is capped.
+    endpoint_cap: Option<EndpointCap>,
+
+    // Sender half of the channel drained by the background recorder thread,
+    // if background recording is enabled.
+    background_recorder: Option<BackgroundRecorder>,
+
+    // Function used to transform response status codes before they are
+    // used as the `status` label.
+    status_mapper: Option<StatusMapper>,
+
+    // Function deriving the `endpoint` label from each request, replacing
+    // the route URI, if configured.
+    endpoint_mapper: Option<EndpointMapper>,
+
+    // Functions called after the built-in metrics are updated for a request
+    // (or it's sent to the background recorder), in registration order.
+    response_callbacks: Arc<Vec<ResponseCallback>>,
+
+    // Optional metrics, only created if enabled using the builder.
+    http_bad_content_type_total: Option<IntCounterVec>,
+    http_incomplete_bodies_total: Option<IntCounterVec>,
+    http_conditional_requests_total: Option<IntCounterVec>,
+    http_aborted_requests_total: Option<IntCounterVec>,
+    http_requests_received_total: Option<IntCounterVec>,
+    status_code_counters: Option<Arc<HashMap<u16, IntCounterVec>>>,
+    http_requests_by_hour_total: Option<HourlyCounter>,
+    http_request_last_timestamp_seconds: Option<GaugeVec>,
+    http_requests_errors_total: Option<IntCounterVec>,
+    http_requests_missing_timer_total: Option<IntCounterVec>,
+    guard_handler_durations: Option<GuardHandlerDurations>,
+    rolling_quantiles: Option<RollingQuantiles>,
+    http_requests_active_window: Option<RequestWindow>,
+    series_retention: Option<Arc<RetainedSeries>>,
+    http_requests_controllable_duration_seconds: Option<HistogramVec>,
+    http_request_size_bytes: Option<HistogramVec>,
+    http_requests_concurrency_max: Option<ConcurrencyMax>,
+
+    // Soft limit on the number of series per metric family, checked when
+    // metrics are scraped.
+    series_budget: Option<SeriesBudget>,
+
+    // Name of the active Rocket config profile, set during liftoff if the
+    // `profile` label is enabled.
+    profile: Option<Arc<OnceLock<String>>>,
+
+    // Counters created on demand by `PrometheusMetrics::inc_counter`, keyed by name.
+    event_counters: Arc<Mutex<HashMap<String, IntCounterVec>>>,
+
+    // Names of the endpoint, method and status labels.
+    label_names: Arc<LabelNames>,
+
+    // Route URIs whose requests aren't recorded.
+    ignored_routes: Arc<HashSet<String>>,
+
+    // Request methods whose requests aren't recorded.
+    ignored_methods: Arc<HashSet<Method>>,
+
+    // Whether requests to the routes serving metrics aren't recorded.
+    exclude_metrics_route: bool,
+
+    // If set, only these endpoints have their duration observed.
+    duration_endpoints: Option<Arc<HashSet<String>>>,
+
+    // Comment lines (already prefixed with `# `) written before the metrics.
+    comment: Option<Arc<str>>,
+
+    // The rendered `target_info` family, written in the OpenMetrics format.
+    target_info: Option<Arc<str>>,
+
+    // Whether to label `http_requests_total` and `http_requests_duration_seconds`
+    // respectively by status class rather than status code.
+    counter_status_class: bool,
+    histogram_status_class: bool,
+
+    // Whether to also count every request under the synthetic `__all__` endpoint.
+    aggregate_endpoints: bool,
+
+    // Whether `http_requests_total` is incremented.
+    request_counter: bool,
+
+    // Whether `http_requests_duration_seconds` is observed.
+    duration_histogram: bool,
+
+    // Whether any metric needs the request start time.
+    time_requests: bool,
+
+    // Whether requests which didn't match a route are recorded.
+    track_unmatched: bool,
+
+    // Whether responses from catchers are recorded under a `<catcher:CODE>`
+    // endpoint.
+    catcher_endpoints: bool,
+
+    // Whether the handler streams the encoded metrics one family at a time.
+    stream_output: bool,
+
+    // `Content-Type` used instead of the default for the Prometheus text
+    // format, if configured.
+    text_content_type: Option<ContentType>,
+
+    // Encoder used for all responses instead of the negotiated format, if
+    // configured.
+    custom_encoder: Option<Arc<CustomEncoder>>,
+
+    // Encoded metrics reused by the handler until they expire, if enabled.
+    output_cache: Option<Arc<OutputCache>>,
+
+    // Header whose presence is recorded in the `has_request_id` label, if enabled.
+    request_id_header: Option<String>,
+
+    // Token which must be passed as the `token` query parameter to scrape
+    // metrics, if configured.
+    token: Option<Arc<str>>,
+
+    // Credentials which must be passed using HTTP Basic authentication to
+    // scrape metrics, if configured.
+    basic_auth: Option<Arc<BasicAuth>>,
+
+    // Addresses from which metrics may be scraped, if restricted.
+    allowed_ips: Option<Arc<HashSet<IpAddr>>>,
+
+    // Header to read the client's address from when sent by a trusted proxy,
+    // if configured.
+    proxy_header: Option<Arc<ProxyHeader>>,
+
+    // Whether to log a trace of the timestamps captured for each request.
+    trace_timings: bool,
+
+    // Whether to log a trace of the order in which fairings saw each request.
+    trace_fairing_order: bool,
+
+    // Whether to add the matched route's method as the `route_method` label.
+    route_method_label: bool,
+
+    // Whether to add the matched route's name (or URI) as the `route_name` label.
+    route_name_label: bool,
+
+    // Whether to add the `has_query` label.
+    query_label: bool,
+
+    // Lowercased hosts recorded in the `host` label, if it's enabled.
+    host_allowlist: Option<Arc<HashSet<String>>>,
+
+    // Whether to add the `status_class` label.
+    status_class_label: bool,
+
+    // The registries served by this instance's handler, which depends on the
+    // route it was mounted as.
+    served_registries: Registries,
+
+    // The methods the handler's routes are registered for.
+    handler_methods: Arc<Vec<Method>>,
+
+    // Whether a `summary` route rendering a table of duration quantiles is
+    // added.
+    quantile_table_route: bool,
+
+    // Whether this instance's handler serves the quantile table instead of
+    // metrics, which depends on the route it was mounted as.
+    serves_quantile_table: bool,
+
+    // State used to detect setup mistakes, if enabled.
+    setup_check: Option<Arc<SetupCheck>>,
+}
+
+/// The syntax used for dynamic segments of route URIs in the `endpoint` label.
+///
+/// See [`PrometheusMetricsBuilder::endpoint_style`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EndpointStyle {
+    /// Rocket's own syntax, e.g. `/users/<id>` and `/files/<path..>`.
+    #[default]
+    Rocket,
+    /// Colon syntax, e.g. `/users/:id` and `/files/*path`.
+    Colon,
+    /// Brace syntax, e.g. `/users/{id}` and `/files/{*path}`.
+    Braces,
+}
+
+impl EndpointStyle {
+    /// Rewrite the dynamic segments of a route URI in this style.
+    fn rewrite(self, uri: &str) -> Cow<'_, str> {
+        if self == Self::Rocket || !uri.contains('<') {
+            return Cow::Borrowed(uri);
+        }
+        let mut rewritten = String::with_capacity(uri.len());
+        let mut rest = uri;
+        while let Some(open) = rest.find('<') {
+            let close = match rest[open..].find('>') {
+                Some(close) => open + close,
+                None => break,
+            };
+            rewritten.push_str(&rest[..open]);
+            let name = &rest[open + 1..close];
+            match (self, name.strip_suffix("..")) {
+                (Self::Colon, None) => rewritten.extend([":", name]),
+                (Self::Colon, Some(name)) => rewritten.extend(["*", name]),
+                (_, None) => rewritten.extend(["{", name, "}"]),
+                (_, Some(name)) => rewritten.extend(["{*", name, "}"]),
+            }
+            rest = &rest[close + 1..];
+        }
+        rewritten.push_str(rest);
+        Cow::Owned(rewritten)
+    }
+}
+
+/// The unit in which request durations are observed.
+///
+/// See [`PrometheusMetricsBuilder::duration_unit`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DurationUnit {
+    /// Seconds, as recommended by Prometheus conventions.
+    #[default]
+    Seconds,
+    /// Milliseconds.
+    Milliseconds,
+}
+
+impl DurationUnit {
+    /// The name of the unit, used as the suffix of metric names.
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Seconds => "seconds",
+            Self::Milliseconds => "milliseconds",
+        }
+    }
+
+    /// The number of this unit in one second.
+    const fn per_second(self) -> f64 {
+        match self {
+            Self::Seconds => 1.0,
+            Self::Milliseconds => 1_000.0,
+        }
+    }
+}
+
+/// Where the `method` label of the built-in metrics comes from.
+///
+/// See [`PrometheusMetricsBuilder::method_label_source`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MethodSource {
+    /// The method of the request itself.
+    #[default]
+    Request,
+    /// The method declared by the matched route, falling back to the method
+    /// of the request if no route matched.
+    Route,
+}
+
+/// Which registries a handler serves metrics from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Registries {
+    /// Both the custom and internal registries.
+    All,
+    /// Only the internal registry holding the built-in metrics.
+    Rocket,
+    /// Only the custom registry.
+    Custom,
+}
+
+/// Tracks whether an instance was attached, to report setup mistakes.
+#[derive(Default)]
+struct SetupCheck {
+    /// Set when the fairing lifts off, which only happens if it was attached.
+    attached: AtomicBool,
+    /// Set once a missing fairing has been reported by the handler.
+    reported: AtomicBool,
+}
+
+/// Histograms splitting request duration into time spent before and after
+/// the handler start was marked.
+#[derive(Clone)]
+struct GuardHandlerDurations {
+    guards: HistogramVec,
+    handler: HistogramVec,
+}
+
+/// Counter of requests by hour of the day.
+#[derive(Clone)]
+struct HourlyCounter {
+    counter: IntCounterVec,
+    /// Offset from UTC, in seconds, used to compute the hour, or `None` to
+    /// use the local time zone.
+    utc_offset_secs: Option<i64>,
+}
+
+/// `hour` label values, indexed by hour.
+const HOURS: [&str; 24] = [
+    "0", "1", "2", "3", "4", "5", "6", "7", "8", "9", "10", "11", "12", "13", "14", "15", "16",
+    "17", "18", "19", "20", "21", "22", "23",
+];
+
+impl HourlyCounter {
+    /// The current hour of the day (0-23) at the configured UTC offset, or
+    /// in the local time zone.
+    fn current_hour(&self) -> usize {
+        let utc_offset_secs = match self.utc_offset_secs {
+            Some(utc_offset_secs) => utc_offset_secs,
+            None => return chrono::Local::now().hour() as usize,
+        };
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs() as i64);
+        ((now + utc_offset_secs).rem_euclid(86_400) / 3_600) as usize
+    }
+}
+
+/// Names of the labels shared by the built-in request metrics.
+struct LabelNames {
+    endpoint: String,
+    method: String,
+    status: String,
 }
 
+impl Default for LabelNames {
+    fn default() -> Self {
+        Self {
+            endpoint: "endpoint".into(),
+            method: "method".into(),
+            status: "status".into(),
+        }
+    }
+}
+
+/// A soft limit on the number of series in each metric family.
+#[derive(Clone)]
+struct SeriesBudget {
+    /// The maximum number of series a family may have before a warning is emitted.
+    max_series: usize,
+    /// Counter incremented for each family found to exceed the budget.
+    warnings: IntCounter,
+}
+
+/// Function used to transform response status codes before labelling.
+type StatusMapper = Arc<dyn Fn(u16) -> u16 + Send + Sync>;
+
+/// Function used to derive the `endpoint` label from a request.
+type EndpointMapper = Arc<dyn Fn(&Request<'_>) -> String + Send + Sync>;
+
+/// Function called with the duration and status of each recorded request.
+type ResponseCallback = Box<dyn Fn(&Request<'_>, Duration, Status) + Send + Sync>;
+
 impl PrometheusMetrics {
     /// Create a new [`PrometheusMetrics`].
     pub fn new() -> Self {
@@ -207,44 +757,21 @@ impl PrometheusMetrics {
     }
 
     /// Create a new [`PrometheusMetrics`] with a custom [`Registry`].
-    // Allow `clippy::missing_panics_doc` because we know:
-    // - the two metrics can't fail to be created (their config is valid)
-    // - registering the metrics can't fail (the registry is new, so there is no chance of metric duplication)
-    #[allow(clippy::missing_panics_doc)]
+    ///
+    /// # Panics
+    ///
+    /// Panics if the `ROCKET_PROMETHEUS_NAMESPACE` environment variable isn't
+    /// a valid metric name prefix. The default metrics can't otherwise fail
+    /// to be created, and registering them can't fail since they're
+    /// registered on a new internal registry. Use
+    /// [`PrometheusMetrics::builder`] to handle the error instead.
     pub fn with_registry(registry: Registry) -> Self {
-        let rocket_registry = Registry::new();
-        let namespace = env::var(NAMESPACE_ENV_VAR).unwrap_or_else(|_| "rocket".into());
-
-        let http_requests_total_opts =
-            opts!("http_requests_total", "Total number of HTTP requests")
-                .namespace(namespace.clone());
-        let http_requests_total =
-            IntCounterVec::new(http_requests_total_opts, &["endpoint", "method", "status"])
-                .unwrap();
-        let http_requests_duration_seconds_opts = opts!(
-            "http_requests_duration_seconds",
-            "HTTP request duration in seconds for all requests"
-        )
-        .namespace(namespace);
-        let http_requests_duration_seconds = HistogramVec::new(
-            http_requests_duration_seconds_opts.into(),
-            &["endpoint", "method", "status"],
-        )
-        .unwrap();
-
-        rocket_registry
-            .register(Box::new(http_requests_total.clone()))
-            .unwrap();
-        rocket_registry
-            .register(Box::new(http_requests_duration_seconds.clone()))
-            .unwrap();
+        Self::builder().registry(registry).build().unwrap()
+    }
 
-        Self {
-            http_requests_total,
-            http_requests_duration_seconds,
-            rocket_registry,
-            custom_registry: registry,
-        }
+    /// Create a [`PrometheusMetricsBuilder`] to configure a new [`PrometheusMetrics`].
+    pub fn builder() -> PrometheusMetricsBuilder {
+        PrometheusMetricsBuilder::new()
     }
 
     /// Create a new [`PrometheusMetrics`] using the default Prometheus [`Registry`].
@@ -288,8 +815,662 @@ impl PrometheusMetrics {
     }
 
     /// Get the `http_requests_duration_seconds` metric.
+    ///
+    /// Its name and buckets depend on the configured
+    /// [duration unit](PrometheusMetricsBuilder::duration_unit).
     pub fn http_requests_duration_seconds(&self) -> &HistogramVec {
-        &self.http_requests_duration_seconds
+        self.duration_histograms.default_histogram()
+    }
+
+    /// Get the `http_requests_in_flight` metric.
+    pub fn http_requests_in_flight(&self) -> &IntGauge {
+        &self.http_requests_in_flight
+    }
+
+    /// Get the cumulative bucket counts of the `http_requests_duration_seconds`
+    /// series with the given labels, as `(upper_bound, count)` pairs in order
+    /// of increasing upper bound.
+    ///
+    /// The implicit `+Inf` bucket isn't included. If the series has extra
+    /// labels (e.g. `has_request_id`, or the `status_class` label added by
+    /// [`PrometheusMetricsBuilder::status_class_label`]), the counts of all
+    /// series matching the given labels are summed. If the histogram is
+    /// labelled by status class instead of status (see
+    /// [`PrometheusMetricsBuilder::histogram_status_class`]), `status` should
+    /// be a class such as `2xx`. Returns an empty `Vec` if no request
+    /// matching the labels has been observed.
+    ///
+    /// ```rust
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let prometheus = PrometheusMetrics::new();
+    /// prometheus
+    ///     .http_requests_duration_seconds()
+    ///     .with_label_values(&["/", "GET", "200"])
+    ///     .observe(0.02);
+    /// let buckets = prometheus.duration_buckets("/", "GET", "200");
+    /// assert_eq!(buckets[0], (0.005, 0));
+    /// assert_eq!(buckets[3], (0.05, 1));
+    /// ```
+    pub fn duration_buckets(&self, endpoint: &str, method: &str, status: &str) -> Vec<(f64, u64)> {
+        let names = &self.label_names;
+        // The status label is renamed when the histogram is labelled by class.
+        let status_name = if self.histogram_status_class {
+            "status_class"
+        } else {
+            names.status.as_str()
+        };
+        let wanted = [
+            (names.endpoint.as_str(), endpoint),
+            (names.method.as_str(), method),
+            (status_name, status),
+        ];
+        let families = self.duration_histograms.collect();
+        let mut buckets: Vec<(f64, u64)> = vec![];
+        for family in families {
+            for metric in family.get_metric() {
+                let matches = metric.get_label().iter().all(|label| {
+                    wanted
+                        .iter()
+                        .find(|(name, _)| *name == label.get_name())
+                        .map_or(true, |(_, value)| *value == label.get_value())
+                });
+                if !matches {
+                    continue;
+                }
+                for (i, bucket) in metric.get_histogram().get_bucket().iter().enumerate() {
+                    match buckets.get_mut(i) {
+                        Some((_, count)) => *count += bucket.get_cumulative_count(),
+                        None => {
+                            buckets.push((bucket.get_upper_bound(), bucket.get_cumulative_count()))
+                        }
+                    }
+                }
+            }
+        }
+        buckets
+    }
+
+    /// Get the `http_incomplete_bodies_total` metric, if enabled using
+    /// [`PrometheusMetricsBuilder::incomplete_bodies_counter`].
+    ///
+    /// This is a user-driven counter which the fairing never increments,
+    /// since it can't detect incomplete bodies itself. Handlers reading
+    /// request bodies should increment it (labels: endpoint) when a client
+    /// disconnects before sending the full body.
+    pub fn http_incomplete_bodies_total(&self) -> Option<&IntCounterVec> {
+        self.http_incomplete_bodies_total.as_ref()
+    }
+
+    /// Increment the counter called `name` with the given labels, creating
+    /// and registering it on the custom registry if it doesn't exist yet.
+    ///
+    /// This is convenient when only the [`PrometheusMetrics`] instance is to
+    /// hand (for example from managed state) and declaring the counter up
+    /// front would be awkward. Looking up the counter takes a lock, so prefer
+    /// a pre-declared metric on hot paths.
+    ///
+    /// The set of label names used for a given `name` must be the same on
+    /// every call, since it is fixed when the counter is first created. It is
+    /// the caller's responsibility to keep the label values low-cardinality:
+    /// each distinct combination creates a new series which is never removed.
+    ///
+    /// ```rust
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let prometheus = PrometheusMetrics::new();
+    /// prometheus
+    ///     .inc_counter("cache_misses_total", &[("cache", "users")])
+    ///     .unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the counter can't be created or registered (for
+    /// example if `name` is invalid or already used by a different metric in
+    /// the custom registry), or if the label names don't match those used
+    /// when the counter was created.
+    pub fn inc_counter(
+        &self,
+        name: &str,
+        labels: &[(&str, &str)],
+    ) -> Result<(), prometheus::Error> {
+        let counter = {
+            let mut counters = self
+                .event_counters
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            match counters.get(name) {
+                Some(counter) => counter.clone(),
+                None => {
+                    let label_names: Vec<&str> = labels.iter().map(|(name, _)| *name).collect();
+                    let counter = IntCounterVec::new(
+                        Opts::new(name, "Counter created by PrometheusMetrics::inc_counter"),
+                        &label_names,
+                    )?;
+                    self.custom_registry.register(Box::new(counter.clone()))?;
+                    counters.insert(name.to_owned(), counter.clone());
+                    counter
+                }
+            }
+        };
+        let labels: HashMap<&str, &str> = labels.iter().copied().collect();
+        counter.get_metric_with(&labels)?.inc();
+        Ok(())
+    }
+
+    /// Add a collector to be gathered by the handler without registering it
+    /// on a [`Registry`].
+    ///
+    /// Metrics from added collectors are served alongside those from the
+    /// registries, sorted by name, and are included when serving only the
+    /// custom registry.
+    /// Unlike registries, no checks are made for invalid or duplicate
+    /// metrics, so the collector is responsible for producing valid output.
+    ///
+    /// ```rust
+    /// use prometheus::IntGauge;
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let gauge = IntGauge::new("lazily_created", "A gauge created elsewhere").unwrap();
+    /// let prometheus = PrometheusMetrics::new();
+    /// prometheus.add_collector(Box::new(gauge));
+    /// ```
+    pub fn add_collector(&self, collector: Box<dyn Collector>) {
+        self.extra_collectors
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(collector);
+    }
+
+    /// Add a collector which calls `collect` to produce its metric families
+    /// each time metrics are gathered by the handler.
+    ///
+    /// This suits metrics which are expensive to compute, so should only be
+    /// computed when scraped, without maintaining a background task to keep
+    /// them up to date. The callback runs on the blocking thread pool while
+    /// the handler gathers metrics, so it may take a while, but each scrape
+    /// waits for it. As with [`PrometheusMetrics::add_collector`], the
+    /// families are served alongside the custom registry, and no checks are
+    /// made for invalid or duplicate metrics.
+    ///
+    /// ```rust
+    /// use prometheus::{core::Collector, IntGauge};
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// # fn count_orphaned_rows() -> i64 { 0 }
+    /// let prometheus = PrometheusMetrics::new();
+    /// prometheus.register_fn(|| {
+    ///     let gauge = IntGauge::new("orphaned_rows", "Rows without a parent").unwrap();
+    ///     gauge.set(count_orphaned_rows());
+    ///     gauge.collect()
+    /// });
+    /// ```
+    pub fn register_fn<F>(&self, collect: F)
+    where
+        F: Fn() -> Vec<MetricFamily> + Send + Sync + 'static,
+    {
+        self.add_collector(Box::new(FnCollector::new(Box::new(collect))));
+    }
+
+    /// Register a gauge called `name` on the custom registry whose value is
+    /// computed by calling `callback` each time metrics are gathered.
+    ///
+    /// This suits values which are cheap to sample but expensive to keep up
+    /// to date, such as a queue depth or connection pool size, without
+    /// needing a background task to update them. The callback runs on every
+    /// scrape, while metrics are being gathered, so it should be quick and
+    /// must not block.
+    ///
+    /// ```rust
+    /// use std::sync::{
+    ///     atomic::{AtomicUsize, Ordering},
+    ///     Arc,
+    /// };
+    ///
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let queue = Arc::new(AtomicUsize::new(0));
+    /// let prometheus = PrometheusMetrics::new();
+    /// let depth = Arc::clone(&queue);
+    /// prometheus
+    ///     .register_callback_gauge("queue_depth", "Jobs waiting in the queue", move || {
+    ///         depth.load(Ordering::Relaxed) as f64
+    ///     })
+    ///     .unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the gauge can't be created or registered, for
+    /// example if `name` is invalid or already used in the custom registry.
+    pub fn register_callback_gauge<F>(
+        &self,
+        name: &str,
+        help: &str,
+        callback: F,
+    ) -> Result<(), prometheus::Error>
+    where
+        F: Fn() -> f64 + Send + Sync + 'static,
+    {
+        let gauge = CallbackGauge::new(Gauge::new(name, help)?, Box::new(callback));
+        self.custom_registry.register(Box::new(gauge))
+    }
+
+    /// Register a pair of gauges on the custom registry exposing the usage of
+    /// a resource such as a connection pool, computed by calling `callback`
+    /// each time metrics are gathered.
+    ///
+    /// `callback` returns `(used, capacity)`, which are exposed as
+    /// `{name}_used` and `{name}_capacity` respectively, with help text
+    /// derived from `help`. It's called once per scrape, so the two values
+    /// are consistent with each other. See
+    /// [`PrometheusMetrics::register_callback_gauge`] for caveats.
+    ///
+    /// ```rust
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// # struct Pool;
+    /// # impl Pool {
+    /// #     fn in_use(&self) -> u32 { 3 }
+    /// #     fn max_size(&self) -> u32 { 10 }
+    /// # }
+    /// let pool = std::sync::Arc::new(Pool);
+    /// let prometheus = PrometheusMetrics::new();
+    /// let stats = std::sync::Arc::clone(&pool);
+    /// prometheus
+    ///     .register_usage_gauges("db_pool_connections", "Database connections", move || {
+    ///         (f64::from(stats.in_use()), f64::from(stats.max_size()))
+    ///     })
+    ///     .unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the gauges can't be created or registered, for
+    /// example if `name` is invalid or already used in the custom registry.
+    pub fn register_usage_gauges<F>(
+        &self,
+        name: &str,
+        help: &str,
+        callback: F,
+    ) -> Result<(), prometheus::Error>
+    where
+        F: Fn() -> (f64, f64) + Send + Sync + 'static,
+    {
+        let used = Gauge::new(format!("{}_used", name), format!("{} in use", help))?;
+        let capacity = Gauge::new(
+            format!("{}_capacity", name),
+            format!("{} available in total", help),
+        )?;
+        let gauges = UsageGauges::new(used, capacity, Box::new(callback));
+        self.custom_registry.register(Box::new(gauges))
+    }
+
+    /// Get the `endpoint` label for a route URI, applying any configured
+    /// prefix groups and endpoint style.
+    fn endpoint_label<'a>(&'a self, uri: &'a str) -> Cow<'a, str> {
+        match self
+            .endpoint_groups
+            .iter()
+            .find(|(prefix, _)| uri.starts_with(prefix.as_str()))
+        {
+            Some((_, group)) => Cow::Borrowed(group.as_str()),
+            None => self.endpoint_style.rewrite(uri),
+        }
+    }
+
+    /// Gather metric families from the custom and internal registries, or
+    /// from just one of them.
+    ///
+    /// Families are sorted by name, so that output is stable even though
+    /// they come from several registries and collectors. The sort is stable,
+    /// so families with the same name keep the order of their sources: the
+    /// custom registry, added collectors, then the internal registry.
+    fn gather_registries(&self, registries: Registries) -> Vec<MetricFamily> {
+        let mut families = match registries {
+            Registries::All | Registries::Custom => self.custom_registry.gather(),
+            Registries::Rocket => vec![],
+        };
+        if registries != Registries::Rocket {
+            let collectors = self
+                .extra_collectors
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            // Empty families can't be encoded, so skip them like registries do.
+            families.extend(
+                collectors
+                    .iter()
+                    .flat_map(|collector| collector.collect())
+                    .filter(|family| !family.get_metric().is_empty()),
+            );
+        }
+        if registries != Registries::Custom {
+            self.remove_expired_series();
+            let mut rocket_families = self.rocket_registry.gather();
+            self.add_profile_label(&mut rocket_families);
+            families.append(&mut rocket_families);
+        }
+        families.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+        families
+    }
+
+    /// Remove the series of the request counter and duration histogram which
+    /// haven't been observed within the retention period, if configured.
+    fn remove_expired_series(&self) {
+        let retention = match &self.series_retention {
+            Some(retention) => retention,
+            None => return,
+        };
+        let now = Instant::now();
+        for labels in retention.counter.expire(now) {
+            let labels: Vec<&str> = labels.iter().map(String::as_str).collect();
+            // The series may already have been removed by `reset`.
+            let _ = self.http_requests_total.remove_label_values(&labels);
+        }
+        for labels in retention.histogram.expire(now) {
+            let labels: Vec<&str> = labels.iter().map(String::as_str).collect();
+            self.duration_histograms.remove(&labels);
+        }
+    }
+
+    /// Gather the metrics which the handler serves, without going through
+    /// Rocket.
+    ///
+    /// This decouples collection from exposition: the fairing can be
+    /// attached without mounting the handler, and metrics read with this
+    /// method, e.g. to push them elsewhere. Families from both the custom
+    /// and the internal registry and from added collectors are included,
+    /// sorted by name.
+    ///
+    /// ```rust
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let prometheus = PrometheusMetrics::new();
+    /// let families = prometheus.gather();
+    /// assert!(families
+    ///     .iter()
+    ///     .any(|family| family.get_name() == "rocket_http_requests_in_flight"));
+    /// ```
+    pub fn gather(&self) -> Vec<MetricFamily> {
+        let families = self.gather_registries(Registries::All);
+        self.check_series_budget(&families);
+        families
+    }
+
+    /// Render the metrics which the handler serves in the Prometheus text
+    /// format, without going through Rocket.
+    ///
+    /// This is useful in tests, or for sending metrics elsewhere. Metrics
+    /// from both the custom and the internal registry are included, preceded
+    /// by any configured [comment](PrometheusMetricsBuilder::comment).
+    ///
+    /// ```rust
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let prometheus = PrometheusMetrics::new();
+    /// let text = prometheus.gather_text().unwrap();
+    /// assert!(text.contains("# TYPE rocket_http_requests_in_flight gauge"));
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a metric family can't be encoded, e.g. because a
+    /// custom collector produced a family without a name.
+    pub fn gather_text(&self) -> Result<String, prometheus::Error> {
+        let buffer = self.encode_metrics(Registries::All, Format::Text)?;
+        String::from_utf8(buffer).map_err(|e| prometheus::Error::Msg(e.to_string()))
+    }
+
+    /// Reset the built-in metrics, removing all of their labelled series.
+    ///
+    /// This is intended for tests which reuse a server across scenarios, and
+    /// shouldn't be used in production: Prometheus assumes counters only
+    /// decrease when a process restarts, so a reset while being scraped
+    /// shows up as a spurious restart. `http_requests_in_flight` isn't
+    /// reset, since it reflects requests currently being handled, and
+    /// observations still queued for the
+    /// [background recorder](PrometheusMetricsBuilder::record_in_background)
+    /// may be recorded after the reset. Metrics in the custom registry,
+    /// including those created by [`PrometheusMetrics::inc_counter`], and
+    /// added collectors aren't affected.
+    ///
+    /// ```rust
+    /// use rocket_prometheus::PrometheusMetrics;
+    ///
+    /// let prometheus = PrometheusMetrics::new();
+    /// prometheus
+    ///     .http_requests_total()
+    ///     .with_label_values(&["/", "GET", "200"])
+    ///     .inc();
+    /// prometheus.reset();
+    /// assert!(!prometheus.gather_text().unwrap().contains("rocket_http_requests_total"));
+    /// ```
+    pub fn reset(&self) {
+        self.http_requests_total.reset();
+        self.duration_histograms.reset();
+        if let Some(summary) = &self.http_requests_duration_summary {
+            summary.reset();
+        }
+        if let Some(exemplars) = &self.exemplars {
+            exemplars.reset();
+        }
+        if let Some(cap) = &self.endpoint_cap {
+            cap.reset();
+        }
+        for counter in [
+            &self.http_bad_content_type_total,
+            &self.http_incomplete_bodies_total,
+            &self.http_conditional_requests_total,
+            &self.http_aborted_requests_total,
+            &self.http_requests_received_total,
+            &self.http_requests_errors_total,
+            &self.http_requests_missing_timer_total,
+        ]
+        .iter()
+        .copied()
+        .flatten()
+        {
+            counter.reset();
+        }
+        for counter in self.status_code_counters.iter().flat_map(|c| c.values()) {
+            counter.reset();
+        }
+        if let Some(hourly) = &self.http_requests_by_hour_total {
+            hourly.counter.reset();
+        }
+        if let Some(gauge) = &self.http_request_last_timestamp_seconds {
+            gauge.reset();
+        }
+        if let Some(split) = &self.guard_handler_durations {
+            split.guards.reset();
+            split.handler.reset();
+        }
+        if let Some(rolling) = &self.rolling_quantiles {
+            rolling.reset();
+        }
+        if let Some(window) = &self.http_requests_active_window {
+            window.reset();
+        }
+        for histogram in [
+            &self.http_requests_controllable_duration_seconds,
+            &self.http_request_size_bytes,
+        ]
+        .iter()
+        .copied()
+        .flatten()
+        {
+            histogram.reset();
+        }
+        if let Some(concurrency) = &self.http_requests_concurrency_max {
+            concurrency.reset();
+        }
+        if let Some(retention) = &self.series_retention {
+            retention.counter.reset();
+            retention.histogram.reset();
+        }
+        if let Some(budget) = &self.series_budget {
+            budget.warnings.reset();
+        }
+        if let Some(cache) = &self.output_cache {
+            cache.clear();
+        }
+    }
+
+    /// Gather metrics from the given registries and encode them in `format`,
+    /// including any comment and trailer.
+    fn encode_metrics(
+        &self,
+        registries: Registries,
+        format: Format,
+    ) -> Result<Vec<u8>, prometheus::Error> {
+        let families = self.gather_registries(registries);
+        self.check_series_budget(&families);
+
+        let mut buffer = vec![];
+        if let Some(encoder) = &self.custom_encoder {
+            encoder.encode(&families, &mut buffer)?;
+            return Ok(buffer);
+        }
+        if let Some(comment) = self.comment.as_ref().filter(|_| format.supports_comments()) {
+            buffer.extend_from_slice(comment.as_bytes());
+        }
+        format.encode(&families, self.exemplars.as_deref(), &mut buffer)?;
+        if let Some(target_info) = self.target_info(format) {
+            buffer.extend_from_slice(target_info);
+        }
+        if let Some(trailer) = format.trailer() {
+            buffer.extend_from_slice(trailer);
+        }
+        Ok(buffer)
+    }
+
+    /// The rendered `target_info` family, if configured and supported by
+    /// `format`.
+    fn target_info(&self, format: Format) -> Option<&[u8]> {
+        self.target_info
+            .as_deref()
+            .filter(|_| format == Format::OpenMetrics)
+            .map(str::as_bytes)
+    }
+
+    /// Warn about any metric families with more series than the configured budget.
+    fn check_series_budget<'a>(&self, families: impl IntoIterator<Item = &'a MetricFamily>) {
+        let budget = match &self.series_budget {
+            Some(budget) => budget,
+            None => return,
+        };
+        for family in families {
+            let series = family.get_metric().len();
+            if series > budget.max_series {
+                log::warn!(
+                    "metric family {} has {} series, exceeding the budget of {}",
+                    family.get_name(),
+                    series,
+                    budget.max_series,
+                );
+                budget.warnings.inc();
+            }
+        }
+    }
+
+    /// Add the `profile` label to every metric in `families`, if enabled and
+    /// the profile is known.
+    // Without the `protobuf` feature of `prometheus`, `take_label` returns a
+    // `Vec` already, so the conversions are no-ops.
+    #[allow(clippy::useless_conversion)]
+    fn add_profile_label(&self, families: &mut [MetricFamily]) {
+        let profile = match self.profile.as_ref().and_then(|profile| profile.get()) {
+            Some(profile) => profile,
+            None => return,
+        };
+        for family in families {
+            for metric in family.mut_metric().iter_mut() {
+                let mut label = LabelPair::default();
+                label.set_name("profile".into());
+                label.set_value(profile.clone());
+                let mut labels: Vec<_> = metric.take_label().into();
+                labels.push(label);
+                metric.set_label(labels.into());
+            }
+        }
+    }
+
+    /// Update the built-in metrics for a single request.
+    ///
+    /// `labels` are the values of the built-in labels, starting with the
+    /// endpoint, method and status. `trace_id` is used for the exemplar of
+    /// the duration histogram, if exemplars are enabled.
+    fn record(&self, labels: &[&str], duration: Option<Duration>, trace_id: Option<String>) {
+        let class_labels = (self.counter_status_class || self.histogram_status_class).then(|| {
+            let mut class_labels = labels.to_vec();
+            class_labels[2] = status_class(labels[2]);
+            class_labels
+        });
+        let (counter_labels, histogram_labels) = match class_labels.as_deref() {
+            Some(class_labels) => (
+                if self.counter_status_class {
+                    class_labels
+                } else {
+                    labels
+                },
+                if self.histogram_status_class {
+                    class_labels
+                } else {
+                    labels
+                },
+            ),
+            None => (labels, labels),
+        };
+
+        let now = Instant::now();
+        if self.request_counter {
+            self.http_requests_total
+                .with_label_values(counter_labels)
+                .inc();
+            if self.aggregate_endpoints {
+                let mut aggregate = counter_labels.to_vec();
+                aggregate[0] = ALL_ENDPOINTS;
+                self.http_requests_total.with_label_values(&aggregate).inc();
+                if let Some(retention) = &self.series_retention {
+                    retention.counter.touch(&aggregate, now);
+                }
+            }
+            if let Some(retention) = &self.series_retention {
+                retention.counter.touch(counter_labels, now);
+            }
+        }
+        if let Some(window) = &self.http_requests_active_window {
+            window.record(counter_labels);
+        }
+        let observe_duration = self
+            .duration_endpoints
+            .as_ref()
+            .map_or(true, |endpoints| endpoints.contains(labels[0]));
+        if let Some(duration) = duration.filter(|_| observe_duration) {
+            let duration_secs = duration.as_secs_f64();
+            if self.duration_histogram {
+                let value = duration_secs * self.duration_unit.per_second();
+                let buckets = self.duration_histograms.observe(histogram_labels, value);
+                if let Some(retention) = &self.series_retention {
+                    retention.histogram.touch(histogram_labels, now);
+                }
+                if let (Some(exemplars), Some(trace_id)) = (&self.exemplars, trace_id) {
+                    if exemplars.exceeds_threshold(duration) {
+                        exemplars.observe(histogram_labels, value, buckets, trace_id);
+                    }
+                }
+            }
+            if let Some(summary) = &self.http_requests_duration_summary {
+                summary.observe(
+                    histogram_labels,
+                    duration_secs * self.duration_unit.per_second(),
+                );
+            }
+            if let Some(rolling) = &self.rolling_quantiles {
+                rolling.observe(labels[0], duration_secs);
+            }
+        }
     }
 }
 
@@ -303,6 +1484,114 @@ impl Default for PrometheusMetrics {
 #[derive(Copy, Clone)]
 struct TimerStart(Option<Instant>);
 
+/// Value stored in request-local state recording when instances of this
+/// fairing saw a request, for diagnosing fairing ordering issues.
+#[derive(Default)]
+struct FairingTrace {
+    stamps: AtomicUsize,
+    events: Mutex<Vec<String>>,
+}
+
+impl FairingTrace {
+    /// Record an event, stamped with its position among all recorded events.
+    fn record(&self, event: impl std::fmt::Display) {
+        let stamp = self.stamps.fetch_add(1, Ordering::Relaxed);
+        self.events
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(format!("#{} {}", stamp, event));
+    }
+
+    /// Log all events recorded so far.
+    fn log(&self) {
+        let events = self.events.lock().unwrap_or_else(PoisonError::into_inner);
+        log::trace!("fairing order: {}", events.join(", "));
+    }
+}
+
+/// A request observation sent to the background recorder thread.
+struct Observation {
+    labels: RequestLabels<Arc<str>>,
+    duration: Option<Duration>,
+    trace_id: Option<String>,
+}
+
+/// The sending side of the background recorder.
+#[derive(Clone)]
+struct BackgroundRecorder {
+    sender: mpsc::Sender<Observation>,
+    // Shared copies of the label values sent to the recorder.
+    label_values: Arc<LabelValues>,
+}
+
+/// Label values interned for the background recorder, so that sending an
+/// observation doesn't allocate once its values have been seen.
+#[derive(Default)]
+struct LabelValues(RwLock<HashSet<Arc<str>>>);
+
+impl LabelValues {
+    /// Get the shared copy of `value`, adding it if it hasn't been seen.
+    fn intern(&self, value: &str) -> Arc<str> {
+        let values = self.0.read().unwrap_or_else(PoisonError::into_inner);
+        if let Some(interned) = values.get(value) {
+            return Arc::clone(interned);
+        }
+        drop(values);
+        let mut values = self.0.write().unwrap_or_else(PoisonError::into_inner);
+        if let Some(interned) = values.get(value) {
+            return Arc::clone(interned);
+        }
+        let interned = Arc::<str>::from(value);
+        values.insert(Arc::clone(&interned));
+        interned
+    }
+}
+
+/// The label values of a request, with the status kept as a code until the
+/// values are needed. Optional labels are `None` unless they're enabled.
+struct RequestLabels<S> {
+    endpoint: S,
+    method: &'static str,
+    code: u16,
+    request_id: Option<bool>,
+    route_method: Option<&'static str>,
+    route_name: Option<S>,
+    query: Option<bool>,
+    host: Option<S>,
+    status_class: bool,
+}
+
+impl<S: AsRef<str>> RequestLabels<S> {
+    /// The label values in label order, given the request's `status`.
+    fn values<'a>(&'a self, status: &'a StatusCode) -> Vec<&'a str> {
+        let mut values = vec![self.endpoint.as_ref(), self.method, status.as_str()];
+        values.extend(self.request_id.map(bool_label));
+        values.extend(self.route_method);
+        values.extend(self.route_name.as_ref().map(|name| name.as_ref()));
+        values.extend(self.query.map(bool_label));
+        values.extend(self.host.as_ref().map(|host| host.as_ref()));
+        if self.status_class {
+            values.push(status.class());
+        }
+        values
+    }
+
+    /// Convert the string label values using `f`.
+    fn map<T>(&self, mut f: impl FnMut(&str) -> T) -> RequestLabels<T> {
+        RequestLabels {
+            endpoint: f(self.endpoint.as_ref()),
+            method: self.method,
+            code: self.code,
+            request_id: self.request_id,
+            route_method: self.route_method,
+            route_name: self.route_name.as_ref().map(|name| f(name.as_ref())),
+            query: self.query,
+            host: self.host.as_ref().map(|host| f(host.as_ref())),
+            status_class: self.status_class,
+        }
+    }
+}
+
 /// A status code which tries not to allocate to produce a `&str` representation.
 enum StatusCode {
     /// A 'standard' status code, i.e. between 100 and 999.
@@ -401,6 +1690,11 @@ impl StatusCode {
             Self::NonStandard(s) => s.as_str(),
         }
     }
+
+    /// The class of the status code, e.g. `2xx`.
+    fn class(&self) -> &'static str {
+        status_class(self.as_str())
+    }
 }
 
 impl From<u16> for StatusCode {
@@ -410,38 +1704,393 @@ impl From<u16> for StatusCode {
     }
 }
 
+/// Get the status class (e.g. `2xx`) of a status label value.
+fn status_class(status: &str) -> &'static str {
+    const CLASSES: [&str; 9] = [
+        "1xx", "2xx", "3xx", "4xx", "5xx", "6xx", "7xx", "8xx", "9xx",
+    ];
+    match status.as_bytes() {
+        [digit @ b'1'..=b'9', _, _] => CLASSES[usize::from(digit - b'1')],
+        _ => "unknown",
+    }
+}
+
+/// Get the label value for a boolean label.
+fn bool_label(value: bool) -> &'static str {
+    if value {
+        "true"
+    } else {
+        "false"
+    }
+}
+
+/// Returns `true` if a request using a method which supports a payload had a
+/// missing, malformed or unexpected `Content-Type` header.
+///
+/// Requests without a `Content-Type` are only considered bad if they
+/// don't explicitly declare an empty body with `Content-Length: 0`.
+fn has_bad_content_type(req: &Request<'_>, route: &Route) -> bool {
+    if !req.method().supports_payload() {
+        return false;
+    }
+    match req.content_type() {
+        None => req.headers().get_one("Content-Length") != Some("0"),
+        Some(content_type) => route
+            .format
+            .as_ref()
+            .is_some_and(|format| !media_type_matches(format, content_type.media_type())),
+    }
+}
+
+/// Log the timestamps captured for a request, relative to its start.
+fn trace_timings(
+    req: &Request<'_>,
+    endpoint: &str,
+    start: Option<Instant>,
+    duration: Option<Duration>,
+) {
+    let start = match start {
+        Some(start) => start,
+        None => {
+            log::trace!("{} {}: request start not recorded", req.method(), endpoint);
+            return;
+        }
+    };
+    let handler_start = req
+        .local_cache(|| HandlerStartTime(None))
+        .0
+        .map(|handler_start| handler_start.saturating_duration_since(start).as_nanos());
+    let excluded = req.local_cache(ExcludedDuration::default).get().as_nanos();
+    log::trace!(
+        "{} {}: request start +0ns, handler start {}, response +{}ns, excluded {}ns",
+        req.method(),
+        endpoint,
+        handler_start.map_or_else(|| "not marked".to_owned(), |ns| format!("+{}ns", ns)),
+        duration.unwrap_or_default().as_nanos(),
+        excluded,
+    );
+}
+
+/// Returns `true` if the request has an `If-Modified-Since` or
+/// `If-None-Match` header.
+fn is_conditional(req: &Request<'_>) -> bool {
+    let headers = req.headers();
+    headers.contains("If-Modified-Since") || headers.contains("If-None-Match")
+}
+
+/// Returns `true` if `media_type` matches the (possibly wildcard) `format`.
+fn media_type_matches(format: &MediaType, media_type: &MediaType) -> bool {
+    (format.top() == "*" || format.top() == media_type.top())
+        && (format.sub() == "*" || format.sub() == media_type.sub())
+}
+
 #[rocket::async_trait]
 impl Fairing for PrometheusMetrics {
     fn info(&self) -> Info {
         Info {
             name: "Prometheus metric collection",
-            kind: Kind::Request | Kind::Response,
+            kind: Kind::Liftoff | Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        if let Some(check) = &self.setup_check {
+            check.attached.store(true, Ordering::Relaxed);
+            let mounted = rocket
+                .routes()
+                .any(|route| route.name.as_deref() == Some(ROUTE_NAME));
+            if !mounted {
+                log::error!(
+                    "the PrometheusMetrics fairing is attached but its handler isn't mounted, \
+                     so metrics won't be served; mount it with e.g. \
+                     `.mount(\"/metrics\", prometheus)`"
+                );
+            }
+        }
+        // Initialize a zero-valued series for every route so that rates can be
+        // computed from the first request onwards. This isn't possible with a
+        // custom endpoint mapper, since its labels depend on the request.
+        if let (Some(counters), None) = (&self.status_code_counters, &self.endpoint_mapper) {
+            for route in rocket.routes() {
+                let endpoint = self.endpoint_label(route.uri.as_str());
+                for counter in counters.values() {
+                    counter.with_label_values(&[&endpoint, route.method.as_str()]);
+                }
+            }
+        }
+        if let Some(recorder) = &self.background_recorder {
+            // Intern the label values of every route up front, so that
+            // requests to them can be sent to the recorder without allocating.
+            for route in rocket.routes() {
+                if self.endpoint_mapper.is_none() {
+                    recorder
+                        .label_values
+                        .intern(&self.endpoint_label(route.uri.as_str()));
+                }
+                if self.route_name_label {
+                    recorder
+                        .label_values
+                        .intern(route.name.as_deref().unwrap_or_else(|| route.uri.as_str()));
+                }
+            }
+        }
+        if let Some(profile) = &self.profile {
+            // If the same instance is launched more than once, keep the
+            // first profile so that existing series aren't relabelled.
+            let _ = profile.set(rocket.figment().profile().to_string());
         }
     }
 
     async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
-        req.local_cache(|| TimerStart(Some(Instant::now())));
+        let now = Instant::now();
+        let start = if self.time_requests {
+            req.local_cache(|| TimerStart(Some(now))).0
+        } else {
+            None
+        };
+        if self.trace_fairing_order && log::log_enabled!(log::Level::Trace) {
+            req.local_cache(FairingTrace::default).record(format_args!(
+                "on_request saw {} {}{}",
+                req.method(),
+                req.uri(),
+                if start.is_some_and(|start| start != now) {
+                    " (start time already set by an earlier instance)"
+                } else {
+                    ""
+                }
+            ));
+        }
+        // Every request which is started here is finished in `on_response`,
+        // whether or not it matched a route.
+        self.http_requests_in_flight.inc();
+        if let (Some(concurrency), Some(start)) = (&self.http_requests_concurrency_max, start) {
+            concurrency.start(start);
+        }
     }
 
     async fn on_response<'r>(&self, req: &'r Request<'_>, response: &mut Response<'r>) {
-        // Don't touch metrics if the request didn't match a route.
-        if req.route().is_none() {
+        if self.trace_fairing_order && log::log_enabled!(log::Level::Trace) {
+            let trace = req.local_cache(FairingTrace::default);
+            trace.record(format_args!(
+                "on_response saw {} {} routed to {} with status {}",
+                req.method(),
+                req.uri(),
+                req.route().map_or("no route", |route| route.uri.as_str()),
+                response.status().code,
+            ));
+            trace.log();
+        }
+        self.http_requests_in_flight.dec();
+        // Recorded when dropped, for the endpoint if the request is tracked.
+        let mut finishing = self
+            .http_requests_concurrency_max
+            .as_ref()
+            .map(|concurrency| concurrency.finish(req.local_cache(|| TimerStart(None)).0));
+
+        if let Some(counter) = &self.http_requests_received_total {
+            counter.with_label_values(&[req.method().as_str()]).inc();
+        }
+
+        if self.ignored_methods.contains(&req.method()) {
             return;
         }
 
-        let endpoint = req.route().unwrap().uri.as_str();
-        let method = req.method().as_str();
-        let status = StatusCode::from(response.status().code);
-        self.http_requests_total
-            .with_label_values(&[endpoint, method, status.as_str()])
-            .inc();
+        // Don't touch metrics if the request didn't match a route, unless
+        // unmatched requests are tracked.
+        let route = req.route();
+        if route.is_none() {
+            if let Some(counter) = &self.http_aborted_requests_total {
+                let reason = match response.status().code {
+                    400 => "bad_request",
+                    404 => "no_route",
+                    _ => "other",
+                };
+                counter.with_label_values(&[reason]).inc();
+            }
+            if !self.track_unmatched && !self.catcher_endpoints {
+                return;
+            }
+        }
 
-        let start_time = req.local_cache(|| TimerStart(None));
-        if let Some(duration) = start_time.0.map(|st| st.elapsed()) {
-            let duration_secs = duration.as_secs_f64();
-            self.http_requests_duration_seconds
-                .with_label_values(&[endpoint, method, status.as_str()])
-                .observe(duration_secs);
+        if route.is_some_and(|route| {
+            self.ignored_routes.contains(route.uri.as_str())
+                || (self.exclude_metrics_route && route.name.as_deref() == Some(ROUTE_NAME))
+        }) {
+            return;
+        }
+        let caught = self.catcher_endpoints && (route.is_none() || catcher::is_caught(req));
+        let endpoint_label = match (route, &self.endpoint_mapper) {
+            _ if caught => Cow::Owned(format!("<catcher:{}>", response.status().code)),
+            (None, _) => Cow::Borrowed(UNMATCHED_ENDPOINT),
+            (Some(_), Some(map)) => Cow::Owned(map(req)),
+            (Some(route), None) => self.endpoint_label(route.uri.as_str()),
+        };
+        let endpoint = match &self.endpoint_rates {
+            Some(rates) if !rates.record(&endpoint_label) => OTHER_ENDPOINTS,
+            _ => &endpoint_label,
+        };
+        // The synthetic endpoints don't count towards the cap.
+        let endpoint = match &self.endpoint_cap {
+            Some(cap)
+                if endpoint != OTHER_ENDPOINTS
+                    && endpoint != UNMATCHED_ENDPOINT
+                    && !caught
+                    && !cap.admit(endpoint) =>
+            {
+                OVERFLOW_ENDPOINTS
+            }
+            _ => endpoint,
+        };
+        if let Some(finishing) = &mut finishing {
+            finishing.endpoint(endpoint);
+        }
+        let method = match (self.method_source, route) {
+            (MethodSource::Route, Some(route)) => route.method.as_str(),
+            _ => req.method().as_str(),
+        };
+        let code = response.status().code;
+        let code = self.status_mapper.as_ref().map_or(code, |map| map(code));
+        let status = StatusCode::from(code);
+        if let Some(counter) = self
+            .status_code_counters
+            .as_ref()
+            .and_then(|counters| counters.get(&code))
+        {
+            counter.with_label_values(&[endpoint, method]).inc();
+        }
+        if let Some(counter) = &self.http_requests_errors_total {
+            if code >= 500 {
+                counter.with_label_values(&[endpoint, method]).inc();
+            }
+        }
+        let start = req.local_cache(|| TimerStart(None)).0;
+        if let (Some(counter), None) = (&self.http_requests_missing_timer_total, start) {
+            counter.with_label_values(&[endpoint, method]).inc();
+        }
+
+        let request_labels = RequestLabels {
+            endpoint,
+            method,
+            code,
+            request_id: self
+                .request_id_header
+                .as_ref()
+                .map(|header| req.headers().contains(header.as_str())),
+            route_method: self
+                .route_method_label
+                .then(|| route.map_or("", |route| route.method.as_str())),
+            route_name: self.route_name_label.then(|| {
+                route.map_or("", |route| {
+                    route.name.as_deref().unwrap_or_else(|| route.uri.as_str())
+                })
+            }),
+            query: self.query_label.then(|| req.uri().query().is_some()),
+            host: self.host_allowlist.as_ref().map(|hosts| match req.host() {
+                None => NO_HOST,
+                Some(host) => hosts
+                    .get(host.domain().as_str().to_ascii_lowercase().as_str())
+                    .map_or(OTHER_HOSTS, String::as_str),
+            }),
+            status_class: self.status_class_label,
+        };
+        let labels = request_labels.values(&status);
+
+        if let (Some(counter), Some(route)) = (&self.http_bad_content_type_total, route) {
+            if has_bad_content_type(req, route) {
+                counter.with_label_values(&[endpoint, method]).inc();
+            }
+        }
+
+        if let Some(counter) = &self.http_conditional_requests_total {
+            let result = match response.status().code {
+                _ if !is_conditional(req) => None,
+                304 => Some("not_modified"),
+                200 => Some("full"),
+                _ => None,
+            };
+            if let Some(result) = result {
+                counter.with_label_values(&[endpoint, result]).inc();
+            }
+        }
+
+        if let Some(histogram) = &self.http_request_size_bytes {
+            let size = req
+                .headers()
+                .get_one("Content-Length")
+                .and_then(|length| length.parse::<u64>().ok());
+            if let Some(size) = size {
+                histogram.with_label_values(&labels).observe(size as f64);
+            }
+        }
+
+        if let Some(hourly) = &self.http_requests_by_hour_total {
+            let hour = HOURS[hourly.current_hour()];
+            hourly.counter.with_label_values(&[endpoint, hour]).inc();
+        }
+
+        if let Some(gauge) = &self.http_request_last_timestamp_seconds {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0.0, |since_epoch| since_epoch.as_secs_f64());
+            gauge.with_label_values(&[endpoint, method]).set(now);
+        }
+
+        if let Some(split) = &self.guard_handler_durations {
+            let handler_start = req.local_cache(|| HandlerStartTime(None)).0;
+            if let (Some(start), Some(handler_start)) = (start, handler_start) {
+                split
+                    .guards
+                    .with_label_values(&labels)
+                    .observe(handler_start.saturating_duration_since(start).as_secs_f64());
+                split
+                    .handler
+                    .with_label_values(&labels)
+                    .observe(handler_start.elapsed().as_secs_f64());
+            }
+        }
+
+        // Without a start time there's no duration to observe, and a made-up
+        // one would skew the duration metrics.
+        let duration = start.map(|start| start.elapsed());
+        if self.trace_timings && log::log_enabled!(log::Level::Trace) {
+            trace_timings(req, endpoint, start, duration);
+        }
+        if let (Some(histogram), Some(duration)) =
+            (&self.http_requests_controllable_duration_seconds, duration)
+        {
+            let excluded = req.local_cache(ExcludedDuration::default).get();
+            histogram
+                .with_label_values(&labels)
+                .observe(duration.saturating_sub(excluded).as_secs_f64());
+        }
+
+        // The request was counted by the missing timer counter instead, so
+        // that the request counter matches the observed durations.
+        if duration.is_none() && self.http_requests_missing_timer_total.is_some() {
+            return;
+        }
+
+        let trace_id = self
+            .exemplars
+            .as_ref()
+            .and_then(|exemplars| exemplars.trace_id(req));
+        match &self.background_recorder {
+            Some(recorder) => {
+                // The recorder thread only exits once every sender has been
+                // dropped, so this can't fail while we hold one.
+                let _ = recorder.sender.send(Observation {
+                    labels: request_labels.map(|value| recorder.label_values.intern(value)),
+                    duration,
+                    trace_id,
+                });
+            }
+            None => self.record(&labels, duration, trace_id),
+        }
+
+        if let Some(duration) = duration {
+            for callback in self.response_callbacks.iter() {
+                callback(req, duration, response.status());
+            }
         }
     }
 }
@@ -449,30 +2098,251 @@ impl Fairing for PrometheusMetrics {
 #[rocket::async_trait]
 impl Handler for PrometheusMetrics {
     async fn handle<'r>(&self, req: &'r Request<'_>, _: Data<'r>) -> Outcome<'r> {
-        // Gather the metrics.
-        let mut buffer = vec![];
-        let encoder = TextEncoder::new();
-        encoder
-            .encode(&self.custom_registry.gather(), &mut buffer)
-            .unwrap();
-        encoder
-            .encode(&self.rocket_registry.gather(), &mut buffer)
-            .unwrap();
-        let body = String::from_utf8(buffer).unwrap();
-        Outcome::from(
-            req,
-            (
-                ContentType::new("text", "plain")
-                    .with_params([("version", "0.0.4"), ("charset", "utf-8")]),
-                body,
+        if let Some(check) = &self.setup_check {
+            if !check.attached.load(Ordering::Relaxed)
+                && !check.reported.swap(true, Ordering::Relaxed)
+            {
+                log::error!(
+                    "the PrometheusMetrics handler is mounted but its fairing isn't attached, \
+                     so requests won't be recorded; attach it with e.g. \
+                     `.attach(prometheus.clone())`"
+                );
+            }
+        }
+
+        if let Some(allowed) = &self.allowed_ips {
+            let ip = match &self.proxy_header {
+                Some(proxy_header) => proxy_header.client_ip(req),
+                None => req.client_ip().map(|ip| ip.to_canonical()),
+            };
+            if !ip.is_some_and(|ip| allowed.contains(&ip)) {
+                return Outcome::Error(Status::Forbidden);
+            }
+        }
+        if let Some(auth) = &self.basic_auth {
+            if !auth.check(req.headers().get_one("Authorization")) {
+                return Outcome::from(req, Unauthorized::default());
+            }
+        }
+        if let Some(token) = &self.token {
+            let given = req.query_value::<&str>("token").and_then(Result::ok);
+            if !given.is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes())) {
+                return Outcome::Error(Status::Forbidden);
+            }
+        }
+
+        if self.serves_quantile_table {
+            return Outcome::from(req, self.quantile_table());
+        }
+
+        // A custom encoder is used whatever the scraper accepts.
+        let format = match &self.custom_encoder {
+            Some(_) => Format::Text,
+            None => Format::negotiate(req.headers().get_one("Accept")),
+        };
+        let content_type = match (&self.custom_encoder, format, &self.text_content_type) {
+            (Some(encoder), _, _) => encoder.content_type.clone(),
+            (None, Format::Text, Some(content_type)) => content_type.clone(),
+            _ => format.content_type(),
+        };
+
+        // Gathering and encoding can be slow for large registries, so they
+        // run on the blocking thread pool instead of stalling the executor.
+        let metrics = self.clone();
+        if self.stream_output && self.custom_encoder.is_none() {
+            let gathered = task::spawn_blocking(move || {
+                let families = metrics.gather_registries(metrics.served_registries);
+                metrics.check_series_budget(&families);
+                families
+            })
+            .await;
+            let families = match gathered {
+                Ok(families) => families,
+                Err(e) => {
+                    log::error!("failed to gather metrics: {}", e);
+                    return Outcome::Error(Status::InternalServerError);
+                }
+            };
+            let comment = self
+                .comment
+                .as_ref()
+                .filter(|_| format.supports_comments())
+                .map(|c| c.as_bytes().to_vec());
+            // Families are encoded lazily, as the response body is polled.
+            let target_info = self.target_info(format).map(<[u8]>::to_vec);
+            let trailer = format.trailer().map(<[u8]>::to_vec);
+            let exemplars = self.exemplars.clone();
+            let chunks =
+                comment
+                    .into_iter()
+                    .chain(families.into_iter().filter_map(move |family| {
+                        encode_family(format, exemplars.as_deref(), family)
+                    }))
+                    .chain(target_info)
+                    .chain(trailer);
+            return Outcome::from(req, (content_type, ByteStream(stream::iter(chunks))));
+        }
+
+        let buffer = task::spawn_blocking(move || {
+            let registries = metrics.served_registries;
+            let encode = || metrics.encode_metrics(registries, format);
+            match &metrics.output_cache {
+                Some(cache) => cache.get_or_encode((registries, format), encode),
+                None => encode(),
+            }
+        })
+        .await
+        .unwrap_or_else(|e| Err(prometheus::Error::Msg(e.to_string())));
+        let buffer = match buffer {
+            Ok(buffer) => buffer,
+            Err(e) => {
+                log::error!("failed to encode metrics: {}", e);
+                return Outcome::from(
+                    req,
+                    (
+                        Status::InternalServerError,
+                        format!("failed to encode metrics: {}", e),
+                    ),
+                );
+            }
+        };
+
+        let encoding = Encoding::negotiate(req.headers().get_one("Accept-Encoding"));
+        if let Some(content_encoding) = encoding.header_value() {
+            match encoding.encode(&buffer) {
+                Ok(body) => {
+                    let response = EncodedBody {
+                        body,
+                        content_type,
+                        content_encoding: Header::new("Content-Encoding", content_encoding),
+                    };
+                    return Outcome::from(req, response);
+                }
+                Err(e) => log::error!("failed to compress metrics, sending uncompressed: {}", e),
+            }
+        }
+
+        Outcome::from(req, (content_type, buffer))
+    }
+}
+
+/// A compressed metrics response body.
+#[derive(rocket::Responder)]
+struct EncodedBody {
+    body: Vec<u8>,
+    content_type: ContentType,
+    content_encoding: Header<'static>,
+}
+
+/// A response challenging the client to authenticate.
+#[derive(rocket::Responder)]
+#[response(status = 401)]
+struct Unauthorized {
+    body: &'static str,
+    challenge: Header<'static>,
+}
+
+impl Default for Unauthorized {
+    fn default() -> Self {
+        Self {
+            body: "authentication required",
+            challenge: Header::new(
+                "WWW-Authenticate",
+                r#"Basic realm="metrics", charset="UTF-8""#,
             ),
-        )
+        }
+    }
+}
+
+/// Compare two byte strings in time independent of their contents (though
+/// not of their lengths).
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Encode a single metric family for a streamed response.
+///
+/// The response status has already been sent by the time this runs, so
+/// errors can only be logged and the family skipped.
+fn encode_family(
+    format: Format,
+    exemplars: Option<&Exemplars>,
+    family: MetricFamily,
+) -> Option<Vec<u8>> {
+    let mut chunk = vec![];
+    match format.encode(std::slice::from_ref(&family), exemplars, &mut chunk) {
+        Ok(()) => Some(chunk),
+        Err(e) => {
+            log::error!(
+                "failed to encode metric family {}: {}",
+                family.get_name(),
+                e
+            );
+            None
+        }
     }
 }
 
+/// Mounting a [`PrometheusMetrics`] instance adds three routes relative to the
+/// mount point:
+///
+/// - `/` serves metrics from both the custom and internal registries;
+/// - `/rocket` serves only the built-in metrics from the internal registry;
+/// - `/custom` serves only metrics from the custom registry (see
+///   [`PrometheusMetrics::registry`]).
+///
+/// A `/summary` route serving a table of duration quantiles is also added if
+/// [`PrometheusMetricsBuilder::quantile_table_route`] is enabled.
+///
+/// Routes other than `/` are ranked below application routes, so that an
+/// instance mounted at e.g. `/` doesn't take over application routes such as
+/// `/custom`.
+///
+/// The routes only handle `GET` requests unless configured otherwise with
+/// [`PrometheusMetricsBuilder::handler_methods`]. Whenever they handle `GET`
+/// they also handle `HEAD`, responding with the same status and headers
+/// (including `Content-Type`) and no body, which suits scrapers that check
+/// liveness with `HEAD`.
 impl From<PrometheusMetrics> for Vec<Route> {
     fn from(other: PrometheusMetrics) -> Self {
-        vec![Route::new(Method::Get, "/", other)]
+        // Rocket strips the body of responses to `HEAD` requests. Its fallback
+        // to `GET` routes isn't relied on, since it only applies if no other
+        // `HEAD` route matches.
+        let mut methods = other.handler_methods.to_vec();
+        if methods.contains(&Method::Get) && !methods.contains(&Method::Head) {
+            methods.push(Method::Head);
+        }
+        let routes = |path: &'static str, handler: PrometheusMetrics| {
+            methods.iter().map(move |&method| {
+                let mut route = Route::new(method, path, handler.clone());
+                route.name = Some(ROUTE_NAME.into());
+                if path != "/" {
+                    route.rank = SUFFIX_ROUTE_RANK;
+                }
+                route
+            })
+        };
+        let summary = other.quantile_table_route.then(|| {
+            let mut handler = other.clone();
+            handler.serves_quantile_table = true;
+            routes("/summary", handler)
+        });
+        [
+            ("/", Registries::All),
+            ("/rocket", Registries::Rocket),
+            ("/custom", Registries::Custom),
+        ]
+        .iter()
+        .flat_map(|&(path, registries)| {
+            let mut handler = other.clone();
+            handler.served_registries = registries;
+            routes(path, handler)
+        })
+        .chain(summary.into_iter().flatten())
+        .collect()
     }
 }
 
@@ -485,4 +2355,313 @@ mod test {
         let _pm1 = PrometheusMetrics::with_default_registry();
         let _pm2 = PrometheusMetrics::with_default_registry();
     }
+
+    #[test]
+    fn test_endpoint_prefix_groups() {
+        let pm = PrometheusMetrics::builder()
+            .group_prefix("/api/orders/", "orders")
+            .group_prefix("/api/", "api")
+            .build()
+            .unwrap();
+        assert_eq!(pm.endpoint_label("/api/orders/<id>"), "orders");
+        assert_eq!(pm.endpoint_label("/api/users/<id>"), "api");
+        assert_eq!(pm.endpoint_label("/hello/<name>"), "/hello/<name>");
+    }
+
+    #[test]
+    fn test_builder_namespace_is_per_instance() {
+        let handles: Vec<_> = ["alpha", "beta", "gamma"]
+            .iter()
+            .copied()
+            .map(|namespace| {
+                std::thread::spawn(move || {
+                    let pm = PrometheusMetrics::builder()
+                        .namespace(namespace)
+                        .build()
+                        .unwrap();
+                    pm.http_requests_total()
+                        .with_label_values(&["/", "GET", "200"])
+                        .inc();
+                    let names: Vec<String> = pm
+                        .rocket_registry
+                        .gather()
+                        .iter()
+                        .map(|family| family.get_name().to_owned())
+                        .collect();
+                    assert_eq!(
+                        names,
+                        vec![
+                            format!("{}_http_requests_in_flight", namespace),
+                            format!("{}_http_requests_total", namespace),
+                        ]
+                    );
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_inc_counter() {
+        let pm = PrometheusMetrics::new();
+        pm.inc_counter("events_total", &[("kind", "a")]).unwrap();
+        pm.inc_counter("events_total", &[("kind", "a")]).unwrap();
+        pm.inc_counter("events_total", &[("kind", "b")]).unwrap();
+        assert!(pm.inc_counter("events_total", &[("other", "a")]).is_err());
+
+        let families = pm.registry().gather();
+        assert_eq!(families.len(), 1);
+        let values: Vec<f64> = families[0]
+            .get_metric()
+            .iter()
+            .map(|m| m.get_counter().get_value())
+            .collect();
+        assert_eq!(values, vec![2.0, 1.0]);
+    }
+
+    #[test]
+    fn test_endpoint_style() {
+        use super::EndpointStyle;
+
+        let uri = "/users/<id>/files/<path..>?<q>";
+        assert_eq!(EndpointStyle::Rocket.rewrite(uri), uri);
+        assert_eq!(
+            EndpointStyle::Colon.rewrite(uri),
+            "/users/:id/files/*path?:q"
+        );
+        assert_eq!(
+            EndpointStyle::Braces.rewrite(uri),
+            "/users/{id}/files/{*path}?{q}"
+        );
+    }
+
+    #[test]
+    fn test_buckets() {
+        let pm = PrometheusMetrics::builder()
+            .buckets(vec![0.001, 0.01])
+            .build()
+            .unwrap();
+        pm.http_requests_duration_seconds()
+            .with_label_values(&["/", "GET", "200"])
+            .observe(0.005);
+        assert_eq!(
+            pm.duration_buckets("/", "GET", "200"),
+            vec![(0.001, 0), (0.01, 1)]
+        );
+
+        for invalid in [vec![], vec![0.1, 0.01], vec![0.1, 0.1]] {
+            assert!(PrometheusMetrics::builder()
+                .buckets(invalid)
+                .build()
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_duration_buckets_status_class() {
+        let pm = PrometheusMetrics::builder()
+            .buckets(vec![0.001, 0.01])
+            .status_class_label(true)
+            .build()
+            .unwrap();
+        pm.http_requests_duration_seconds()
+            .with_label_values(&["/", "GET", "200", "2xx"])
+            .observe(0.005);
+        assert_eq!(
+            pm.duration_buckets("/", "GET", "200"),
+            vec![(0.001, 0), (0.01, 1)]
+        );
+        assert!(pm.duration_buckets("/", "GET", "404").is_empty());
+
+        let pm = PrometheusMetrics::builder()
+            .buckets(vec![0.001, 0.01])
+            .histogram_status_class(true)
+            .build()
+            .unwrap();
+        pm.http_requests_duration_seconds()
+            .with_label_values(&["/", "GET", "2xx"])
+            .observe(0.005);
+        assert_eq!(
+            pm.duration_buckets("/", "GET", "2xx"),
+            vec![(0.001, 0), (0.01, 1)]
+        );
+    }
+
+    #[test]
+    fn test_const_labels() {
+        for invalid in ["", "1region", "re-gion", "__region", "endpoint"] {
+            assert!(PrometheusMetrics::builder()
+                .const_labels([(invalid, "eu-west-1")])
+                .build()
+                .is_err());
+        }
+        assert!(PrometheusMetrics::builder()
+            .const_labels([("fairing", "public")])
+            .fairing_label("admin")
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_label_names() {
+        use prometheus::core::Collector;
+
+        let pm = PrometheusMetrics::builder()
+            .endpoint_label_name("path")
+            .method_label_name("http_method")
+            .status_label_name("code")
+            .build()
+            .unwrap();
+        pm.http_requests_duration_seconds()
+            .with_label_values(&["/", "GET", "200"])
+            .observe(0.005);
+        let family = &pm.http_requests_duration_seconds().collect()[0];
+        let names: Vec<&str> = family.get_metric()[0]
+            .get_label()
+            .iter()
+            .map(|label| label.get_name())
+            .collect();
+        assert_eq!(names, ["code", "http_method", "path"]);
+        assert_eq!(pm.duration_buckets("/", "GET", "200")[0], (0.005, 1));
+
+        for invalid in ["", "http-method", "__method", "endpoint"] {
+            assert!(PrometheusMetrics::builder()
+                .method_label_name(invalid)
+                .build()
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_duration_unit() {
+        use prometheus::core::Collector;
+
+        use super::DurationUnit;
+
+        let pm = PrometheusMetrics::builder()
+            .namespace("duration_unit")
+            .duration_unit(DurationUnit::Milliseconds)
+            .build()
+            .unwrap();
+        pm.http_requests_duration_seconds()
+            .with_label_values(&["/", "GET", "200"])
+            .observe(7.0);
+        let family = &pm.http_requests_duration_seconds().collect()[0];
+        assert_eq!(
+            family.get_name(),
+            "duration_unit_http_requests_duration_milliseconds"
+        );
+        assert_eq!(
+            &pm.duration_buckets("/", "GET", "200")[..2],
+            [(5.0, 0), (10.0, 1)]
+        );
+    }
+
+    #[test]
+    fn test_status_class() {
+        assert_eq!(super::status_class("200"), "2xx");
+        assert_eq!(super::status_class("404"), "4xx");
+        assert_eq!(super::status_class("42"), "unknown");
+    }
+
+    #[test]
+    fn test_register_callback_gauge() {
+        use std::sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        };
+
+        let pm = PrometheusMetrics::new();
+        let calls = Arc::new(AtomicU64::new(0));
+        let counted = Arc::clone(&calls);
+        pm.register_callback_gauge("sampled", "A sampled value", move || {
+            (counted.fetch_add(1, Ordering::Relaxed) + 1) as f64
+        })
+        .unwrap();
+        assert!(pm
+            .register_callback_gauge("sampled", "A sampled value", || 0.0)
+            .is_err());
+
+        for expected in [1.0, 2.0] {
+            let families = pm.registry().gather();
+            assert_eq!(
+                families[0].get_metric()[0].get_gauge().get_value(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_register_fn() {
+        use std::sync::{
+            atomic::{AtomicI64, Ordering},
+            Arc,
+        };
+
+        use prometheus::{core::Collector, IntGauge};
+
+        use super::Registries;
+
+        let pm = PrometheusMetrics::new();
+        let calls = Arc::new(AtomicI64::new(0));
+        let counted = Arc::clone(&calls);
+        pm.register_fn(move || {
+            let gauge = IntGauge::new("computed", "A computed value").unwrap();
+            gauge.set(counted.fetch_add(1, Ordering::Relaxed) + 1);
+            gauge.collect()
+        });
+        // Nothing is computed until metrics are gathered.
+        assert_eq!(calls.load(Ordering::Relaxed), 0);
+
+        for expected in [1, 2] {
+            let families = pm.gather_registries(Registries::Custom);
+            assert_eq!(families[0].get_name(), "computed");
+            assert_eq!(
+                families[0].get_metric()[0].get_gauge().get_value(),
+                f64::from(expected)
+            );
+        }
+    }
+
+    #[test]
+    fn test_process_metrics() {
+        let pm = PrometheusMetrics::builder()
+            .process_metrics(true)
+            .build()
+            .unwrap();
+        let text = pm.gather_text().unwrap();
+        let expected = cfg!(all(feature = "process", target_os = "linux"));
+        assert_eq!(text.contains("process_resident_memory_bytes"), expected);
+    }
+
+    #[test]
+    fn test_gather_sorted() {
+        use prometheus::IntCounter;
+
+        use super::Registries;
+
+        let pm = PrometheusMetrics::new();
+        let custom = IntCounter::new("zz_custom_total", "A custom counter").unwrap();
+        custom.inc();
+        pm.registry().register(Box::new(custom)).unwrap();
+        let added = IntCounter::new("aa_added_total", "An added counter").unwrap();
+        added.inc();
+        pm.add_collector(Box::new(added));
+
+        let names = || -> Vec<String> {
+            pm.gather_registries(Registries::All)
+                .iter()
+                .map(|family| family.get_name().to_owned())
+                .collect()
+        };
+        let first = names();
+        assert_eq!(first.first().map(String::as_str), Some("aa_added_total"));
+        assert_eq!(first.last().map(String::as_str), Some("zz_custom_total"));
+        let mut sorted = first.clone();
+        sorted.sort();
+        assert_eq!(first, sorted);
+        assert_eq!(first, names());
+    }
 }
@@ -0,0 +1,59 @@
+//! Pushing metrics to a Prometheus Pushgateway.
+//!
+//! This module is only available with the `pushgateway` feature enabled.
+
+use std::collections::HashMap;
+
+use crate::{PrometheusMetrics, Registries};
+
+impl PrometheusMetrics {
+    /// Push the metrics from both the custom and the internal registry to the
+    /// Pushgateway at `gateway_url`, grouped by `job` and the `grouping`
+    /// labels.
+    ///
+    /// Metrics are sent with a `POST` request to
+    /// `{gateway_url}/metrics/job/{job}/{label}/{value}...`, so they replace
+    /// pushed metrics with the same names in the group but leave others in
+    /// place. This is intended for short-lived processes, such as batch jobs,
+    /// which can't be scraped.
+    ///
+    /// This blocks the current thread until the request completes; use
+    /// [`PrometheusMetrics::push_async`] from async code.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or the gateway rejects the
+    /// metrics, or if a pushed metric already has a `job` or grouping label.
+    pub fn push(
+        &self,
+        gateway_url: &str,
+        job: &str,
+        grouping: HashMap<String, String>,
+    ) -> Result<(), prometheus::Error> {
+        let families = self.gather_registries(Registries::All);
+        prometheus::push_add_metrics(job, grouping, gateway_url, families, None)
+    }
+
+    /// Push metrics to a Pushgateway without blocking the async runtime.
+    ///
+    /// The request is made on Rocket's blocking thread pool; see
+    /// [`PrometheusMetrics::push`] for details.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as [`PrometheusMetrics::push`], or an error if
+    /// the blocking task panicked.
+    pub async fn push_async(
+        &self,
+        gateway_url: &str,
+        job: &str,
+        grouping: HashMap<String, String>,
+    ) -> Result<(), prometheus::Error> {
+        let metrics = self.clone();
+        let gateway_url = gateway_url.to_owned();
+        let job = job.to_owned();
+        rocket::tokio::task::spawn_blocking(move || metrics.push(&gateway_url, &job, grouping))
+            .await
+            .map_err(|e| prometheus::Error::Msg(format!("failed to push metrics: {}", e)))?
+    }
+}
@@ -0,0 +1,112 @@
+//! Per-endpoint request tracking, used to limit the cardinality of the
+//! `endpoint` label.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// Tracks how many requests each endpoint received in fixed time windows,
+/// to decide whether it is busy enough to be recorded under its own name.
+#[derive(Clone)]
+pub(crate) struct EndpointRates {
+    window: Duration,
+    min_requests: u64,
+    counts: Arc<Mutex<HashMap<String, WindowCounts>>>,
+}
+
+/// Request counts for the current and previous windows of one endpoint.
+struct WindowCounts {
+    start: Instant,
+    current: u64,
+    previous: u64,
+}
+
+impl WindowCounts {
+    /// Move the current window forward to contain `now`.
+    fn advance(&mut self, now: Instant, window: Duration) {
+        let elapsed = now.saturating_duration_since(self.start);
+        if elapsed >= window * 2 {
+            // No requests at all in the previous window.
+            self.previous = 0;
+            self.current = 0;
+            self.start = now;
+        } else if elapsed >= window {
+            self.previous = self.current;
+            self.current = 0;
+            self.start += window;
+        }
+    }
+}
+
+impl EndpointRates {
+    pub(crate) fn new(min_requests: u64, window: Duration) -> Self {
+        Self {
+            window,
+            min_requests,
+            counts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Count a request to `endpoint`, returning `true` if the endpoint has
+    /// received at least the minimum number of requests in either the
+    /// current or the previous window.
+    pub(crate) fn record(&self, endpoint: &str) -> bool {
+        let now = Instant::now();
+        let mut counts = self.counts.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(counts) = counts.get_mut(endpoint) {
+            counts.advance(now, self.window);
+            counts.current += 1;
+            return counts.current.max(counts.previous) >= self.min_requests;
+        }
+        counts.insert(
+            endpoint.to_owned(),
+            WindowCounts {
+                start: now,
+                current: 1,
+                previous: 0,
+            },
+        );
+        self.min_requests <= 1
+    }
+}
+
+/// Tracks the distinct endpoints seen so far, to cap how many are recorded
+/// under their own name.
+#[derive(Clone)]
+pub(crate) struct EndpointCap {
+    max_endpoints: usize,
+    seen: Arc<Mutex<HashSet<String>>>,
+}
+
+impl EndpointCap {
+    pub(crate) fn new(max_endpoints: usize) -> Self {
+        Self {
+            max_endpoints,
+            seen: Arc::default(),
+        }
+    }
+
+    /// Returns `true` if `endpoint` has been seen before, or can be added
+    /// without exceeding the maximum number of endpoints.
+    pub(crate) fn admit(&self, endpoint: &str) -> bool {
+        let mut seen = self.seen.lock().unwrap_or_else(PoisonError::into_inner);
+        if seen.contains(endpoint) {
+            return true;
+        }
+        if seen.len() >= self.max_endpoints {
+            return false;
+        }
+        seen.insert(endpoint.to_owned());
+        true
+    }
+
+    /// Forget all endpoints seen so far.
+    pub(crate) fn reset(&self) {
+        self.seen
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+}
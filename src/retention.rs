@@ -0,0 +1,98 @@
+//! Removal of series which haven't been observed recently.
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// Last-seen tracking for the series of the request counter and the
+/// duration histogram, whose labels may differ.
+pub(crate) struct RetainedSeries {
+    pub(crate) counter: SeriesRetention,
+    pub(crate) histogram: SeriesRetention,
+}
+
+impl RetainedSeries {
+    pub(crate) fn new(retention: Duration) -> Self {
+        Self {
+            counter: SeriesRetention::new(retention),
+            histogram: SeriesRetention::new(retention),
+        }
+    }
+}
+
+/// When each series of a metric vec was last observed, used to find the
+/// series which have expired.
+pub(crate) struct SeriesRetention {
+    retention: Duration,
+    last_seen: Mutex<HashMap<Vec<String>, Instant>>,
+}
+
+impl SeriesRetention {
+    pub(crate) fn new(retention: Duration) -> Self {
+        Self {
+            retention,
+            last_seen: Mutex::default(),
+        }
+    }
+
+    /// Mark the series with the given label values as observed at `now`.
+    pub(crate) fn touch(&self, labels: &[&str], now: Instant) {
+        let key = labels.iter().map(|&label| label.to_owned()).collect();
+        self.last_seen
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(key, now);
+    }
+
+    /// Forget and return the label values of all series which haven't been
+    /// observed within the retention period before `now`.
+    pub(crate) fn expire(&self, now: Instant) -> Vec<Vec<String>> {
+        let mut expired = vec![];
+        self.last_seen
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|labels, &mut last_seen| {
+                let keep = now.saturating_duration_since(last_seen) < self.retention;
+                if !keep {
+                    expired.push(labels.clone());
+                }
+                keep
+            });
+        expired
+    }
+
+    /// Forget all series.
+    pub(crate) fn reset(&self) {
+        self.last_seen
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::SeriesRetention;
+
+    #[test]
+    fn test_expire() {
+        let retention = SeriesRetention::new(Duration::from_secs(10));
+        let start = Instant::now();
+        retention.touch(&["/a", "GET", "200"], start);
+        retention.touch(&["/b", "GET", "200"], start);
+        retention.touch(&["/a", "GET", "200"], start + Duration::from_secs(5));
+
+        assert!(retention.expire(start + Duration::from_secs(9)).is_empty());
+        assert_eq!(
+            retention.expire(start + Duration::from_secs(10)),
+            vec![vec!["/b".to_owned(), "GET".to_owned(), "200".to_owned()]]
+        );
+        // Expired series are forgotten until they're observed again.
+        assert!(retention.expire(start + Duration::from_secs(11)).is_empty());
+        assert_eq!(retention.expire(start + Duration::from_secs(15)).len(), 1);
+    }
+}
@@ -0,0 +1,94 @@
+//! In-process latency quantiles over a rolling window of recent requests.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    GaugeVec,
+};
+
+/// The quantiles exposed, matching the order of `RollingQuantiles::gauges`.
+pub(crate) const QUANTILES: [f64; 3] = [0.5, 0.95, 0.99];
+
+/// Collector exposing request duration quantiles per endpoint, computed at
+/// scrape time from the most recent observations.
+#[derive(Clone)]
+pub(crate) struct RollingQuantiles {
+    window: usize,
+    samples: Arc<Mutex<HashMap<String, VecDeque<f64>>>>,
+    gauges: [GaugeVec; 3],
+}
+
+impl RollingQuantiles {
+    /// Create a new collector keeping the last `window` observations for each
+    /// endpoint, exposing them using one gauge per entry in [`QUANTILES`].
+    pub(crate) fn new(window: usize, gauges: [GaugeVec; 3]) -> Self {
+        Self {
+            window,
+            samples: Arc::new(Mutex::new(HashMap::new())),
+            gauges,
+        }
+    }
+
+    /// Record an observation for an endpoint, evicting the oldest observation
+    /// if the window is full.
+    pub(crate) fn observe(&self, endpoint: &str, value: f64) {
+        let mut samples = self.samples.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(window) = samples.get_mut(endpoint) {
+            if window.len() >= self.window {
+                window.pop_front();
+            }
+            window.push_back(value);
+            return;
+        }
+        let mut window = VecDeque::with_capacity(self.window);
+        window.push_back(value);
+        samples.insert(endpoint.to_owned(), window);
+    }
+
+    /// Forget all observations and endpoints.
+    pub(crate) fn reset(&self) {
+        self.samples
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+        for gauge in &self.gauges {
+            gauge.reset();
+        }
+    }
+}
+
+impl Collector for RollingQuantiles {
+    fn desc(&self) -> Vec<&Desc> {
+        self.gauges.iter().flat_map(|gauge| gauge.desc()).collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        {
+            let samples = self.samples.lock().unwrap_or_else(PoisonError::into_inner);
+            for (endpoint, window) in samples.iter() {
+                let mut sorted: Vec<f64> = window.iter().copied().collect();
+                sorted.sort_by(f64::total_cmp);
+                for (gauge, q) in self.gauges.iter().zip(QUANTILES.iter()) {
+                    gauge
+                        .with_label_values(&[endpoint.as_str()])
+                        .set(nearest_rank(&sorted, *q));
+                }
+            }
+        }
+        self.gauges
+            .iter()
+            .flat_map(|gauge| gauge.collect())
+            .collect()
+    }
+}
+
+/// Get the `q` quantile of a non-empty, sorted slice using the nearest-rank method.
+pub(crate) fn nearest_rank(sorted: &[f64], q: f64) -> f64 {
+    let rank = (q * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
@@ -0,0 +1,133 @@
+//! A summary of request durations, as an alternative to the histogram.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use prometheus::{
+    core::{Collector, Desc},
+    proto::{LabelPair, Metric, MetricFamily, MetricType, Quantile, Summary},
+};
+
+use crate::rolling::nearest_rank;
+
+/// The observations of a single series of a [`DurationSummary`].
+struct Series {
+    // The most recent observations, used to compute quantiles.
+    window: VecDeque<f64>,
+    // The number and sum of all observations.
+    count: u64,
+    sum: f64,
+}
+
+/// Collector exposing a summary of request durations with quantiles
+/// computed at scrape time over the most recent observations of each series.
+#[derive(Clone)]
+pub(crate) struct DurationSummary {
+    desc: Arc<Desc>,
+    quantiles: Arc<[f64]>,
+    window: usize,
+    series: Arc<Mutex<HashMap<Vec<String>, Series>>>,
+}
+
+impl DurationSummary {
+    /// Create a new summary exposing the given quantiles over the last
+    /// `window` observations of each series.
+    pub(crate) fn new(
+        desc: Desc,
+        quantiles: Vec<f64>,
+        window: usize,
+    ) -> Result<Self, prometheus::Error> {
+        if window == 0 {
+            return Err(prometheus::Error::Msg(
+                "summary window must not be empty".into(),
+            ));
+        }
+        if let Some(q) = quantiles.iter().find(|q| !(0.0..=1.0).contains(*q)) {
+            return Err(prometheus::Error::Msg(format!(
+                "summary quantiles must be between 0 and 1: {}",
+                q
+            )));
+        }
+        Ok(Self {
+            desc: Arc::new(desc),
+            quantiles: quantiles.into(),
+            window,
+            series: Arc::default(),
+        })
+    }
+
+    /// Record an observation for the series with the given label values.
+    pub(crate) fn observe(&self, labels: &[&str], value: f64) {
+        let mut series = self.series.lock().unwrap_or_else(PoisonError::into_inner);
+        let key: Vec<String> = labels.iter().map(|&label| label.to_owned()).collect();
+        let series = series.entry(key).or_insert_with(|| Series {
+            window: VecDeque::with_capacity(self.window),
+            count: 0,
+            sum: 0.0,
+        });
+        if series.window.len() >= self.window {
+            series.window.pop_front();
+        }
+        series.window.push_back(value);
+        series.count += 1;
+        series.sum += value;
+    }
+
+    /// Remove all series.
+    pub(crate) fn reset(&self) {
+        self.series
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+}
+
+impl Collector for DurationSummary {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let series = self.series.lock().unwrap_or_else(PoisonError::into_inner);
+        if series.is_empty() {
+            return vec![];
+        }
+        let mut family = MetricFamily::default();
+        family.set_name(self.desc.fq_name.clone());
+        family.set_help(self.desc.help.clone());
+        family.set_field_type(MetricType::SUMMARY);
+        // Sort the series so that the output is stable between scrapes.
+        let mut series: Vec<_> = series.iter().collect();
+        series.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (values, observations) in series {
+            let mut labels: Vec<LabelPair> = self.desc.const_label_pairs.clone();
+            for (name, value) in self.desc.variable_labels.iter().zip(values) {
+                let mut pair = LabelPair::default();
+                pair.set_name(name.clone());
+                pair.set_value(value.clone());
+                labels.push(pair);
+            }
+            labels.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+
+            let mut sorted: Vec<f64> = observations.window.iter().copied().collect();
+            sorted.sort_by(f64::total_cmp);
+            let mut summary = Summary::default();
+            summary.set_sample_count(observations.count);
+            summary.set_sample_sum(observations.sum);
+            for &q in self.quantiles.iter() {
+                let mut quantile = Quantile::default();
+                quantile.set_quantile(q);
+                quantile.set_value(nearest_rank(&sorted, q));
+                summary.mut_quantile().push(quantile);
+            }
+
+            let mut metric = Metric::default();
+            metric.set_label(labels.into());
+            metric.set_summary(summary);
+            family.mut_metric().push(metric);
+        }
+        vec![family]
+    }
+}
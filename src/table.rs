@@ -0,0 +1,229 @@
+//! A human-readable table of request duration quantiles per endpoint.
+
+use std::{collections::BTreeMap, fmt::Write};
+
+use prometheus::{core::Collector, proto::Histogram};
+
+use crate::{rolling::QUANTILES, PrometheusMetrics};
+
+/// Cumulative bucket counts of all series of an endpoint, in the order of the
+/// histogram's buckets.
+///
+/// Series with custom buckets (e.g. per method) may not share all bucket
+/// bounds, so only the bounds common to all series are kept, as when
+/// summing buckets in a query.
+#[derive(Default)]
+struct EndpointBuckets {
+    upper_bounds: Vec<f64>,
+    cumulative_counts: Vec<u64>,
+    count: u64,
+    series: usize,
+}
+
+impl EndpointBuckets {
+    /// Add the buckets of a series of the endpoint.
+    fn add(&mut self, histogram: &Histogram) {
+        let buckets = histogram.get_bucket();
+        if self.series == 0 {
+            self.upper_bounds = buckets.iter().map(|b| b.get_upper_bound()).collect();
+            self.cumulative_counts = vec![0; self.upper_bounds.len()];
+        }
+        let (upper_bounds, cumulative_counts) = self
+            .upper_bounds
+            .iter()
+            .zip(&self.cumulative_counts)
+            .filter_map(|(&bound, &total)| {
+                let bucket = buckets.iter().find(|b| b.get_upper_bound() == bound)?;
+                Some((bound, total + bucket.get_cumulative_count()))
+            })
+            .unzip();
+        self.upper_bounds = upper_bounds;
+        self.cumulative_counts = cumulative_counts;
+        self.count += histogram.get_sample_count();
+        self.series += 1;
+    }
+}
+
+impl PrometheusMetrics {
+    /// Render the quantiles in [`QUANTILES`] of the request duration
+    /// histogram for each endpoint, estimated from its buckets.
+    pub(crate) fn quantile_table(&self) -> String {
+        let mut endpoints: BTreeMap<String, EndpointBuckets> = BTreeMap::new();
+        let families = self.duration_histograms.collect();
+        let name = families
+            .first()
+            .map_or("http_requests_duration_seconds", |family| family.get_name());
+        for metric in families.iter().flat_map(|family| family.get_metric()) {
+            let endpoint = metric
+                .get_label()
+                .iter()
+                .find(|label| label.get_name() == self.label_names.endpoint)
+                .map_or("", |label| label.get_value());
+            endpoints
+                .entry(endpoint.to_owned())
+                .or_default()
+                .add(metric.get_histogram());
+        }
+
+        let width = endpoints
+            .keys()
+            .map(|endpoint| endpoint.chars().count())
+            .chain(Some("endpoint".len()))
+            .max()
+            .unwrap_or_default();
+        let mut table = format!("# Estimated from the buckets of {}\n", name);
+        let _ = write!(
+            table,
+            "{:<width$} {:>10}",
+            "endpoint",
+            "count",
+            width = width
+        );
+        for q in QUANTILES {
+            let _ = write!(table, " {:>10}", format!("p{}", (q * 100.0).round()));
+        }
+        table.push('\n');
+        for (endpoint, buckets) in &endpoints {
+            let _ = write!(
+                table,
+                "{:<width$} {:>10}",
+                endpoint,
+                buckets.count,
+                width = width
+            );
+            for q in QUANTILES {
+                match bucket_quantile(
+                    q,
+                    &buckets.upper_bounds,
+                    &buckets.cumulative_counts,
+                    buckets.count,
+                ) {
+                    Some(value) => {
+                        let _ = write!(table, " {:>10.4}", value);
+                    }
+                    None => {
+                        let _ = write!(table, " {:>10}", "-");
+                    }
+                }
+            }
+            table.push('\n');
+        }
+        table
+    }
+}
+
+/// Estimate a quantile from cumulative bucket counts, interpolating linearly
+/// within the bucket containing it as Prometheus' `histogram_quantile` does.
+///
+/// The `+Inf` bucket is implicit, with a cumulative count of `count`.
+/// Quantiles falling in it are estimated as the largest finite upper bound.
+/// Returns `None` if there are no observations.
+fn bucket_quantile(
+    q: f64,
+    upper_bounds: &[f64],
+    cumulative_counts: &[u64],
+    count: u64,
+) -> Option<f64> {
+    if count == 0 {
+        return None;
+    }
+    let rank = q * count as f64;
+    let mut lower_bound = 0.0;
+    let mut lower_count = 0;
+    for (&upper_bound, &cumulative_count) in upper_bounds.iter().zip(cumulative_counts) {
+        if cumulative_count as f64 >= rank {
+            if upper_bound <= 0.0 {
+                return Some(upper_bound);
+            }
+            let in_bucket = (cumulative_count - lower_count) as f64;
+            return Some(
+                lower_bound + (upper_bound - lower_bound) * (rank - lower_count as f64) / in_bucket,
+            );
+        }
+        lower_bound = upper_bound;
+        lower_count = cumulative_count;
+    }
+    upper_bounds.last().copied()
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::Method;
+
+    use super::bucket_quantile;
+    use crate::PrometheusMetrics;
+
+    #[test]
+    fn test_bucket_quantile() {
+        let bounds = [0.1, 0.5, 1.0];
+        // 10 observations: 4 up to 0.1, 4 more up to 0.5, 1 more up to 1 and 1 above.
+        let counts = [4, 8, 9];
+        assert_eq!(bucket_quantile(0.2, &bounds, &counts, 10), Some(0.05));
+        assert_eq!(bucket_quantile(0.8, &bounds, &counts, 10), Some(0.5));
+        assert_eq!(bucket_quantile(0.99, &bounds, &counts, 10), Some(1.0));
+        assert_eq!(bucket_quantile(0.5, &bounds, &[0, 0, 0], 0), None);
+    }
+
+    #[test]
+    fn test_quantile_table() {
+        let pm = PrometheusMetrics::builder()
+            .buckets(vec![0.1, 1.0])
+            .build()
+            .unwrap();
+        let histogram = pm.http_requests_duration_seconds();
+        histogram
+            .with_label_values(&["/a", "GET", "200"])
+            .observe(0.05);
+        histogram
+            .with_label_values(&["/a", "POST", "200"])
+            .observe(0.05);
+        histogram
+            .with_label_values(&["/bb", "GET", "200"])
+            .observe(0.5);
+
+        let table = pm.quantile_table();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(
+            lines[0],
+            "# Estimated from the buckets of rocket_http_requests_duration_seconds"
+        );
+        assert_eq!(
+            lines[1],
+            "endpoint      count        p50        p95        p99"
+        );
+        assert_eq!(
+            lines[2],
+            "/a                2     0.0500     0.0950     0.0990"
+        );
+        assert_eq!(
+            lines[3],
+            "/bb               1     0.5500     0.9550     0.9910"
+        );
+    }
+
+    #[test]
+    fn test_quantile_table_custom_buckets() {
+        let pm = PrometheusMetrics::builder()
+            .buckets(vec![0.1, 0.5, 1.0])
+            .method_buckets(Method::Post, vec![0.1, 1.0, 5.0])
+            .endpoint_buckets([("/slow", vec![1.0, 10.0])])
+            .build()
+            .unwrap();
+        pm.duration_histograms.observe(&["/a", "GET", "200"], 0.05);
+        pm.duration_histograms.observe(&["/a", "POST", "200"], 0.05);
+        pm.duration_histograms
+            .observe(&["/slow", "GET", "200"], 5.0);
+
+        let table = pm.quantile_table();
+        let lines: Vec<&str> = table.lines().collect();
+        // Only the bounds shared by both methods are used for /a.
+        assert_eq!(
+            lines[2],
+            "/a                2     0.0500     0.0950     0.0990"
+        );
+        assert_eq!(
+            lines[3],
+            "/slow             1     5.5000     9.5500     9.9100"
+        );
+    }
+}
@@ -0,0 +1,252 @@
+//! Helpers for asserting on metrics in tests.
+//!
+//! This module is only available with the `test-util` feature enabled.
+//!
+//! ```rust
+//! use rocket::{get, local::blocking::Client, routes};
+//! use rocket_prometheus::PrometheusMetrics;
+//!
+//! #[get("/")]
+//! fn index() -> &'static str {
+//!     "Hello, world!"
+//! }
+//!
+//! let prometheus = PrometheusMetrics::new();
+//! let rocket = rocket::build()
+//!     .attach(prometheus.clone())
+//!     .mount("/", routes![index]);
+//! let client = Client::untracked(rocket).unwrap();
+//!
+//! let before = prometheus.snapshot();
+//! client.get("/").dispatch();
+//! let diff = before.diff(&prometheus.snapshot());
+//!
+//! let change = diff
+//!     .get(r#"rocket_http_requests_total{endpoint="/",method="GET",status="200"}"#)
+//!     .unwrap();
+//! assert_eq!(change.delta(), 1.0);
+//! ```
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+use crate::PrometheusMetrics;
+
+/// Remove the `_sum` lines of histograms and summaries from metrics in the
+/// Prometheus text exposition format.
+///
+/// Duration sums depend on how long requests took, so this makes the output
+/// stable enough to compare against a golden file. Bucket counts can still
+/// vary if requests are slow enough to cross a bucket boundary; to inspect
+/// metrics programmatically instead, see [`PrometheusMetrics::gather`].
+///
+/// ```rust
+/// use rocket_prometheus::testing::strip_histogram_sums;
+///
+/// let text = "# TYPE latency histogram\nlatency_sum 0.123\nlatency_count 1\n";
+/// assert_eq!(
+///     strip_histogram_sums(text),
+///     "# TYPE latency histogram\nlatency_count 1\n"
+/// );
+/// ```
+pub fn strip_histogram_sums(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut sum_prefix = None;
+    for line in text.lines() {
+        if let Some(meta) = line.strip_prefix("# TYPE ") {
+            sum_prefix = sum_name(meta);
+        } else if let Some(prefix) = &sum_prefix {
+            let name = line.split(['{', ' ']).next().unwrap_or(line);
+            if name == prefix {
+                continue;
+            }
+        }
+        stripped.push_str(line);
+        stripped.push('\n');
+    }
+    stripped
+}
+
+/// The name of the `_sum` series of a family, given its `TYPE` metadata, if
+/// it's a histogram or summary.
+fn sum_name(meta: &str) -> Option<String> {
+    let mut parts = meta.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some(name), Some("histogram" | "summary")) => Some(format!("{}_sum", name)),
+        _ => None,
+    }
+}
+
+impl PrometheusMetrics {
+    /// Take a snapshot of the current value of every series exposed by this
+    /// instance, from both the custom and the internal registry.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the metrics can't be encoded, see
+    /// [`PrometheusMetrics::gather_text`].
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let text = self.gather_text().expect("failed to encode metrics");
+        MetricsSnapshot::from_text(&text)
+    }
+}
+
+/// The values of a set of series at a point in time.
+///
+/// Series are identified by their name and labels exactly as they appear in
+/// the text exposition format, e.g.
+/// `rocket_http_requests_total{endpoint="/",method="GET",status="200"}`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MetricsSnapshot {
+    series: BTreeMap<String, f64>,
+    // Series holding the `_sum` of a histogram or summary.
+    sums: BTreeSet<String>,
+}
+
+impl MetricsSnapshot {
+    /// Parse a snapshot from metrics in the Prometheus text exposition format.
+    ///
+    /// Lines which can't be parsed are ignored.
+    pub fn from_text(text: &str) -> Self {
+        let mut snapshot = Self::default();
+        let mut sum_prefix = None;
+        for line in text.lines() {
+            if let Some(meta) = line.strip_prefix("# TYPE ") {
+                sum_prefix = sum_name(meta);
+                continue;
+            }
+            if line.starts_with('#') {
+                continue;
+            }
+            let (series, value) = match line.rsplit_once(' ') {
+                Some((series, value)) => (series, value),
+                None => continue,
+            };
+            let value = match value.parse() {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            if let Some(prefix) = &sum_prefix {
+                let name = series.split('{').next().unwrap_or(series);
+                if name == prefix {
+                    snapshot.sums.insert(series.to_owned());
+                }
+            }
+            snapshot.series.insert(series.to_owned(), value);
+        }
+        snapshot
+    }
+
+    /// Get the value of a series, if present.
+    pub fn get(&self, series: &str) -> Option<f64> {
+        self.series.get(series).copied()
+    }
+
+    /// Iterate over all series and their values, ordered by series.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, f64)> {
+        self.series
+            .iter()
+            .map(|(series, value)| (series.as_str(), *value))
+    }
+
+    /// Compare this snapshot with a later one, returning the series which
+    /// changed.
+    ///
+    /// The `_sum` series of histograms and summaries are ignored, since for
+    /// request durations they depend on timing. Use
+    /// [`MetricsSnapshot::diff_with_sums`] to include them.
+    pub fn diff(&self, after: &Self) -> MetricsDiff {
+        self.diff_inner(after, false)
+    }
+
+    /// Compare this snapshot with a later one, returning the series which
+    /// changed, including the `_sum` series of histograms and summaries.
+    pub fn diff_with_sums(&self, after: &Self) -> MetricsDiff {
+        self.diff_inner(after, true)
+    }
+
+    fn diff_inner(&self, after: &Self, include_sums: bool) -> MetricsDiff {
+        let mut changes = BTreeMap::new();
+        let names: BTreeSet<&String> = self.series.keys().chain(after.series.keys()).collect();
+        for series in names {
+            if !include_sums && (self.sums.contains(series) || after.sums.contains(series)) {
+                continue;
+            }
+            let change = Change {
+                before: self.series.get(series).copied(),
+                after: after.series.get(series).copied(),
+            };
+            if change.before != change.after {
+                changes.insert(series.clone(), change);
+            }
+        }
+        MetricsDiff { changes }
+    }
+}
+
+/// The change in value of a single series between two snapshots.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Change {
+    /// The value in the earlier snapshot, or `None` if the series didn't exist.
+    pub before: Option<f64>,
+    /// The value in the later snapshot, or `None` if the series didn't exist.
+    pub after: Option<f64>,
+}
+
+impl Change {
+    /// The difference between the two values, treating missing series as zero.
+    pub fn delta(&self) -> f64 {
+        self.after.unwrap_or_default() - self.before.unwrap_or_default()
+    }
+}
+
+/// The series which changed between two [`MetricsSnapshot`]s.
+///
+/// The [`Display`](fmt::Display) implementation lists one change per line,
+/// which makes for readable assertion failures.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MetricsDiff {
+    changes: BTreeMap<String, Change>,
+}
+
+impl MetricsDiff {
+    /// Returns `true` if no series changed.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// The number of series which changed.
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    /// Get the change to a series, if it changed.
+    pub fn get(&self, series: &str) -> Option<&Change> {
+        self.changes.get(series)
+    }
+
+    /// Iterate over the changed series, ordered by series.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Change)> {
+        self.changes
+            .iter()
+            .map(|(series, change)| (series.as_str(), change))
+    }
+}
+
+impl fmt::Display for MetricsDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (series, change) in &self.changes {
+            let show = |value: Option<f64>| value.map_or_else(|| "-".to_owned(), |v| v.to_string());
+            writeln!(
+                f,
+                "{}: {} -> {}",
+                series,
+                show(change.before),
+                show(change.after)
+            )?;
+        }
+        Ok(())
+    }
+}
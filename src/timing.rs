@@ -0,0 +1,171 @@
+//! Helpers for splitting request durations into finer-grained parts.
+
+use std::{
+    convert::Infallible,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use rocket::{
+    request::{FromRequest, Outcome},
+    Request,
+};
+
+use crate::TimerStart;
+
+/// The time at which the request started, as captured by the
+/// [`PrometheusMetrics`] fairing.
+///
+/// This is when the fairing's `on_request` callback ran, which is the start
+/// of the span measured by `http_requests_duration_seconds`. It's `None` if
+/// no attached instance times requests (for example if all duration metrics
+/// are disabled), or if `on_request` didn't run for this request. Other
+/// fairings and handlers can use it to measure durations of their own from
+/// the same starting point.
+///
+/// ```rust
+/// use rocket::Request;
+/// use rocket_prometheus::request_start;
+///
+/// fn log_elapsed(req: &Request<'_>) {
+///     if let Some(start) = request_start(req) {
+///         println!("{} has taken {:?} so far", req.uri(), start.elapsed());
+///     }
+/// }
+/// ```
+///
+/// [`PrometheusMetrics`]: crate::PrometheusMetrics
+pub fn request_start(req: &Request<'_>) -> Option<Instant> {
+    req.local_cache(|| TimerStart(None)).0
+}
+
+/// The time at which request guards finished for the request, if it was
+/// marked with [`mark_handler_start`] or the [`HandlerStart`] guard.
+pub fn handler_start(req: &Request<'_>) -> Option<Instant> {
+    req.local_cache(|| HandlerStartTime(None)).0
+}
+
+/// Value stored in request-local state marking when request guards finished.
+#[derive(Copy, Clone)]
+pub(crate) struct HandlerStartTime(pub(crate) Option<Instant>);
+
+/// Mark the point at which request guards have finished and the handler is
+/// about to run.
+///
+/// This is used to split the request duration into guard time and handler
+/// time when [`PrometheusMetricsBuilder::guard_handler_durations`] is
+/// enabled. Only the first call for a request has any effect.
+///
+/// Most applications should use the [`HandlerStart`] request guard instead,
+/// which calls this when it is evaluated.
+///
+/// [`PrometheusMetricsBuilder::guard_handler_durations`]: crate::PrometheusMetricsBuilder::guard_handler_durations
+pub fn mark_handler_start(req: &Request<'_>) {
+    req.local_cache(|| HandlerStartTime(Some(Instant::now())));
+}
+
+/// Request guard marking the point at which guards have finished and the
+/// handler is about to run.
+///
+/// Rocket evaluates request guards in the order they appear in the
+/// handler's arguments, so this should be the _last_ request guard. Note
+/// that data guards (e.g. `Json<T>`) always run after request guards, so
+/// time spent reading and parsing the body is counted as handler time.
+///
+/// ```rust
+/// use rocket::get;
+/// use rocket_prometheus::HandlerStart;
+///
+/// # struct User;
+/// # #[rocket::async_trait]
+/// # impl<'r> rocket::request::FromRequest<'r> for User {
+/// #     type Error = ();
+/// #     async fn from_request(_: &'r rocket::Request<'_>) -> rocket::request::Outcome<Self, ()> {
+/// #         rocket::request::Outcome::Success(User)
+/// #     }
+/// # }
+/// #[get("/profile")]
+/// fn profile(user: User, _start: HandlerStart) -> &'static str {
+///     "Hello!"
+/// }
+/// ```
+#[derive(Copy, Clone, Debug)]
+pub struct HandlerStart;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for HandlerStart {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        mark_handler_start(req);
+        Outcome::Success(HandlerStart)
+    }
+}
+
+/// Value stored in request-local state accumulating time to exclude from the
+/// controllable request duration.
+#[derive(Default)]
+pub(crate) struct ExcludedDuration(Mutex<Duration>);
+
+impl ExcludedDuration {
+    fn add(&self, duration: Duration) {
+        let mut total = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        *total = total.saturating_add(duration);
+    }
+
+    pub(crate) fn get(&self) -> Duration {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Exclude `duration` from the controllable duration of the request.
+///
+/// This is used when [`PrometheusMetricsBuilder::controllable_durations`] is
+/// enabled, to leave out time spent waiting on dependencies outside the
+/// application's control. Calls for the same request accumulate.
+///
+/// Handlers can use the [`ExcludedTime`] request guard instead.
+///
+/// [`PrometheusMetricsBuilder::controllable_durations`]: crate::PrometheusMetricsBuilder::controllable_durations
+pub fn exclude_duration(req: &Request<'_>, duration: Duration) {
+    req.local_cache(ExcludedDuration::default).add(duration);
+}
+
+/// Request guard for excluding time from the controllable duration of the
+/// request.
+///
+/// ```rust
+/// use std::time::Instant;
+///
+/// use rocket::get;
+/// use rocket_prometheus::ExcludedTime;
+///
+/// # fn call_slow_dependency() {}
+/// #[get("/report")]
+/// fn report(excluded: ExcludedTime<'_>) -> &'static str {
+///     let start = Instant::now();
+///     call_slow_dependency();
+///     excluded.add(start.elapsed());
+///     "Done!"
+/// }
+/// ```
+#[derive(Copy, Clone)]
+pub struct ExcludedTime<'r>(&'r ExcludedDuration);
+
+impl ExcludedTime<'_> {
+    /// Exclude `duration` from the controllable duration of the request.
+    ///
+    /// See [`exclude_duration`].
+    pub fn add(&self, duration: Duration) {
+        self.0.add(duration);
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ExcludedTime<'r> {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(ExcludedTime(req.local_cache(ExcludedDuration::default)))
+    }
+}
@@ -0,0 +1,141 @@
+//! Request counts over a sliding window of recent time.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    IntGaugeVec,
+};
+
+/// The number of slots each window is divided into, which determines how
+/// smoothly requests leave the window.
+const SLOTS: usize = 10;
+
+/// Request counts of one series in consecutive slots, forming a ring.
+struct Ring {
+    slots: [u64; SLOTS],
+    // Index of the slot containing the current time.
+    head: usize,
+    head_start: Instant,
+}
+
+impl Ring {
+    fn new(now: Instant) -> Self {
+        Self {
+            slots: [0; SLOTS],
+            head: 0,
+            head_start: now,
+        }
+    }
+
+    /// Move the head forward to the slot containing `now`, clearing the
+    /// slots which have left the window.
+    fn advance(&mut self, now: Instant, slot_len: Duration) {
+        let elapsed = now.saturating_duration_since(self.head_start).as_nanos();
+        let slot_nanos = slot_len.as_nanos().max(1);
+        let steps = elapsed / slot_nanos;
+        if steps == 0 {
+            return;
+        }
+        if steps >= SLOTS as u128 {
+            self.slots = [0; SLOTS];
+        } else {
+            for _ in 0..steps {
+                self.head = (self.head + 1) % SLOTS;
+                self.slots[self.head] = 0;
+            }
+        }
+        self.head_start = now - Duration::from_nanos((elapsed % slot_nanos) as u64);
+    }
+}
+
+/// Collector exposing the number of requests in each series over a sliding
+/// window, computed at scrape time.
+#[derive(Clone)]
+pub(crate) struct RequestWindow {
+    slot_len: Duration,
+    rings: Arc<Mutex<HashMap<Vec<String>, Ring>>>,
+    gauge: IntGaugeVec,
+}
+
+impl RequestWindow {
+    pub(crate) fn new(window: Duration, gauge: IntGaugeVec) -> Self {
+        Self {
+            slot_len: window / SLOTS as u32,
+            rings: Arc::default(),
+            gauge,
+        }
+    }
+
+    /// Count a request in the series with the given label values.
+    pub(crate) fn record(&self, labels: &[&str]) {
+        let now = Instant::now();
+        let mut rings = self.rings.lock().unwrap_or_else(PoisonError::into_inner);
+        let key: Vec<String> = labels.iter().map(|&label| label.to_owned()).collect();
+        let ring = rings.entry(key).or_insert_with(|| Ring::new(now));
+        ring.advance(now, self.slot_len);
+        ring.slots[ring.head] += 1;
+    }
+
+    /// Forget all requests and series.
+    pub(crate) fn reset(&self) {
+        self.rings
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+        self.gauge.reset();
+    }
+}
+
+impl Collector for RequestWindow {
+    fn desc(&self) -> Vec<&Desc> {
+        self.gauge.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let now = Instant::now();
+        {
+            let mut rings = self.rings.lock().unwrap_or_else(PoisonError::into_inner);
+            for (key, ring) in rings.iter_mut() {
+                ring.advance(now, self.slot_len);
+                let labels: Vec<&str> = key.iter().map(String::as_str).collect();
+                let count: u64 = ring.slots.iter().sum();
+                self.gauge
+                    .with_label_values(&labels)
+                    .set(i64::try_from(count).unwrap_or(i64::MAX));
+            }
+        }
+        self.gauge.collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{Ring, SLOTS};
+
+    #[test]
+    fn test_ring() {
+        let slot_len = Duration::from_secs(1);
+        let start = Instant::now();
+        let mut ring = Ring::new(start);
+        ring.slots[ring.head] += 2;
+
+        ring.advance(start + Duration::from_millis(1500), slot_len);
+        ring.slots[ring.head] += 1;
+        assert_eq!(ring.slots.iter().sum::<u64>(), 3);
+
+        // The first slot leaves the window once it's a full window old.
+        ring.advance(start + slot_len * SLOTS as u32, slot_len);
+        assert_eq!(ring.slots.iter().sum::<u64>(), 1);
+
+        ring.advance(start + slot_len * 100, slot_len);
+        assert_eq!(ring.slots.iter().sum::<u64>(), 0);
+    }
+}
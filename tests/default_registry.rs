@@ -109,6 +109,9 @@ rocket_http_requests_duration_seconds_bucket{endpoint="/hello/<name>?<caps>",met
 rocket_http_requests_duration_seconds_bucket{endpoint="/hello/<name>?<caps>",method="POST",status="200",le="10"} 1
 rocket_http_requests_duration_seconds_bucket{endpoint="/hello/<name>?<caps>",method="POST",status="200",le="+Inf"} 1
 rocket_http_requests_duration_seconds_count{endpoint="/hello/<name>?<caps>",method="POST",status="200"} 1
+# HELP rocket_http_requests_in_flight Number of HTTP requests currently being handled
+# TYPE rocket_http_requests_in_flight gauge
+rocket_http_requests_in_flight 1
 # HELP rocket_http_requests_total Total number of HTTP requests
 # TYPE rocket_http_requests_total counter
 rocket_http_requests_total{endpoint="/hello/<name>?<caps>",method="GET",status="200"} 3
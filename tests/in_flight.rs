@@ -0,0 +1,57 @@
+use std::sync::Arc;
+
+use rocket::{
+    futures::{future::join_all, join},
+    get,
+    local::asynchronous::Client,
+    routes,
+    tokio::sync::Barrier,
+    State,
+};
+use rocket_prometheus::PrometheusMetrics;
+
+const CONCURRENT_REQUESTS: usize = 3;
+
+/// Barriers used to hold requests in flight until the test has checked the gauge.
+struct Barriers {
+    entered: Barrier,
+    release: Barrier,
+}
+
+#[get("/slow")]
+async fn slow(barriers: &State<Arc<Barriers>>) -> &'static str {
+    barriers.entered.wait().await;
+    barriers.release.wait().await;
+    "done"
+}
+
+#[rocket::async_test]
+async fn test_in_flight_returns_to_zero() {
+    let barriers = Arc::new(Barriers {
+        entered: Barrier::new(CONCURRENT_REQUESTS + 1),
+        release: Barrier::new(CONCURRENT_REQUESTS + 1),
+    });
+    let prometheus = PrometheusMetrics::new();
+    let rocket = rocket::build()
+        .attach(prometheus.clone())
+        .manage(Arc::clone(&barriers))
+        .mount("/", routes![slow])
+        .mount("/metrics", prometheus.clone());
+    let client = Client::untracked(rocket).await.unwrap();
+
+    let requests = join_all((0..CONCURRENT_REQUESTS).map(|_| client.get("/slow").dispatch()));
+    let check = async {
+        barriers.entered.wait().await;
+        assert_eq!(
+            prometheus.http_requests_in_flight().get(),
+            CONCURRENT_REQUESTS as i64
+        );
+        barriers.release.wait().await;
+    };
+    join!(requests, check);
+    assert_eq!(prometheus.http_requests_in_flight().get(), 0);
+
+    // Requests which don't match a route are also finished.
+    client.get("/missing").dispatch().await;
+    assert_eq!(prometheus.http_requests_in_flight().get(), 0);
+}
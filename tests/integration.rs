@@ -1,19 +1,59 @@
 #[macro_use]
 extern crate rocket;
 
+use std::time::{Duration, Instant, SystemTime};
+
 use once_cell::sync::Lazy;
-use prometheus::{opts, IntCounterVec};
-use rocket::{http::ContentType, local::blocking::Client};
-use rocket_prometheus::PrometheusMetrics;
+use prometheus::{
+    core::{Collector, Desc},
+    opts,
+    proto::{Metric, MetricFamily},
+    Encoder, IntCounter, IntCounterVec,
+};
+use rocket::{
+    http::{ContentType, Header, Method, Status},
+    local::blocking::Client,
+};
+use rocket_prometheus::{MethodSource, PrometheusConfig, PrometheusMetrics};
 use serde_json::json;
 
+/// Encoder writing the name and number of series of each metric family.
+struct SeriesCountEncoder;
+
+impl Encoder for SeriesCountEncoder {
+    fn encode<W: std::io::Write>(
+        &self,
+        families: &[MetricFamily],
+        writer: &mut W,
+    ) -> prometheus::Result<()> {
+        for family in families {
+            writeln!(
+                writer,
+                "{} {}",
+                family.get_name(),
+                family.get_metric().len()
+            )?;
+        }
+        Ok(())
+    }
+
+    fn format_type(&self) -> &str {
+        "text/x-series-count"
+    }
+}
+
 static NAME_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| {
     IntCounterVec::new(opts!("name_counter", "Count of names"), &["name"])
         .expect("Could not create lazy IntCounterVec")
 });
 
 mod routes {
-    use rocket::serde::json::Json;
+    use rocket::{
+        http::Status,
+        request::{FromRequest, Outcome},
+        serde::json::Json,
+        Request,
+    };
     use serde::Deserialize;
 
     use super::NAME_COUNTER;
@@ -41,6 +81,38 @@ mod routes {
             .unwrap_or_else(|| name.to_string());
         format!("Hello, {} year old named {}!", person.age, name)
     }
+
+    #[get("/fail")]
+    pub fn fail() -> Status {
+        Status::InternalServerError
+    }
+
+    #[get("/custom")]
+    pub fn custom() -> &'static str {
+        "custom"
+    }
+
+    pub struct Limited;
+
+    #[rocket::async_trait]
+    impl<'r> FromRequest<'r> for Limited {
+        type Error = ();
+
+        async fn from_request(_req: &'r Request<'_>) -> Outcome<Self, ()> {
+            Outcome::Error((Status::TooManyRequests, ()))
+        }
+    }
+
+    #[get("/limited")]
+    pub fn limited(_limited: Limited) -> &'static str {
+        "unreachable"
+    }
+
+    #[catch(429)]
+    pub fn too_many_requests(req: &Request<'_>) -> &'static str {
+        rocket_prometheus::mark_caught(req);
+        "Slow down!"
+    }
 }
 
 #[cfg(test)]
@@ -71,14 +143,8 @@ mod test {
         assert_eq!(
             response
                 .lines()
-                .enumerate()
-                .filter_map(|(i, line)|
                 // Skip out the 'sum' lines since they depend on request duration.
-                if i != 18 && i != 32 {
-                    Some(line)
-                } else {
-                    None
-                })
+                .filter(|line| !line.starts_with("rocket_http_requests_duration_seconds_sum"))
                 .collect::<Vec<&str>>()
                 .join("\n"),
             r#"# HELP name_counter Count of names
@@ -113,10 +179,1421 @@ rocket_http_requests_duration_seconds_bucket{endpoint="/hello/<name>?<caps>",met
 rocket_http_requests_duration_seconds_bucket{endpoint="/hello/<name>?<caps>",method="POST",status="200",le="10"} 1
 rocket_http_requests_duration_seconds_bucket{endpoint="/hello/<name>?<caps>",method="POST",status="200",le="+Inf"} 1
 rocket_http_requests_duration_seconds_count{endpoint="/hello/<name>?<caps>",method="POST",status="200"} 1
+# HELP rocket_http_requests_in_flight Number of HTTP requests currently being handled
+# TYPE rocket_http_requests_in_flight gauge
+rocket_http_requests_in_flight 1
 # HELP rocket_http_requests_total Total number of HTTP requests
 # TYPE rocket_http_requests_total counter
 rocket_http_requests_total{endpoint="/hello/<name>?<caps>",method="GET",status="200"} 3
 rocket_http_requests_total{endpoint="/hello/<name>?<caps>",method="POST",status="200"} 1"#
         );
     }
+
+    #[test]
+    fn test_split_registries() {
+        let prometheus = PrometheusMetrics::new();
+        let counter = IntCounter::new("split_counter", "A counter").unwrap();
+        counter.inc();
+        prometheus.registry().register(Box::new(counter)).unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let custom = client.get("/metrics/custom").dispatch();
+        let custom = custom.into_string().unwrap();
+        assert!(custom.contains("split_counter"));
+        assert!(!custom.contains("rocket_http_requests_total"));
+
+        let internal = client.get("/metrics/rocket").dispatch();
+        let internal = internal.into_string().unwrap();
+        assert!(!internal.contains("split_counter"));
+        assert!(internal.contains(r#"rocket_http_requests_total{endpoint="/metrics/custom""#));
+
+        // Application routes take precedence over the split routes.
+        let rocket = rocket::build()
+            .mount("/", routes![routes::custom])
+            .mount("/", PrometheusMetrics::new());
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        let custom = client.get("/custom").dispatch().into_string().unwrap();
+        assert_eq!(custom, "custom");
+    }
+
+    #[test]
+    fn test_request_size() {
+        let prometheus = PrometheusMetrics::builder()
+            .request_size_histogram(true)
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/", routes![routes::hello_post])
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        let body = serde_json::to_string(&json!({"age": 50})).unwrap();
+        client
+            .post("/hello/bar")
+            .header(ContentType::JSON)
+            .header(Header::new("Content-Length", body.len().to_string()))
+            .body(body)
+            .dispatch();
+        let metrics = client.get("/metrics").dispatch();
+        let response = metrics.into_string().unwrap();
+        assert!(response.contains(
+            r#"rocket_http_request_size_bytes_sum{endpoint="/hello/<name>?<caps>",method="POST",status="200"} 10"#
+        ));
+        // The scrape itself has no body, so it isn't observed.
+        assert!(!response.contains(r#"rocket_http_request_size_bytes_count{endpoint="/metrics""#));
+    }
+
+    #[test]
+    fn test_ignore_routes() {
+        let prometheus = PrometheusMetrics::builder()
+            .ignore_routes(["/metrics"])
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/", routes![routes::hello_post])
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client
+            .post("/hello/bar")
+            .header(ContentType::JSON)
+            .body(serde_json::to_string(&json!({"age": 50})).unwrap())
+            .dispatch();
+        client.get("/metrics").dispatch();
+        let metrics = client.get("/metrics").dispatch();
+        let response = metrics.into_string().unwrap();
+        assert!(response.contains(
+            r#"rocket_http_requests_total{endpoint="/hello/<name>?<caps>",method="POST",status="200"} 1"#
+        ));
+        assert!(!response.contains(r#"endpoint="/metrics""#));
+    }
+
+    #[test]
+    fn test_openmetrics() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("openmetrics")
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client.get("/metrics").dispatch();
+        let metrics = client
+            .get("/metrics")
+            .header(Header::new(
+                "Accept",
+                "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5",
+            ))
+            .dispatch();
+        assert_eq!(
+            metrics.content_type().unwrap().media_type().sub(),
+            "openmetrics-text"
+        );
+        let response = metrics.into_string().unwrap();
+        assert!(response.contains("# TYPE openmetrics_http_requests counter\n"));
+        assert!(response.contains(
+            r#"openmetrics_http_requests_total{endpoint="/metrics",method="GET",status="200"} 1"#
+        ));
+        assert!(response.ends_with("# EOF\n"));
+    }
+
+    #[test]
+    fn test_target_info() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("resource_attributes")
+            .target_info([("service_name", "my-service"), ("service_version", "1.2.3")])
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        let response = client
+            .get("/metrics")
+            .header(Header::new("Accept", "application/openmetrics-text"))
+            .dispatch()
+            .into_string()
+            .unwrap();
+        assert!(response.ends_with(
+            "# TYPE target info\n# HELP target Target metadata\ntarget_info{service_name=\"my-service\",service_version=\"1.2.3\"} 1\n# EOF\n"
+        ));
+
+        // Only OpenMetrics defines `target_info`.
+        let text = client.get("/metrics").dispatch().into_string().unwrap();
+        assert!(!text.contains("target_info"));
+
+        let invalid = PrometheusMetrics::builder()
+            .target_info([("service.name", "my-service")])
+            .build();
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn test_basic_auth() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("basic_auth")
+            .basic_auth("prometheus", "secret")
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let response = client.get("/metrics").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(
+            response.headers().get_one("WWW-Authenticate"),
+            Some(r#"Basic realm="metrics", charset="UTF-8""#)
+        );
+
+        // "prometheus:wrong"
+        let response = client
+            .get("/metrics")
+            .header(Header::new(
+                "Authorization",
+                "Basic cHJvbWV0aGV1czp3cm9uZw==",
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        // "prometheus:secret"
+        let response = client
+            .get("/metrics")
+            .header(Header::new(
+                "Authorization",
+                "Basic cHJvbWV0aGV1czpzZWNyZXQ=",
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn test_allow_ips() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("allow_ips")
+            .allow_ips(["10.0.0.1".parse().unwrap()])
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let response = client
+            .get("/metrics")
+            .header(Header::new("X-Real-IP", "10.0.0.1"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = client
+            .get("/metrics")
+            .header(Header::new("X-Real-IP", "10.0.0.2"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+
+        let response = client
+            .get("/metrics")
+            .remote("10.0.0.3:8000".parse().unwrap())
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn test_client_ip_header() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("client_ip_header")
+            .client_ip_header("X-Forwarded-For", ["10.0.0.1".parse().unwrap()])
+            .allow_ips(["203.0.113.7".parse().unwrap()])
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let response = client
+            .get("/metrics")
+            .remote("10.0.0.1:8000".parse().unwrap())
+            .header(Header::new("X-Forwarded-For", "198.51.100.1, 203.0.113.7"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        // The header is only trusted from a trusted proxy.
+        let response = client
+            .get("/metrics")
+            .remote("10.0.0.2:8000".parse().unwrap())
+            .header(Header::new("X-Forwarded-For", "203.0.113.7"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = client
+            .get("/metrics")
+            .remote("203.0.113.7:8000".parse().unwrap())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        // Rocket's `ip_header` is ignored.
+        let response = client
+            .get("/metrics")
+            .remote("10.0.0.2:8000".parse().unwrap())
+            .header(Header::new("X-Real-IP", "203.0.113.7"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+
+        // Addresses added before the client's can't be trusted.
+        let response = client
+            .get("/metrics")
+            .remote("10.0.0.1:8000".parse().unwrap())
+            .header(Header::new("X-Forwarded-For", "203.0.113.7, 198.51.100.1"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn test_build_info() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("build_info")
+            .build_info("4bf92f3", "2024-05-01")
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        let metrics = client.get("/metrics").dispatch();
+        let response = metrics.into_string().unwrap();
+        assert!(response.contains(&format!(
+            r#"rocket_prometheus_build_info{{build_date="2024-05-01",git_sha="4bf92f3",version="{}"}} 1"#,
+            env!("CARGO_PKG_VERSION")
+        )));
+    }
+
+    #[test]
+    fn test_endpoint_label() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("endpoint_label")
+            .endpoint_label(|req| format!("{}:custom", req.uri().path()))
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/", routes![routes::hello_post])
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client
+            .post("/hello/foo?caps=true")
+            .header(ContentType::JSON)
+            .body(serde_json::to_string(&json!({"age": 50})).unwrap())
+            .dispatch();
+        let metrics = client.get("/metrics").dispatch();
+        let response = metrics.into_string().unwrap();
+        assert!(response.contains(
+            r#"endpoint_label_http_requests_total{endpoint="/hello/foo:custom",method="POST",status="200"} 1"#
+        ));
+    }
+
+    #[test]
+    fn test_track_unmatched() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("track_unmatched")
+            .track_unmatched(true)
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client.get("/wp-login.php").dispatch();
+        client.delete("/metrics").dispatch();
+        let metrics = client.get("/metrics").dispatch();
+        let response = metrics.into_string().unwrap();
+        assert!(response.contains(
+            r#"track_unmatched_http_requests_total{endpoint="<unmatched>",method="GET",status="404"} 1"#
+        ));
+        assert!(response.contains(
+            r#"track_unmatched_http_requests_total{endpoint="<unmatched>",method="DELETE",status="404"} 1"#
+        ));
+    }
+
+    #[test]
+    fn test_catcher_endpoints() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("catcher_endpoints")
+            .catcher_endpoints(true)
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/", routes![routes::fail, routes::limited])
+            .register("/", catchers![routes::too_many_requests])
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client.get("/wp-login.php").dispatch();
+        client.get("/limited").dispatch();
+        client.get("/fail").dispatch();
+        let metrics = client.get("/metrics").dispatch();
+        let response = metrics.into_string().unwrap();
+        assert!(response.contains(
+            r#"catcher_endpoints_http_requests_total{endpoint="<catcher:404>",method="GET",status="404"} 1"#
+        ));
+        assert!(response.contains(
+            r#"catcher_endpoints_http_requests_total{endpoint="<catcher:429>",method="GET",status="429"} 1"#
+        ));
+        // Catchers which don't mark their responses are attributed to the route.
+        assert!(response.contains(
+            r#"catcher_endpoints_http_requests_total{endpoint="/fail",method="GET",status="500"} 1"#
+        ));
+        assert!(!response.contains(r#"endpoint="/limited""#));
+    }
+
+    #[test]
+    fn test_series_retention() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("series_retention")
+            .series_retention(Duration::from_millis(200))
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/", routes![routes::hello])
+            .mount("/metrics", prometheus.clone());
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client.get("/hello/foo").dispatch();
+        let response = client.get("/metrics").dispatch().into_string().unwrap();
+        assert!(response.contains(
+            r#"series_retention_http_requests_total{endpoint="/hello/<name>?<caps>",method="GET",status="200"} 1"#
+        ));
+
+        std::thread::sleep(Duration::from_millis(300));
+        let response = client.get("/metrics").dispatch().into_string().unwrap();
+        assert!(!response.contains(r#"endpoint="/hello/<name>?<caps>""#));
+
+        // The series was removed, so it restarts from zero.
+        client.get("/hello/foo").dispatch();
+        let response = client.get("/metrics").dispatch().into_string().unwrap();
+        assert!(response.contains(
+            r#"series_retention_http_requests_total{endpoint="/hello/<name>?<caps>",method="GET",status="200"} 1"#
+        ));
+    }
+
+    #[test]
+    fn test_const_labels() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("const_labels")
+            .const_labels([("region", "eu-west-1")])
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client.get("/metrics").dispatch();
+        let metrics = client.get("/metrics").dispatch();
+        let response = metrics.into_string().unwrap();
+        assert!(response.contains(
+            r#"const_labels_http_requests_total{endpoint="/metrics",method="GET",region="eu-west-1",status="200"} 1"#
+        ));
+        assert!(response.contains(r#"const_labels_http_requests_in_flight{region="eu-west-1"} 1"#));
+    }
+
+    #[test]
+    fn test_profile_label_clash() {
+        let const_label = PrometheusMetrics::builder()
+            .profile_label(true)
+            .const_labels([("profile", "debug")])
+            .build();
+        assert!(const_label.is_err());
+        let label_name = PrometheusMetrics::builder()
+            .profile_label(true)
+            .endpoint_label_name("profile")
+            .build();
+        assert!(label_name.is_err());
+    }
+
+    #[test]
+    fn test_disable_request_counter() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("no_counter")
+            .enable_request_counter(false)
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client.get("/metrics").dispatch();
+        let metrics = client.get("/metrics").dispatch();
+        let response = metrics.into_string().unwrap();
+        assert!(!response.contains("no_counter_http_requests_total"));
+        assert!(response.contains(
+            r#"no_counter_http_requests_duration_seconds_count{endpoint="/metrics",method="GET",status="200"} 1"#
+        ));
+    }
+
+    #[test]
+    fn test_disable_duration_histogram() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("no_histogram")
+            .enable_duration_histogram(false)
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client.get("/metrics").dispatch();
+        let metrics = client.get("/metrics").dispatch();
+        let response = metrics.into_string().unwrap();
+        assert!(!response.contains("no_histogram_http_requests_duration_seconds"));
+        assert!(response.contains(
+            r#"no_histogram_http_requests_total{endpoint="/metrics",method="GET",status="200"} 1"#
+        ));
+    }
+
+    #[test]
+    fn test_last_request_timestamp() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("last_request")
+            .last_request_timestamp(true)
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client.get("/metrics").dispatch();
+        let metrics = client.get("/metrics").dispatch();
+        let response = metrics.into_string().unwrap();
+        let series = r#"last_request_http_request_last_timestamp_seconds{endpoint="/metrics",method="GET"} "#;
+        let value: f64 = response
+            .lines()
+            .find_map(|line| line.strip_prefix(series))
+            .expect("timestamp series")
+            .parse()
+            .unwrap();
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+        assert!(
+            (now - value).abs() < 1.0,
+            "{} is not within 1s of {}",
+            value,
+            now
+        );
+    }
+
+    #[test]
+    fn test_cache_ttl() {
+        let counter = IntCounter::new("cached_counter", "A cached counter").unwrap();
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("cache_ttl")
+            .cache_ttl(Duration::from_secs(3600))
+            .build()
+            .unwrap();
+        prometheus
+            .registry()
+            .register(Box::new(counter.clone()))
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let first = client.get("/metrics").dispatch().into_string().unwrap();
+        assert!(first.contains("cached_counter 0"));
+        counter.inc();
+        let second = client.get("/metrics").dispatch().into_string().unwrap();
+        assert_eq!(first, second);
+
+        // Other formats are cached separately.
+        let openmetrics = client
+            .get("/metrics")
+            .header(Header::new("Accept", "application/openmetrics-text"))
+            .dispatch()
+            .into_string()
+            .unwrap();
+        assert!(openmetrics.contains("cached_counter_total 1"));
+    }
+
+    #[test]
+    fn test_errors_counter() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("errors")
+            .errors_counter(true)
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/", routes![routes::fail])
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client.get("/fail").dispatch();
+        client.get("/fail").dispatch();
+        let metrics = client.get("/metrics").dispatch();
+        let response = metrics.into_string().unwrap();
+        assert!(response
+            .contains(r#"errors_http_requests_errors_total{endpoint="/fail",method="GET"} 2"#));
+        assert!(!response.contains(r#"errors_http_requests_errors_total{endpoint="/metrics""#));
+    }
+
+    #[test]
+    fn test_from_config() {
+        let figment = rocket::Config::figment()
+            .merge(("prometheus.namespace", "from_config"))
+            .merge(("prometheus.ignore_routes", ["/metrics"]));
+        let config: PrometheusConfig = figment.extract_inner("prometheus").unwrap();
+        let prometheus = PrometheusMetrics::from_config(config).unwrap();
+        let rocket = rocket::custom(figment)
+            .attach(prometheus.clone())
+            .mount("/", routes![routes::fail])
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client.get("/fail").dispatch();
+        client.get("/metrics").dispatch();
+        let metrics = client.get("/metrics").dispatch();
+        let response = metrics.into_string().unwrap();
+        assert!(response.contains(
+            r#"from_config_http_requests_total{endpoint="/fail",method="GET",status="500"} 1"#
+        ));
+        assert!(!response.contains(r#"endpoint="/metrics""#));
+
+        let defaults: PrometheusConfig = rocket::Config::figment()
+            .extract_inner("prometheus")
+            .unwrap_or_default();
+        assert_eq!(defaults, PrometheusConfig::default());
+    }
+
+    #[test]
+    fn test_head() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("head")
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/internal/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        let response = client.head("/internal/metrics").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type().unwrap().media_type().sub(), "plain");
+        assert!(response.into_bytes().unwrap_or_default().is_empty());
+
+        // `HEAD` is routed explicitly rather than relying on Rocket's
+        // fallback to `GET` routes.
+        let routes: Vec<rocket::Route> = PrometheusMetrics::new().into();
+        assert!(routes
+            .iter()
+            .any(|route| route.method == Method::Head && route.uri.as_str() == "/"));
+    }
+
+    #[test]
+    fn test_route_name_label() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("route_name")
+            .route_name_label(true)
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/", routes![routes::fail])
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client.get("/fail").dispatch();
+        let metrics = client.get("/metrics").dispatch();
+        let response = metrics.into_string().unwrap();
+        assert!(response.contains(
+            r#"route_name_http_requests_total{endpoint="/fail",method="GET",route_name="fail",status="500"} 1"#
+        ));
+    }
+
+    #[test]
+    fn test_query_label() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("query_label")
+            .query_label(true)
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/", routes![routes::fail])
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client.get("/fail").dispatch();
+        client.get("/fail?verbose=1").dispatch();
+        client.get("/fail?verbose=1").dispatch();
+        let metrics = client.get("/metrics").dispatch();
+        let response = metrics.into_string().unwrap();
+        assert!(response.contains(
+            r#"query_label_http_requests_total{endpoint="/fail",has_query="false",method="GET",status="500"} 1"#
+        ));
+        assert!(response.contains(
+            r#"query_label_http_requests_total{endpoint="/fail",has_query="true",method="GET",status="500"} 2"#
+        ));
+        assert!(response.contains(
+            r#"query_label_http_requests_duration_seconds_count{endpoint="/fail",has_query="true",method="GET",status="500"} 2"#
+        ));
+    }
+
+    #[test]
+    fn test_max_endpoints() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("max_endpoints")
+            .max_endpoints(1)
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/", routes![routes::fail])
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client.get("/fail").dispatch();
+        client.get("/metrics").dispatch();
+        client.get("/fail").dispatch();
+        let metrics = client.get("/metrics").dispatch();
+        let response = metrics.into_string().unwrap();
+        assert!(response.contains(
+            r#"max_endpoints_http_requests_total{endpoint="/fail",method="GET",status="500"} 2"#
+        ));
+        assert!(response.contains(
+            r#"max_endpoints_http_requests_total{endpoint="<overflow>",method="GET",status="200"} 1"#
+        ));
+        assert!(!response.contains(r#"endpoint="/metrics""#));
+    }
+
+    #[test]
+    fn test_ignore_methods() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("ignore_methods")
+            .ignore_methods([Method::Options, Method::Head])
+            .track_unmatched(true)
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/", routes![routes::fail])
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client.options("/fail").dispatch();
+        client.head("/fail").dispatch();
+        client.get("/fail").dispatch();
+        let metrics = client.get("/metrics").dispatch();
+        let response = metrics.into_string().unwrap();
+        assert!(response.contains(
+            r#"ignore_methods_http_requests_total{endpoint="/fail",method="GET",status="500"} 1"#
+        ));
+        assert!(!response.contains(r#"method="OPTIONS""#));
+        assert!(!response.contains(r#"method="HEAD""#));
+    }
+
+    #[test]
+    fn test_quantile_table_route() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("quantile_table")
+            .quantile_table_route(true)
+            .handler_methods([Method::Get, Method::Post])
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/", routes![routes::fail])
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client.get("/fail").dispatch();
+        let response = client.get("/metrics/summary").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::Plain));
+        let table = response.into_string().unwrap();
+        let mut lines = table.lines();
+        assert_eq!(
+            lines.next(),
+            Some("# Estimated from the buckets of quantile_table_http_requests_duration_seconds")
+        );
+        assert!(lines.next().unwrap().starts_with("endpoint "));
+        assert!(lines.next().unwrap().starts_with("/fail "));
+        // The route handles the same methods as the metrics routes.
+        assert_eq!(
+            client.post("/metrics/summary").dispatch().status(),
+            Status::Ok
+        );
+
+        let client = Client::untracked(rocket::build().mount("/metrics", PrometheusMetrics::new()))
+            .expect("valid rocket instance");
+        assert_eq!(
+            client.get("/metrics/summary").dispatch().status(),
+            Status::NotFound
+        );
+    }
+
+    #[test]
+    fn test_requests_by_hour() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("requests_by_hour")
+            .requests_by_hour_counter(None)
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/", routes![routes::fail])
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client.get("/fail").dispatch();
+        let response = client.get("/metrics").dispatch().into_string().unwrap();
+        let hours: Vec<u32> = response
+            .lines()
+            .filter_map(|line| {
+                line.strip_prefix(
+                    r#"requests_by_hour_http_requests_by_hour_total{endpoint="/fail",hour=""#,
+                )
+            })
+            .map(|rest| rest.split('"').next().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(hours.len(), 1);
+        assert!(hours[0] < 24);
+    }
+
+    #[test]
+    fn test_concurrency_max() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("concurrency_max")
+            .concurrency_max(false)
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/", routes![routes::hello, routes::fail])
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client.get("/hello/foo").dispatch();
+        client.get("/hello/bar").dispatch();
+        client.get("/fail").dispatch();
+        let response = client.get("/metrics").dispatch().into_string().unwrap();
+        // Requests are dispatched one at a time.
+        assert!(response.contains(
+            r#"concurrency_max_http_requests_concurrency_max{endpoint="/hello/<name>?<caps>"} 1"#
+        ));
+        assert!(response
+            .contains(r#"concurrency_max_http_requests_concurrency_max{endpoint="/fail"} 1"#));
+    }
+
+    #[test]
+    fn test_method_buckets() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("method_buckets")
+            .buckets(vec![1.0])
+            .method_buckets(Method::Post, vec![0.5, 10.0])
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/", routes![routes::hello, routes::hello_post])
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client.get("/hello/foo").dispatch();
+        client
+            .post("/hello/bar")
+            .header(ContentType::JSON)
+            .body(serde_json::to_string(&json!({"age": 50})).unwrap())
+            .dispatch();
+        let metrics = client.get("/metrics").dispatch();
+        let response = metrics.into_string().unwrap();
+        // Both histograms are exposed as a single family.
+        assert_eq!(
+            response
+                .matches("# TYPE method_buckets_http_requests_duration_seconds histogram")
+                .count(),
+            1
+        );
+        assert!(response.contains(
+            r#"method_buckets_http_requests_duration_seconds_bucket{endpoint="/hello/<name>?<caps>",method="POST",status="200",le="10"} 1"#
+        ));
+        assert!(!response.contains(
+            r#"method_buckets_http_requests_duration_seconds_bucket{endpoint="/hello/<name>?<caps>",method="POST",status="200",le="1"}"#
+        ));
+        assert!(response.contains(
+            r#"method_buckets_http_requests_duration_seconds_bucket{endpoint="/hello/<name>?<caps>",method="GET",status="200",le="1"} 1"#
+        ));
+    }
+
+    #[test]
+    fn test_endpoint_buckets() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("endpoint_buckets")
+            .buckets(vec![1.0])
+            .endpoint_buckets([("/hello/<name>?<caps>", vec![0.5, 10.0])])
+            .exemplar_header("traceparent")
+            .series_retention(Duration::from_millis(200))
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/", routes![routes::hello, routes::fail])
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client
+            .get("/hello/foo")
+            .header(Header::new(
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            ))
+            .dispatch();
+        client.get("/fail").dispatch();
+        let metrics = client
+            .get("/metrics")
+            .header(Header::new("Accept", "application/openmetrics-text"))
+            .dispatch();
+        let response = metrics.into_string().unwrap();
+        // Both histograms are exposed as a single family.
+        assert_eq!(
+            response
+                .matches("# TYPE endpoint_buckets_http_requests_duration_seconds histogram")
+                .count(),
+            1
+        );
+        assert!(response.contains(
+            r#"endpoint_buckets_http_requests_duration_seconds_bucket{endpoint="/hello/<name>?<caps>",method="GET",status="200",le="10"} 1"#
+        ));
+        assert!(!response.contains(
+            r#"endpoint_buckets_http_requests_duration_seconds_bucket{endpoint="/hello/<name>?<caps>",method="GET",status="200",le="1"}"#
+        ));
+        assert!(response.contains(
+            r#"endpoint_buckets_http_requests_duration_seconds_bucket{endpoint="/fail",method="GET",status="500",le="1"} 1"#
+        ));
+        // Exemplars are recorded for the endpoint's own buckets.
+        let annotated: Vec<_> = response
+            .lines()
+            .filter(|line| line.contains(r#"# {trace_id="4bf92f3577b34da6a3ce929d0e0e4736"}"#))
+            .collect();
+        assert_eq!(annotated.len(), 1);
+        assert!(annotated[0].starts_with(
+            r#"endpoint_buckets_http_requests_duration_seconds_bucket{endpoint="/hello/<name>?<caps>",method="GET",status="200",le="0.5"}"#
+        ));
+
+        // Series of the endpoint's histogram expire too.
+        std::thread::sleep(Duration::from_millis(300));
+        client.get("/fail").dispatch();
+        let response = client.get("/metrics").dispatch().into_string().unwrap();
+        assert!(!response.contains(r#"endpoint="/hello/<name>?<caps>""#));
+        assert!(response.contains(r#"endpoint="/fail""#));
+
+        let duplicate = PrometheusMetrics::builder()
+            .endpoint_buckets([("/a", vec![1.0])])
+            .endpoint_buckets([("/a", vec![2.0])])
+            .build();
+        assert!(duplicate.is_err());
+    }
+
+    #[test]
+    fn test_handler_methods() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("handler_methods")
+            .handler_methods([Method::Get, Method::Post])
+            .exclude_metrics_route(true)
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/", routes![routes::fail])
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client.get("/fail").dispatch();
+        let get = client.get("/metrics").dispatch();
+        assert_eq!(get.status(), Status::Ok);
+        let get = get.into_string().unwrap();
+        let post = client.post("/metrics").body("ignored").dispatch();
+        assert_eq!(post.status(), Status::Ok);
+        assert_eq!(post.into_string().unwrap(), get);
+        assert_eq!(
+            client.post("/metrics/custom").dispatch().status(),
+            Status::Ok
+        );
+        assert_eq!(client.put("/metrics").dispatch().status(), Status::NotFound);
+    }
+
+    #[test]
+    fn test_reset() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("reset")
+            .build()
+            .unwrap();
+        let custom = IntCounter::new("reset_custom_total", "A custom counter").unwrap();
+        prometheus
+            .registry()
+            .register(Box::new(custom.clone()))
+            .unwrap();
+        custom.inc();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/", routes![routes::fail])
+            .mount("/metrics", prometheus.clone());
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client.get("/fail").dispatch();
+
+        prometheus.reset();
+        let metrics = client.get("/metrics").dispatch();
+        let response = metrics.into_string().unwrap();
+        assert!(!response.contains(r#"endpoint="/fail""#));
+        assert!(response.contains("reset_custom_total 1"));
+    }
+
+    #[test]
+    fn test_method_label_source() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("method_source")
+            .method_label_source(MethodSource::Route)
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/", routes![routes::fail])
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client.head("/fail").dispatch();
+        client.get("/fail").dispatch();
+        let metrics = client.get("/metrics").dispatch();
+        let response = metrics.into_string().unwrap();
+        assert!(response.contains(
+            r#"method_source_http_requests_total{endpoint="/fail",method="GET",status="500"} 2"#
+        ));
+        assert!(!response.contains(r#"method="HEAD""#));
+    }
+
+    #[test]
+    fn test_active_window() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("active_window")
+            .active_window(Duration::from_secs(60))
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/", routes![routes::fail])
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client.get("/fail").dispatch();
+        client.get("/fail").dispatch();
+        let metrics = client.get("/metrics").dispatch();
+        let response = metrics.into_string().unwrap();
+        assert!(response.contains("# TYPE active_window_http_requests_active_window gauge"));
+        assert!(response.contains(
+            r#"active_window_http_requests_active_window{endpoint="/fail",method="GET",status="500"} 2"#
+        ));
+
+        assert!(PrometheusMetrics::builder()
+            .active_window(Duration::ZERO)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_custom_encoder() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("custom_encoder")
+            .encoder(
+                SeriesCountEncoder,
+                ContentType::new("text", "x-series-count"),
+            )
+            .comment("ignored")
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/", routes![routes::fail])
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client.get("/fail").dispatch();
+        let response = client
+            .get("/metrics")
+            .header(Header::new("Accept", "application/openmetrics-text"))
+            .dispatch();
+        assert_eq!(
+            response.content_type(),
+            Some(ContentType::new("text", "x-series-count"))
+        );
+        assert_eq!(
+            response.into_string().unwrap(),
+            "custom_encoder_http_requests_duration_seconds 1\n\
+             custom_encoder_http_requests_in_flight 1\n\
+             custom_encoder_http_requests_total 1\n"
+        );
+    }
+
+    #[test]
+    fn test_content_type() {
+        let prometheus = PrometheusMetrics::new();
+        let rocket = rocket::build().mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        let content_type = |accept: &str| {
+            let response = client
+                .get("/metrics")
+                .header(Header::new("Accept", accept.to_owned()))
+                .dispatch();
+            response
+                .headers()
+                .get_one("Content-Type")
+                .unwrap()
+                .to_owned()
+        };
+        assert_eq!(
+            content_type("text/plain"),
+            "text/plain; version=0.0.4; charset=utf-8"
+        );
+        assert_eq!(
+            content_type("application/openmetrics-text"),
+            "application/openmetrics-text; version=1.0.0; charset=utf-8"
+        );
+
+        let prometheus = PrometheusMetrics::builder()
+            .text_content_type(ContentType::new("text", "plain").with_params(("version", "0.0.4")))
+            .build()
+            .unwrap();
+        let rocket = rocket::build().mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        let response = client.get("/metrics").dispatch();
+        assert_eq!(
+            response.headers().get_one("Content-Type"),
+            Some("text/plain; version=0.0.4")
+        );
+        let response = client
+            .get("/metrics")
+            .header(Header::new("Accept", "application/openmetrics-text"))
+            .dispatch();
+        assert_eq!(
+            response.content_type().unwrap().media_type().sub(),
+            "openmetrics-text"
+        );
+    }
+
+    #[test]
+    fn test_host_label() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("host_label")
+            .host_label(["a.example.com", "B.example.com"])
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/", routes![routes::fail])
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        for host in ["a.example.com", "b.example.com:8000", "c.example.com"] {
+            client
+                .get("/fail")
+                .header(Header::new("Host", host))
+                .dispatch();
+        }
+        client.get("/fail").dispatch();
+        let metrics = client.get("/metrics").dispatch();
+        let response = metrics.into_string().unwrap();
+        for host in ["a.example.com", "b.example.com", "<other>", "<none>"] {
+            assert!(response.contains(&format!(
+                r#"host_label_http_requests_total{{endpoint="/fail",host="{}",method="GET",status="500"}} 1"#,
+                host
+            )));
+        }
+    }
+
+    #[test]
+    fn test_response_callback() {
+        use std::sync::{Arc, Mutex};
+
+        let calls = Arc::new(Mutex::new(vec![]));
+        let (first, second) = (Arc::clone(&calls), Arc::clone(&calls));
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("response_callback")
+            .response_callback(move |req, _, status| {
+                first
+                    .lock()
+                    .unwrap()
+                    .push(format!("first {} {}", req.uri(), status.code));
+            })
+            .response_callback(move |req, _, status| {
+                second
+                    .lock()
+                    .unwrap()
+                    .push(format!("second {} {}", req.uri(), status.code));
+            })
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus)
+            .mount("/", routes![routes::fail]);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client.get("/fail").dispatch();
+        client.get("/missing").dispatch();
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec!["first /fail 500".to_owned(), "second /fail 500".to_owned()]
+        );
+    }
+
+    #[test]
+    fn test_missing_timer() {
+        use rocket::{fairing::Fairing, Response};
+
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("missing_timer")
+            .track_unmatched(true)
+            .build()
+            .unwrap();
+        let rocket = rocket::build().attach(prometheus.clone());
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client.get("/anything").dispatch();
+        let req = client.get("/anything");
+        // Run only `on_response`, as if `on_request` never saw the request.
+        rocket::async_test(prometheus.on_response(req.inner(), &mut Response::new()));
+
+        let text = prometheus.gather_text().unwrap();
+        assert!(text.contains(
+            r#"missing_timer_http_requests_missing_timer_total{endpoint="<unmatched>",method="GET"} 1"#
+        ));
+        // There's no start time to measure a duration from, so only the
+        // dispatched request is counted and observed.
+        let sum = |name: &str| {
+            text.lines()
+                .filter(|line| line.starts_with(name))
+                .map(|line| line.rsplit(' ').next().unwrap().parse::<u64>().unwrap())
+                .sum::<u64>()
+        };
+        assert_eq!(sum("missing_timer_http_requests_total{"), 1);
+        assert_eq!(
+            sum("missing_timer_http_requests_duration_seconds_count{"),
+            sum("missing_timer_http_requests_total{")
+        );
+    }
+
+    #[test]
+    fn test_gather() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("gather")
+            .build()
+            .unwrap();
+        let custom = IntCounter::new("gather_custom_total", "A custom counter").unwrap();
+        prometheus
+            .registry()
+            .register(Box::new(custom.clone()))
+            .unwrap();
+        custom.inc();
+        prometheus
+            .http_requests_total()
+            .with_label_values(&["/", "GET", "200"])
+            .inc();
+
+        let families = prometheus.gather();
+        let names: Vec<_> = families.iter().map(|family| family.get_name()).collect();
+        assert!(names.contains(&"gather_custom_total"));
+        assert!(names.contains(&"gather_http_requests_total"));
+        assert!(names.contains(&"gather_http_requests_in_flight"));
+    }
+
+    #[test]
+    fn test_exclude_metrics_route() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("exclude_metrics")
+            .exclude_metrics_route(true)
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/", routes![routes::fail])
+            .mount("/internal/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client.get("/fail").dispatch();
+        client.get("/internal/metrics").dispatch();
+        client.get("/internal/metrics/rocket").dispatch();
+        let metrics = client.get("/internal/metrics").dispatch();
+        let response = metrics.into_string().unwrap();
+        assert!(response.contains(r#"endpoint="/fail""#));
+        assert!(!response.contains(r#"endpoint="/internal/metrics"#));
+    }
+
+    #[test]
+    fn test_duration_summary() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("summary")
+            .duration_summary(vec![0.5, 0.99], 100)
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/", routes![routes::fail])
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client.get("/fail").dispatch();
+        client.get("/fail").dispatch();
+        let metrics = client.get("/metrics").dispatch();
+        let response = metrics.into_string().unwrap();
+        assert!(response.contains("# TYPE summary_http_requests_duration_seconds_summary summary"));
+        assert!(response.contains(
+            r#"summary_http_requests_duration_seconds_summary{endpoint="/fail",method="GET",status="500",quantile="0.99"} "#
+        ));
+        assert!(response.contains(
+            r#"summary_http_requests_duration_seconds_summary_count{endpoint="/fail",method="GET",status="500"} 2"#
+        ));
+        assert!(!response.contains("summary_http_requests_duration_seconds_bucket"));
+
+        assert!(PrometheusMetrics::builder()
+            .duration_summary(vec![1.5], 100)
+            .build()
+            .is_err());
+        assert!(PrometheusMetrics::builder()
+            .duration_summary(vec![0.5], 0)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_exemplars() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("exemplars")
+            .exemplar_header("traceparent")
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/", routes![routes::fail])
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client
+            .get("/fail")
+            .header(Header::new(
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            ))
+            .dispatch();
+
+        let openmetrics = client
+            .get("/metrics")
+            .header(Header::new("Accept", "application/openmetrics-text"))
+            .dispatch()
+            .into_string()
+            .unwrap();
+        let annotated: Vec<_> = openmetrics
+            .lines()
+            .filter(|line| line.contains(r#"# {trace_id="4bf92f3577b34da6a3ce929d0e0e4736"}"#))
+            .collect();
+        assert_eq!(annotated.len(), 1);
+        assert!(annotated[0].starts_with(
+            r#"exemplars_http_requests_duration_seconds_bucket{endpoint="/fail",method="GET",status="500",le="#
+        ));
+
+        let text = client.get("/metrics").dispatch().into_string().unwrap();
+        assert!(!text.contains("trace_id"));
+    }
+
+    #[test]
+    fn test_exemplar_threshold() {
+        let prometheus = PrometheusMetrics::builder()
+            .namespace("exemplar_threshold")
+            .exemplar_header("traceparent")
+            .exemplar_threshold(Duration::from_secs(60))
+            .build()
+            .unwrap();
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/", routes![routes::fail])
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        client
+            .get("/fail")
+            .header(Header::new(
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            ))
+            .dispatch();
+
+        let openmetrics = client
+            .get("/metrics")
+            .header(Header::new("Accept", "application/openmetrics-text"))
+            .dispatch()
+            .into_string()
+            .unwrap();
+        // The request is observed, but too fast to record an exemplar.
+        assert!(openmetrics.contains(
+            r#"exemplar_threshold_http_requests_duration_seconds_count{endpoint="/fail",method="GET",status="500"} 1"#
+        ));
+        assert!(!openmetrics.contains("trace_id"));
+    }
+
+    /// A collector producing a metric family which can't be encoded.
+    struct BrokenCollector;
+
+    impl Collector for BrokenCollector {
+        fn desc(&self) -> Vec<&Desc> {
+            vec![]
+        }
+
+        fn collect(&self) -> Vec<MetricFamily> {
+            // Families must have a name to be encoded.
+            let mut family = MetricFamily::default();
+            family.mut_metric().push(Metric::default());
+            vec![family]
+        }
+    }
+
+    #[test]
+    fn test_encoding_error() {
+        let prometheus = PrometheusMetrics::new();
+        prometheus.add_collector(Box::new(BrokenCollector));
+        assert!(prometheus.gather_text().is_err());
+        let rocket = rocket::build()
+            .attach(prometheus.clone())
+            .mount("/", routes![routes::hello_post])
+            .mount("/metrics", prometheus);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let metrics = client.get("/metrics").dispatch();
+        assert_eq!(metrics.status(), Status::InternalServerError);
+
+        // The server keeps handling requests.
+        let response = client
+            .post("/hello/bar")
+            .header(ContentType::JSON)
+            .body(serde_json::to_string(&json!({"age": 50})).unwrap())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    /// A collector which takes a while to collect, like a large registry.
+    struct SlowCollector;
+
+    impl Collector for SlowCollector {
+        fn desc(&self) -> Vec<&Desc> {
+            vec![]
+        }
+
+        fn collect(&self) -> Vec<MetricFamily> {
+            std::thread::sleep(Duration::from_millis(500));
+            vec![]
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_gather_does_not_block() {
+        use rocket::local::asynchronous::Client;
+
+        let prometheus = PrometheusMetrics::new();
+        prometheus.add_collector(Box::new(SlowCollector));
+        let rocket = rocket::build().mount("/metrics", prometheus);
+        let client = Client::untracked(rocket)
+            .await
+            .expect("valid rocket instance");
+
+        // The test runtime has a single thread, so the timer can only fire
+        // on time if the scrape yields to the executor while gathering.
+        let start = Instant::now();
+        let timer = async {
+            rocket::tokio::time::sleep(Duration::from_millis(10)).await;
+            start.elapsed()
+        };
+        let (response, timer_elapsed) =
+            rocket::tokio::join!(client.get("/metrics").dispatch(), timer);
+        assert_eq!(response.status(), Status::Ok);
+        assert!(timer_elapsed < Duration::from_millis(400));
+        assert!(start.elapsed() >= Duration::from_millis(500));
+    }
 }
@@ -0,0 +1,89 @@
+#[macro_use]
+extern crate rocket;
+
+use rocket::local::blocking::Client;
+use rocket_prometheus::{
+    testing::{strip_histogram_sums, MetricsSnapshot},
+    PrometheusMetrics,
+};
+
+#[get("/hello/<name>")]
+fn hello(name: &str) -> String {
+    format!("Hello, {}!", name)
+}
+
+#[test]
+fn test_snapshot_diff() {
+    let prometheus = PrometheusMetrics::new();
+    let rocket = rocket::build()
+        .attach(prometheus.clone())
+        .mount("/", routes![hello])
+        .mount("/metrics", prometheus.clone());
+    let client = Client::untracked(rocket).expect("valid rocket instance");
+
+    client.get("/hello/foo").dispatch();
+    let before = prometheus.snapshot();
+    client.get("/hello/bar").dispatch();
+    let after = prometheus.snapshot();
+
+    let diff = before.diff(&after);
+    assert_eq!(
+        diff.get(
+            r#"rocket_http_requests_total{endpoint="/hello/<name>",method="GET",status="200"}"#
+        )
+        .map(|change| (change.before, change.after)),
+        Some((Some(1.0), Some(2.0))),
+        "{}",
+        diff
+    );
+    assert_eq!(
+        diff.get(r#"rocket_http_requests_duration_seconds_count{endpoint="/hello/<name>",method="GET",status="200"}"#)
+            .map(|change| change.delta()),
+        Some(1.0),
+        "{}",
+        diff
+    );
+    // Duration sums are ignored by default.
+    assert!(diff
+        .iter()
+        .all(|(series, _)| !series.contains("duration_seconds_sum")));
+    assert!(before.diff_with_sums(&after).len() > diff.len());
+
+    // Nothing changes without requests.
+    assert!(after.diff(&prometheus.snapshot()).is_empty());
+}
+
+#[test]
+fn test_snapshot_from_text() {
+    let snapshot = MetricsSnapshot::from_text(
+        r#"# HELP name_counter Count of names
+# TYPE name_counter counter
+name_counter{name="bar"} 1
+name_counter{name="foo"} 2
+"#,
+    );
+    assert_eq!(snapshot.get(r#"name_counter{name="foo"}"#), Some(2.0));
+    assert_eq!(snapshot.get(r#"name_counter{name="baz"}"#), None);
+    assert_eq!(snapshot, snapshot.clone());
+}
+
+#[test]
+fn test_strip_histogram_sums() {
+    let prometheus = PrometheusMetrics::new();
+    let rocket = rocket::build()
+        .attach(prometheus.clone())
+        .mount("/", routes![hello]);
+    let client = Client::untracked(rocket).expect("valid rocket instance");
+    client.get("/hello/foo").dispatch();
+
+    let text = prometheus.gather_text().unwrap();
+    assert!(text.contains("rocket_http_requests_duration_seconds_sum{"));
+    let stripped = strip_histogram_sums(&text);
+    assert!(!stripped.contains("rocket_http_requests_duration_seconds_sum"));
+    assert!(stripped.contains("rocket_http_requests_duration_seconds_count{"));
+    assert_eq!(
+        stripped.lines().count(),
+        text.lines().count() - 1,
+        "only the sum line is removed"
+    );
+}